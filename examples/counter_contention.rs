@@ -0,0 +1,57 @@
+//! Compares the throughput of hot counters bumped from many threads, with and without sharding.
+//!
+//! Run with `cargo run --release --example counter_contention`.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use dipstick::AtomicBucket;
+use tracing::{info, info_span, subscriber};
+use tracing_dipstick::{DipstickLayer, DipstickLayerBuilder};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Registry;
+
+const THREADS: usize = 16;
+const ITERATIONS: usize = 100_000;
+
+fn measure(name: &str, builder: DipstickLayerBuilder<AtomicBucket>) {
+    let bridge = builder.finish();
+    let subscriber = Registry::default().with(bridge);
+    subscriber::with_default(subscriber, || {
+        let dispatch = tracing::dispatcher::get_default(Clone::clone);
+        let start = Instant::now();
+        let workers = (0..THREADS)
+            .map(|_| {
+                let dispatch = dispatch.clone();
+                thread::spawn(move || {
+                    tracing::dispatcher::with_default(&dispatch, || {
+                        let _span = info_span!("worker", metrics.scope = "worker").entered();
+                        for _ in 0..ITERATIONS {
+                            info!(metrics.counter = "hits");
+                        }
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        let elapsed = start.elapsed();
+        let total = THREADS * ITERATIONS;
+        println!(
+            "{}: {} increments in {:?} ({:.0} per second)",
+            name,
+            total,
+            elapsed,
+            total as f64 / elapsed.as_secs_f64()
+        );
+    });
+}
+
+fn main() {
+    measure("direct", DipstickLayer::builder(AtomicBucket::new()));
+    measure(
+        "sharded",
+        DipstickLayer::builder(AtomicBucket::new()).sharded_counters(Duration::from_secs(1)),
+    );
+}
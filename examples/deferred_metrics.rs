@@ -0,0 +1,38 @@
+//! Compares the cost of recording metrics on the hot path with deferring them to span close.
+//!
+//! Run with `cargo run --release --example deferred_metrics`.
+
+use std::time::Instant;
+
+use dipstick::AtomicBucket;
+use tracing::{info, info_span, subscriber};
+use tracing_dipstick::{DipstickLayer, DipstickLayerBuilder};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Registry;
+
+const ITERATIONS: usize = 1_000_000;
+
+fn measure(name: &str, builder: DipstickLayerBuilder<AtomicBucket>) {
+    let bridge = builder.finish();
+    let subscriber = Registry::default().with(bridge);
+    subscriber::with_default(subscriber, || {
+        let span = info_span!("work", metrics.scope = "work").entered();
+        let start = Instant::now();
+        for i in 0..ITERATIONS {
+            info!(metrics.counter = "hits", metrics.gauge.last = i);
+        }
+        let hot = start.elapsed();
+        let start = Instant::now();
+        drop(span);
+        let close = start.elapsed();
+        println!("{}: hot path {:?}, close {:?}", name, hot, close);
+    });
+}
+
+fn main() {
+    measure("direct", DipstickLayer::builder(AtomicBucket::new()));
+    measure(
+        "deferred",
+        DipstickLayer::builder(AtomicBucket::new()).defer_to_close(true),
+    );
+}
@@ -0,0 +1,65 @@
+//! Shows the events don't clone the scope of the span they happen in.
+//!
+//! Counts the allocations done by events inside a scoped span, both with and without metrics. The
+//! allocations left with a counter are done by the dipstick bucket when looking up the metric,
+//! none of them come from cloning the scope.
+//!
+//! Run with `cargo run --release --example event_allocations`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use dipstick::AtomicBucket;
+use tracing::{info, info_span, subscriber};
+use tracing_dipstick::DipstickLayer;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Registry;
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+const ITERATIONS: usize = 100_000;
+
+fn measure<F: Fn()>(name: &str, event: F) {
+    // Warm up, so the metrics are already registered
+    for _ in 0..100 {
+        event();
+    }
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        event();
+    }
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    println!(
+        "{}: {} allocations, {:?} for {} events",
+        name, allocations, elapsed, ITERATIONS
+    );
+}
+
+fn main() {
+    let subscriber = Registry::default().with(DipstickLayer::new(AtomicBucket::new()));
+    subscriber::with_default(subscriber, || {
+        let _outer = info_span!("outer", metrics.scope = "outer").entered();
+        let _inner = info_span!("inner", metrics.scope = "inner").entered();
+        measure("without metrics", || info!(cnt = 1));
+        measure("with a counter", || info!(metrics.counter = "hits"));
+    });
+}
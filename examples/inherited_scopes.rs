@@ -0,0 +1,65 @@
+//! Shows the spans without their own `metrics.scope` share the scope of their parent.
+//!
+//! Counts the allocations done while creating and closing nested spans that record metrics, but
+//! don't change the scope, inside a scoped span. The metrics are registered during the warm up, so
+//! only the cost of the spans themselves shows up.
+//!
+//! Run with `cargo run --release --example inherited_scopes`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use dipstick::AtomicBucket;
+use tracing::{info_span, subscriber};
+use tracing_dipstick::DipstickLayer;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Registry;
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+const ITERATIONS: usize = 10_000;
+
+fn nested() {
+    let _outer = info_span!("outer", metrics.counter = "outer").entered();
+    let _inner = info_span!("inner", metrics.counter = "inner").entered();
+}
+
+fn main() {
+    let subscriber = Registry::default().with(DipstickLayer::new(AtomicBucket::new()));
+    subscriber::with_default(subscriber, || {
+        let _root = info_span!("root", metrics.scope = "app").entered();
+        let _scoped = info_span!("scoped", metrics.scope = "service").entered();
+        // Warm up, so the registry has its slots prepared and the metrics are registered
+        for _ in 0..100 {
+            nested();
+        }
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            nested();
+        }
+        let elapsed = start.elapsed();
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+        println!(
+            "{} allocations, {:?} for {} iterations",
+            allocations, elapsed, ITERATIONS
+        );
+    });
+}
@@ -0,0 +1,66 @@
+//! Shows the layer doesn't allocate anything for spans without metrics.
+//!
+//! Counts the allocations done while creating and closing nested spans without any `metrics.*`
+//! attributes, with and without the layer.
+//!
+//! Run with `cargo run --release --example metric_free_spans`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use dipstick::AtomicBucket;
+use tracing::{info_span, subscriber, Subscriber};
+use tracing_dipstick::DipstickLayer;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Registry;
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+const ITERATIONS: usize = 100_000;
+
+fn measure<S: Subscriber + Send + Sync>(name: &str, subscriber: S) {
+    subscriber::with_default(subscriber, || {
+        // Warm up, so the registry has its slots prepared
+        for _ in 0..100 {
+            let _outer = info_span!("outer", cnt = 1).entered();
+            let _inner = info_span!("inner").entered();
+        }
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            let _outer = info_span!("outer", cnt = 1).entered();
+            let _inner = info_span!("inner").entered();
+        }
+        let elapsed = start.elapsed();
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+        println!(
+            "{}: {} allocations, {:?} for {} iterations",
+            name, allocations, elapsed, ITERATIONS
+        );
+    });
+}
+
+fn main() {
+    measure("registry only", Registry::default());
+    measure(
+        "with the layer",
+        Registry::default().with(DipstickLayer::new(AtomicBucket::new())),
+    );
+}
@@ -0,0 +1,42 @@
+//! Measures the cost of an event bumping the same counter over and over.
+//!
+//! Both inside a scoped span and outside of any span. The handles of the counters are cached, so
+//! only the first event has to look the counter up by its name in the bucket.
+//!
+//! Run with `cargo run --release --example repeated_counter`.
+
+use std::time::Instant;
+
+use dipstick::AtomicBucket;
+use tracing::{info, info_span, subscriber};
+use tracing_dipstick::DipstickLayer;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Registry;
+
+const ITERATIONS: usize = 1_000_000;
+
+fn measure<F: Fn()>(name: &str, body: F) {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        body();
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{}: {} events in {:?} ({:?} per event)",
+        name,
+        ITERATIONS,
+        elapsed,
+        elapsed / ITERATIONS as u32
+    );
+}
+
+fn main() {
+    let bridge = DipstickLayer::new(AtomicBucket::new());
+    let subscriber = Registry::default().with(bridge);
+    subscriber::with_default(subscriber, || {
+        measure("root", || info!(metrics.counter = "hits"));
+        let _span = info_span!("worker", metrics.scope = "worker").entered();
+        measure("scoped", || info!(metrics.counter = "hits"));
+        measure("value", || info!(metrics.counter.hits = 2));
+    });
+}
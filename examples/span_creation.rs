@@ -0,0 +1,37 @@
+//! Measures the cost of creating and closing spans with a single timer and a single level.
+//!
+//! Run with `cargo run --release --example span_creation`.
+
+use std::time::Instant;
+
+use dipstick::AtomicBucket;
+use tracing::{info_span, subscriber};
+use tracing_dipstick::DipstickLayer;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Registry;
+
+const ITERATIONS: usize = 1_000_000;
+
+fn main() {
+    let bridge = DipstickLayer::new(AtomicBucket::new());
+    subscriber::with_default(Registry::default().with(bridge), || {
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            let _span = info_span!("timed", metrics.timer = "time").entered();
+        }
+        println!("timer: {:?} per span", start.elapsed() / ITERATIONS as u32);
+
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            let _span = info_span!("active", metrics.level = "active").entered();
+        }
+        println!("level: {:?} per span", start.elapsed() / ITERATIONS as u32);
+
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            let _span =
+                info_span!("both", metrics.timer = "time", metrics.level = "active").entered();
+        }
+        println!("both: {:?} per span", start.elapsed() / ITERATIONS as u32);
+    });
+}
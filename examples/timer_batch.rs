@@ -0,0 +1,45 @@
+//! Compares the hot path cost of explicit timer values inside a span, with and without batching.
+//!
+//! Run with `cargo run --release --example timer_batch`.
+
+use std::time::Instant;
+
+use dipstick::AtomicBucket;
+use tracing::{info, info_span, subscriber};
+use tracing_dipstick::{DipstickLayer, DipstickLayerBuilder};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Registry;
+
+const ITERATIONS: u64 = 1_000_000;
+
+fn measure(name: &str, builder: DipstickLayerBuilder<AtomicBucket>) {
+    let bridge = builder.finish();
+    let subscriber = Registry::default().with(bridge);
+    subscriber::with_default(subscriber, || {
+        let start = Instant::now();
+        let span = info_span!("worker", metrics.scope = "worker").entered();
+        for i in 0..ITERATIONS {
+            info!(metrics.distribution.latency = i % 1000);
+        }
+        let hot = start.elapsed();
+        // The rest of the batches is sent here
+        drop(span);
+        let total = start.elapsed();
+        println!(
+            "{}: {} values in {:?} ({:.0} ns each), {:?} including the close",
+            name,
+            ITERATIONS,
+            hot,
+            hot.as_nanos() as f64 / ITERATIONS as f64,
+            total
+        );
+    });
+}
+
+fn main() {
+    measure("per value", DipstickLayer::builder(AtomicBucket::new()));
+    measure(
+        "batched",
+        DipstickLayer::builder(AtomicBucket::new()).timer_batch(64),
+    );
+}
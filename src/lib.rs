@@ -19,23 +19,956 @@
 //! after the `metrics.`) corresponds to the metric types in [`dipstick`]'s [`InputScope`]. Spans
 //! happen when they are created and some effects happen when they are closed/destroyed.
 //!
-//! * `metrics.counter="name"`: Adds 1 to the metric counter called `name`.
+//! * `metrics.counter="name"`: Adds 1 to the metric counter called `name` (the value can be
+//!   changed by [`DipstickLayerBuilder::default_counter_value`]).
+//! * `metrics.counter.dynamic="field"`: Counts a counter named by the value of another field
+//!   called `field`, see [below](#names-from-fields).
+//! * `metrics.marker="name"`: Marks an occurrence of the marker called `name`. Unlike counters,
+//!   markers don't have the `metrics.marker.name=value` form, as they only ever mark a single
+//!   occurrence (such fields are ignored).
 //! * `metrics.level="name"`: Adds 1 to the level called `name`. If it is present on a span, the 1
-//!   is subtracted when it is closed (it's more useful on spans).
+//!   is subtracted when it is closed (it's more useful on spans). The `metrics.level.name=amount`
+//!   form adjusts the level by the amount, which may be negative, see [below](#level-amounts).
+//! * `metrics.level.max="name"`: Tracks the maximum number of spans holding this attribute
+//!   concurrently within a subtree. The outermost span with the attribute (of the given name)
+//!   starts the tracking; it and every descendant span with the same attribute count as holding
+//!   it between their creation and closing. The maximum is set as a gauge called `name` once the
+//!   outermost span and all its descendants are closed. Unlike the combined `metrics.level`, this
+//!   doesn't see the spans outside of the subtree. This is accepted on spans only.
+//! * `metrics.peak="name"`: Tracks the highest number of spans holding this attribute at the same
+//!   time, over the whole lifetime of the layer, see [below](#peaks). This is accepted on spans
+//!   only.
+//! * `metrics.count_children="name"`: Counts the spans created inside this one, see
+//!   [below](#counting-child-spans). This is accepted on spans only.
+//! * `metrics.not_entered="name"`: Adds 1 to the counter called `name` when the span closes
+//!   without ever being entered, see [below](#spans-never-entered). This is accepted on spans only.
 //! * `metrics.gauge="name"`: Sets the gauge to 1. This one is more useful in the second form
 //!   below.
+//! * `metrics.reset="name"`: Sets the gauge to 0, see [below](#resetting-gauges).
+//! * `metrics.level_value="name"`: Sets the gauge to the numeric level of the span or event (the
+//!   `@level` special value below).
 //! * `metrics.timer="name"`: Records the time between the creation of the span and its destruction.
 //!   This attribute is accepted only on spans.
+//! * `metrics.timer.from_enter="name"`: Similar to the above, but the time is measured from the
+//!   first time the span is entered. This is useful for spans created ahead of the work they
+//!   represent. If the span is never entered, nothing is recorded. This is accepted only on spans.
+//! * `metrics.timer.start="name"`, `metrics.timer.stop="name"`: Records the time between two
+//!   separate events, see [below](#timers-across-events).
+//! * `metrics.timer.busy="name"`: Records only the time the span was entered, see [busy
+//!   time](#busy-time). This is accepted only on spans.
+//! * `metrics.timer.percentiles="name"`: Same as `metrics.timer`, but also marks the timer as one
+//!   that should get percentiles, see [statistics of timers](#statistics-of-timers). This is
+//!   accepted only on spans.
 //! * `metrics.scope="scope-name"`: Names of metrics that are inside this span get prefixed by this
 //!   name, eg. their names will be `scope-name.name`. Nested spans with this attributes accumulate
-//!   the name, eg `outer-scope-name.inner-scope-name.name`. This is accepted on spans only.
+//!   the name, eg `outer-scope-name.inner-scope-name.name`. On an event, it applies to the metrics
+//!   of the event only, see [below](#scopes-of-events). A dotted name (`"outer.inner"`) stands for
+//!   multiple nested scopes, see [below](#dotted-scopes).
 //! * `metrics.scope.full="scope-name"`: Similar to the above, but the name is not nested, it is
 //!   replaced.
+//! * Both of the above accept non-string values too (eg. `metrics.scope = shard_id`), see
+//!   [below](#non-string-scopes).
+//! * `metrics.scope.field="field"`: Adds the value of another field called `field` to the scope,
+//!   see [below](#scopes-from-fields). This is accepted on spans only.
+//! * `metrics.scope.reset=true`: The metrics of this event go to the root scope, see
+//!   [below](#escaping-the-scope). This is accepted on events only.
+//! * `metrics.scope.dynamic=true`: The scope of this span follows the span it is entered in,
+//!   instead of the one it was created in. See [below](#following-the-dynamic-parent). This is
+//!   accepted on spans only.
+//! * `metrics.tag.key="value"`: Tags the metrics of the span (including all its descendants) or
+//!   event with the `key` and `value`, see [below](#tags).
+//! * `metrics.on="enter"`: Records the metrics of the span every time it is entered instead of
+//!   once on its creation, see [below](#metrics-on-enter). This is accepted on spans only.
+//! * `metrics.summary=true`: Collects the totals of counters and timers recorded within this span
+//!   and its descendants into a [`Summary`], passed to the hook configured through
+//!   [`DipstickLayerBuilder::on_summary`] once the span closes. This is accepted on spans only and
+//!   is ignored if no hook is configured.
+//! * `metrics.auto_gauge=true`: Records all the numeric fields of the span as gauges, see [gauges
+//!   from span fields](#gauges-from-span-fields). This is accepted on spans only.
+//! * `metrics.when="field"`: Records the metrics of the event only if the `bool` field called
+//!   `field` is `true`, see [below](#conditional-metrics). This is accepted on events only.
+//! * `metrics.flush_every=seconds`: Flushes the scope of the span periodically while the span is
+//!   open, see [below](#periodic-flushing-of-scopes). This is accepted on spans only.
 //!
 //! The `counter`, `level` and `gauge` accept alternative variant of `metrics.type.name=value` (for
-//! example, `metrics.gauge.name=42`), which uses the given value instead of `1`.
+//! example, `metrics.gauge.name=42`), which uses the given value instead of `1`. A `bool` value
+//! counts as `1` for `true` and `0` for `false`. This is handy for flags set as gauges, while for
+//! counters it means `false` adds nothing.
 //!
-//! Unfortunately, typos don't cause compile errors, they are just ignored :-(.
+//! ```rust
+//! tracing::info!(metrics.gauge.healthy = true);
+//! tracing::info!(metrics.counter.failed = false);
+//! ```
+//!
+//! A single span or event may carry any number of these attributes, in any mix of the forms. All
+//! of them are recorded. The metrics of an event are collected first and recorded together once
+//! all its fields are processed, in the order of the fields.
+//!
+//! ```rust
+//! tracing::debug!(
+//!     metrics.counter.done = 4,
+//!     metrics.counter = "batches",
+//!     metrics.gauge.queue = 7,
+//!     metrics.marker = "flushed",
+//!     "Batch processed",
+//! );
+//! ```
+//!
+//! Counters only ever go up. Negative values of counters (eg. `metrics.counter.name=-1`) are
+//! ignored and a warning is emitted through [`tracing`] (once for each such field). Use a
+//! `metrics.level` or `metrics.gauge.delta` for values that can go both ways.
+//!
+//! The attributes that take a metric name, like `metrics.timer.start` or `metrics.level.max`,
+//! don't accept a number. Their names also fit the `metrics.type.name=value` form, but the value
+//! is not taken as a timer called `start` or a level called `max`. It is ignored and a warning is
+//! emitted (once for each such field).
+//!
+//! # Resetting gauges
+//!
+//! A gauge can be set to 0 either through the value form (`metrics.gauge.name=0`) or by
+//! `metrics.reset="name"`, which is handy in state machines. Levels can't be reset this way, as
+//! they are only ever adjusted relative to their current value.
+//!
+//! ```rust
+//! tracing::info!(metrics.reset = "state");
+//! ```
+//!
+//! Furthermore, `metrics.gauge.delta.name=value` adjusts the gauge by the value instead of setting
+//! it (the string form `metrics.gauge.delta="name"` adjusts it by `1`). Backends that take relative
+//! adjustments of gauges (see [`DipstickSink::relative_gauges`]) get the adjustments as they are.
+//! For the others, including all the [`dipstick`] outputs, the layer keeps the running total of
+//! each such gauge and sets the gauge to it. The totals are kept by the full name of the gauge
+//! (including the scope) and shared by all the threads. Each adjustment updates
+//! the total and sets the gauge while holding a lock, so concurrent adjustments don't get lost and
+//! the last value set is always the current total.
+//!
+//! ```rust
+//! tracing::info!(metrics.gauge.delta.queue = 3, "Enqueued");
+//! tracing::info!(metrics.gauge.delta.queue = -1, "Dequeued");
+//! ```
+//!
+//! The `metrics.timer.name=value` form records a duration measured elsewhere (eg. the latency of
+//! an external call) into the timer called `name`. The value is in nanoseconds (as returned by
+//! [`Duration::as_nanos`]), but it is recorded with the microsecond precision of [`dipstick`]
+//! timers. Negative values are clamped to zero. Unlike the other timers, this one is accepted on
+//! events too (on a span, it is recorded right away when the span is created).
+//!
+//! The `metrics.distribution.name=value` form feeds arbitrary sampled values (eg. sizes of
+//! requests) into a distribution called `name`. There's no dedicated distribution metric in
+//! [`dipstick`], so this reuses the timer machinery ‒ the value is recorded into a timer as if it
+//! were microseconds. The aggregation (eg. an [`AtomicBucket`]) then
+//! computes the statistics of it; the percentiles show up only if the bucket is configured to
+//! produce them (eg. with [`stats_all`][dipstick::stats_all]). Note that some outputs scale the
+//! timers (eg. statsd presents them in milliseconds, dividing the values by 1000). Negative values
+//! are clamped to zero, as timers can't hold them.
+//!
+//! Floating point values (eg. `metrics.gauge.temperature = 21.5`) are accepted everywhere the
+//! integer ones are. As [`dipstick`] works with integers only, they are rounded to the nearest
+//! integer (halves away from zero, so `21.5` becomes `22`), for all the metric types. Values too
+//! large for `i64` saturate to the largest or smallest one. Values that are not finite (NaN or
+//! infinities) are handled according to the [`NonFinite`] policy set through
+//! [`DipstickLayerBuilder::non_finite`].
+//!
+//! Similarly, 128-bit integer values and `u64` values over [`i64::MAX`] are clamped to the range
+//! of `i64`. As this likely means something is wrong with the instrumentation, a warning is
+//! emitted through [`tracing`] (once for each such field) when the value doesn't fit.
+//!
+//! If the name is left empty (eg. `metrics.counter=""`) or the attribute is set to `true` instead
+//! of a name (eg. `metrics.counter=true`), the metric is _bare_. Such metric is named after the
+//! span (for an event, the span it happens in), with every character except ASCII letters, digits
+//! and `_` replaced by `_`. Outside of any span, it uses the name configured through
+//! [`DipstickLayerBuilder::default_metric_name`] or is ignored if there's none. In other words, an
+//! explicit name takes precedence over the name of the span, which takes precedence over the
+//! default one. Setting the attribute to `false` records nothing.
+//!
+//! ```rust
+//! let _span = tracing::info_span!("request", metrics.timer = true).entered();
+//! // Counted as `request`
+//! tracing::info!(metrics.counter = true, "Handled");
+//! ```
+//!
+//! The special name `@target` (eg. `metrics.counter="@target"`) stands for the target of the span
+//! or event, which is the module path by default. Every character except ASCII letters, digits and
+//! `_` is replaced by `_` (with runs of them collapsed), so `my_app::db` becomes `my_app_db`. The
+//! name is placed into the scope as any other. This allows counting events per module without
+//! naming each of them.
+//!
+//! ```rust
+//! mod db {
+//!     pub fn query() {
+//!         // Counted as `..._db`, with the path of the crate before it
+//!         tracing::info!(metrics.counter = "@target", "Querying");
+//!     }
+//! }
+//! # db::query();
+//! ```
+//!
+//! Similarly, a few special values computed from the span or event itself can be used in the
+//! `metrics.type.name=value` form instead of a number:
+//!
+//! * `@field_count`: The number of fields of the span or event, as declared at the callsite (that
+//!   includes the `metrics.*` ones and the message of an event).
+//! * `@level`: The level of the span or event as a number, from `5` for `ERROR` to `1` for
+//!   `TRACE` (`WARN` is `4`, `INFO` is `3` and `DEBUG` is `2`). The more severe the level, the
+//!   higher the number, so alerting on a threshold or taking the maximum over a period works as
+//!   expected.
+//!
+//! As the level is the most common of these, `metrics.level_value="name"` is a shorthand for
+//! `metrics.gauge.name="@level"`. Putting it on the events of interest makes the gauge show the
+//! level of the most recent one (eg. for a "last seen severity" dashboard).
+//!
+//! ```rust
+//! tracing::warn!(user = "alice", metrics.gauge.fields = "@field_count", "Login failed");
+//! tracing::error!(metrics.level_value = "severity", "Disk full");
+//! ```
+//!
+//! Unfortunately, typos don't cause compile errors, they are just ignored :-(. With
+//! [`DipstickLayerBuilder::warn_unknown`], at least a warning is emitted through [`tracing`] for
+//! every `metrics.*` field that is not recognized (once for each such field name). As with other
+//! warnings from inside the layer, they are seen only when the subscriber is set as the global
+//! default (scoped subscribers don't get events emitted from within themselves). For an aggregate
+//! view, [`DipstickLayer::stats`] counts the metric fields emitted and ignored.
+//!
+//! To catch the typos at compile time instead, use the [`metric_event!`] and [`metric_span!`]
+//! macros in place of the ones from [`tracing`]. They check the `metrics.*` attributes and refuse
+//! to compile with an unknown one.
+//!
+//! # Closing of spans
+//!
+//! The metrics finished by a span (eg. its timers and levels) are finished when the span closes,
+//! that is, when the last handle to it is dropped. This happens in the layer's
+//! [`on_close`][Layer::on_close], so the other layers see them recorded already when they get the
+//! notification about the closed span (provided they come after this layer in the stack).
+//!
+//! Clones of a span (including the clones of its ID made through
+//! [`clone_span`][Subscriber::clone_span]) share its metrics. They are finished exactly once,
+//! when the last of the clones is dropped, no matter on which thread.
+//!
+//! # Level amounts
+//!
+//! A span with `metrics.level.name = amount` adjusts the level by the amount when it is created
+//! and undoes exactly that adjustment when it is closed, so a closed span never leaves a trace in
+//! the level. This holds for negative amounts as well: such span lowers the level for its lifetime
+//! and raises it back when closed (which is useful eg. for spans returning capacity to a pool
+//! for a while). The amount is clamped to `-i64::MAX..=i64::MAX` so the undo is always exact. On
+//! events, the adjustment is permanent, whatever its sign.
+//!
+//! ```rust
+//! // Returns 3 items to the pool for as long as the span is open
+//! let _borrow = tracing::info_span!("borrow", metrics.level.pool = -3).entered();
+//! ```
+//!
+//! # Metrics on enter
+//!
+//! A span that is entered many times (eg. the span of an async task, entered on every poll)
+//! records its metrics only once, when it is created. With `metrics.on="enter"`, the counters,
+//! markers, gauges, levels and timers of the span are recorded every time it is entered instead.
+//! The levels are lowered again and the timers are stopped when the span is exited, so each timer
+//! measures a single enter-to-exit interval (the `metrics.timer` and `metrics.timer.from_enter`
+//! behave the same in this mode). The gauges are not reset on close, even with
+//! [`DipstickLayerBuilder::span_gauge_reset`]. The `metrics.level.max` and `metrics.timer.busy`
+//! are not affected, they still work on the whole lifetime of the span.
+//!
+//! ```rust
+//! let span = tracing::info_span!(
+//!     "poll",
+//!     metrics.on = "enter",
+//!     metrics.counter = "polls",
+//!     metrics.timer = "poll_time",
+//! );
+//! for _ in 0..3 {
+//!     let _guard = span.enter();
+//! }
+//! ```
+//!
+//! # Busy time
+//!
+//! The `metrics.timer` measures the whole lifetime of a span. For spans of async tasks, that
+//! includes the time the task waits for something (while the span is not entered). The
+//! `metrics.timer.busy` timer instead sums the times between each enter and the matching exit of
+//! the span and records the total when the span closes. If the span is entered on multiple
+//! threads at once, the times of all of them are summed, so the total can exceed the lifetime of
+//! the span.
+//!
+//! ```rust
+//! let span = tracing::info_span!("task", metrics.timer.busy = "busy");
+//! for _ in 0..2 {
+//!     let _guard = span.enter();
+//!     // Do some work
+//! }
+//! ```
+//!
+//! # Counting child spans
+//!
+//! A span with `metrics.count_children="name"` counts all the spans created inside it (its
+//! descendants, no matter how deep and on which threads, including the ones without any metrics).
+//! Once it closes, the number is added to the counter called `name` (in the span's own scope).
+//! Nested spans with this attribute count their own descendants independently.
+//!
+//! ```rust
+//! let batch = tracing::info_span!("batch", metrics.count_children = "items");
+//! batch.in_scope(|| {
+//!     for _ in 0..3 {
+//!         let _item = tracing::info_span!("item").entered();
+//!     }
+//! });
+//! ```
+//!
+//! # Conditional metrics
+//!
+//! Sometimes a metric should be recorded only in some cases, but branching around the event just
+//! for that would be clumsy. With `metrics.when="field"`, the event records its metrics only if
+//! its field called `field` is `true`. If it is `false`, nothing is recorded by the event at all
+//! (including the counters of [`DipstickLayerBuilder::message_counters`] and similar).
+//!
+//! If the event has no such field, the guard has no effect and the metrics are recorded (with
+//! [`DipstickLayerBuilder::warn_unknown`], a warning is emitted through [`tracing`], as this is
+//! likely a typo). The same goes for a field that is not a `bool` (in this case the warning is
+//! emitted always, once for each such field).
+//!
+//! ```rust
+//! let cache_hit = true;
+//! tracing::info!(cache_hit, metrics.when = "cache_hit", metrics.counter = "hits");
+//! ```
+//!
+//! # Timers across events
+//!
+//! Some flows don't have a span covering them, as they start and end in unrelated places. An
+//! event with `metrics.timer.start="name"` starts a timer and a later event with
+//! `metrics.timer.stop="name"` records the time elapsed since then. The events may come from
+//! different threads, but they need to be in the same scope, the timers are matched by the full
+//! name. There's at most one running timer of each name; starting it again while it's running
+//! restarts it. A stop without a running timer is ignored and a warning is emitted through
+//! [`tracing`] (once for each such field).
+//!
+//! ```rust
+//! tracing::info!(metrics.timer.start = "sync", "Sync requested");
+//! // Possibly somewhere else
+//! tracing::info!(metrics.timer.stop = "sync", "Sync done");
+//! ```
+//!
+//! # Peaks
+//!
+//! A `metrics.level` shows how many spans (eg. requests) are in flight right now, but short
+//! spikes are easily missed between the flushes. A span with `metrics.peak="name"` counts as
+//! holding the peak called `name` between its creation and closing and the gauge called `name` is
+//! set to the highest number of spans holding it at the same time. The gauge is set on every
+//! creation and closing of such span (therefore it keeps being reported even if it doesn't
+//! change), but it never decreases.
+//!
+//! The peaks are tracked by the full name (including the scope), across the whole application
+//! (unlike `metrics.level.max`, which looks only at a subtree of spans). The current counts are
+//! kept by the layer, so no `metrics.level` is needed ‒ but if one is used too, it needs a
+//! different name than the peak.
+//!
+//! ```rust
+//! let _request = tracing::info_span!("request", metrics.peak = "requests.peak").entered();
+//! ```
+//!
+//! # Spans never entered
+//!
+//! A span created and dropped without ever being entered is often a mistake (eg. a forgotten
+//! `.entered()`); its timers then measure nothing meaningful. A span with
+//! `metrics.not_entered="name"` bumps the counter called `name` (in its own scope) when it closes
+//! without having been entered. Nothing is recorded for the spans that were entered at least once.
+//! The other metrics of the span are recorded as usual either way.
+//!
+//! ```rust
+//! // Oops, forgot to enter it
+//! let _request = tracing::info_span!("request", metrics.not_entered = "dead_spans");
+//! ```
+//!
+//! # Names from fields
+//!
+//! Sometimes the name of a metric is known only at runtime (eg. a counter for each HTTP status
+//! code). The `metrics.counter.dynamic="field"` attribute counts a counter named by the value of
+//! the sibling field called `field`. String values are used as they are, other values are
+//! formatted (eg. the number `404` becomes the name `404`). The name is placed into the scope as
+//! any other counter, so it gets all the `metrics.scope` prefixes. An empty value makes a bare
+//! metric. If the event or span has no field of that name, nothing is counted. Keep in mind each
+//! distinct value creates a new metric, so this is suitable only for fields with a small set of
+//! possible values.
+//!
+//! ```rust
+//! let _span = tracing::info_span!("http", metrics.scope = "status").entered();
+//! // Counted as `status.404`
+//! tracing::info!(status = 404, metrics.counter.dynamic = "status");
+//! ```
+//!
+//! # Recording fields later
+//!
+//! The fields of a span may be declared empty ([`tracing::field::Empty`]) and
+//! [recorded][tracing::Span::record] later. The `metrics.*` fields recorded this way are processed
+//! the same as if they were present when the span was created, into the span's scope. That way a
+//! span can eg. count the outcome it learns about only at the end. The metrics tied to the
+//! lifetime of the span (timers, levels) still end when the span closes. As usual, the fields
+//! need to be declared on the span, even if empty, as [`tracing`] doesn't accept undeclared ones.
+//!
+//! The scope of the span can't change after its creation, therefore `metrics.scope`,
+//! `metrics.scope.full`, `metrics.scope.field` and the tags recorded later are ignored (with a
+//! warning, if [`DipstickLayerBuilder::warn_unknown`] is enabled). Similarly, the attributes
+//! configuring the span (eg. `metrics.summary` or `metrics.on`) take effect only at creation.
+//!
+//! ```rust
+//! use tracing::field::Empty;
+//!
+//! let span = tracing::info_span!("request", metrics.scope = "request", metrics.counter = Empty);
+//! // Later on, once the outcome is known
+//! span.record("metrics.counter", "failed");
+//! ```
+//!
+//! # Scopes from fields
+//!
+//! The `metrics.scope.field="field"` attribute names the scope of a span by the runtime value of
+//! its sibling field called `field` (eg. an ID of a tenant). Like with `metrics.counter.dynamic`,
+//! string values are used as they are and other values are formatted. It stacks with the static
+//! `metrics.scope` (or `metrics.scope.full`) of the same span, the value comes after it (and
+//! before the [tags](#tags)). If the span has no such field, the scope is not extended by
+//! anything (and, with [`DipstickLayerBuilder::warn_unknown`], a warning is emitted). As each
+//! distinct value creates new metrics, this is suitable only for fields with a small set of
+//! possible values.
+//!
+//! The name is different from `metrics.counter.dynamic`, as `metrics.scope.dynamic` already means
+//! [following the dynamic parent](#following-the-dynamic-parent).
+//!
+//! ```rust
+//! let _span = tracing::info_span!(
+//!     "request",
+//!     tenant = "acme",
+//!     metrics.scope = "requests",
+//!     metrics.scope.field = "tenant",
+//! )
+//! .entered();
+//! // Counted as `requests.acme.handled`
+//! tracing::info!(metrics.counter = "handled");
+//! ```
+//!
+//! # Non-string scopes
+//!
+//! Sometimes a scope is best named by a number (eg. the id of a shard). Values of `metrics.scope`
+//! and `metrics.scope.full` other than strings are formatted through their
+//! [`Debug`] implementation. To keep the names of metrics sane, the result is
+//! truncated to 64 bytes. Only the scope attributes themselves are formatted, other fields are
+//! not affected.
+//!
+//! ```rust
+//! let shard_id = 3;
+//! let _span = tracing::info_span!("shard", metrics.scope = shard_id).entered();
+//! // Counted as `3.writes`
+//! tracing::info!(metrics.counter = "writes");
+//! ```
+//!
+//! # Scopes of events
+//!
+//! The `metrics.scope` and `metrics.scope.full` attributes work on events too, but they apply
+//! only to the metrics of the event itself, nothing else is affected. Like with spans,
+//! `metrics.scope` is added to the scope of the span the event is in (or to the root scope, if
+//! there's none) and `metrics.scope.full` replaces it. The tags of the event come after the
+//! event's scope.
+//!
+//! ```rust
+//! let _span = tracing::info_span!("request", metrics.scope = "request").entered();
+//! // Counted as `request.cache.misses`
+//! tracing::info!(metrics.scope = "cache", metrics.counter = "misses");
+//! // Counted as `request.done`
+//! tracing::info!(metrics.counter = "done");
+//! ```
+//!
+//! # Dotted scopes
+//!
+//! A `metrics.scope` containing dots is split into its segments, each becoming a separate nested
+//! scope, as if each segment was on its own span. Therefore, `metrics.scope = "http.server"` is
+//! the same as two spans with `metrics.scope = "http"` and `metrics.scope = "server"`. Empty
+//! segments are skipped. This applies to the [formatted non-string values](#non-string-scopes)
+//! too.
+//!
+//! With the default naming the difference is not visible, as [`dipstick`] joins the prefixes with
+//! dots anyway. It matters when the layer joins the scopes itself with a [different
+//! separator][DipstickLayerBuilder::scope_separator] (the dots become the separator) or for a
+//! [custom backend][DipstickSink] that looks at the individual prefixes.
+//!
+//! The `metrics.scope.full` is not split, the whole value replaces the scope as a single
+//! segment.
+//!
+//! ```rust
+//! // The same as two nested spans, with the scopes `http` and `server`
+//! let _span = tracing::info_span!("server", metrics.scope = "http.server").entered();
+//! ```
+//!
+//! # Tags
+//!
+//! Many backends understand dimensions of metrics (eg. the region or the kind of a request), but
+//! [`dipstick`] has only limited support for labels. Therefore, the tags from `metrics.tag.key =
+//! "value"` are encoded into the names of the metrics: each tag adds the `key` and `value` as two
+//! more segments of the scope, eg. `region.eu.name`. Non-string values are formatted (eg.
+//! `metrics.tag.code = 404` becomes `code.404`).
+//!
+//! Tags of a span come after its own `metrics.scope` and, like the scope, they are inherited by
+//! everything inside the span (so the tags of the outer spans come before the scopes and tags of
+//! the inner ones). Multiple tags on the same span or event are ordered by their keys, regardless
+//! of the order in which they are written, so the names are deterministic. Tags on events apply
+//! to the metrics of that event only, placed after the scope of the span (and before the level
+//! from [`DipstickLayerBuilder::level_scope`]). As the tags are part of the scope,
+//! `metrics.scope.full` replaces the inherited tags as well.
+//!
+//! ```rust
+//! let _outer = tracing::info_span!("outer", metrics.tag.region = "eu").entered();
+//! let _inner = tracing::info_span!(
+//!     "inner",
+//!     metrics.scope = "db",
+//!     metrics.tag.table = "users",
+//!     metrics.tag.kind = "read",
+//! )
+//! .entered();
+//! // Counted as `region.eu.db.kind.read.table.users.queries`
+//! tracing::info!(metrics.counter = "queries");
+//! ```
+//!
+//! # Escaping the scope
+//!
+//! An event with `metrics.scope.reset=true` records its metrics into the root scope (the one the
+//! layer was created with), bypassing the scopes and tags of all the spans it is in. This is
+//! useful for global metrics (eg. a total error counter) recorded deep inside scoped code. Even a
+//! `metrics.scope.full` of a span is bypassed, as it is one of the accumulated prefixes. The tags
+//! of the event itself still apply, as does [`DipstickLayerBuilder::level_scope`].
+//!
+//! ```rust
+//! let _span = tracing::info_span!("request", metrics.scope.full = "requests").entered();
+//! // Counted as `errors`, not `requests.errors`
+//! tracing::error!(metrics.scope.reset = true, metrics.counter = "errors");
+//! ```
+//!
+//! # Separating metrics by severity
+//!
+//! With [`DipstickLayerBuilder::level_scope`], metrics from events at the configured level or
+//! more severe are placed into an additional scope named after the level of the event (`error`,
+//! `warn`, `info`, `debug` or `trace`). This helps to separate the error-path metrics from the
+//! happy-path ones. The level segment comes last, after all the `metrics.scope` prefixes, eg.
+//! `outer.inner.error.name`. Only events are affected, not spans.
+//!
+//! Alternatively, [`DipstickLayerBuilder::append_level_suffix`] appends the level to the name of
+//! each metric instead, eg. `started.info` and `started.error`. This applies to the metrics named
+//! by the fields of both events and spans (using the level of the span), but not to the ones the
+//! layer names itself (eg. `metrics.not_entered`). The scope prefixes still go before the name, so
+//! the result is `outer.inner.name.level`.
+//!
+//! ```rust
+//! use dipstick::AtomicBucket;
+//! use tracing_dipstick::DipstickLayer;
+//! use tracing_subscriber::layer::SubscriberExt;
+//! use tracing_subscriber::Registry;
+//!
+//! let bridge = DipstickLayer::builder(AtomicBucket::new())
+//!     .append_level_suffix(true)
+//!     .finish();
+//! tracing::subscriber::set_global_default(Registry::default().with(bridge)).unwrap();
+//! ```
+//!
+//! # Per-thread metrics
+//!
+//! To break the metrics down by the worker threads, [`DipstickLayerBuilder::append_thread_name`]
+//! appends the name of the current thread to the name of each metric, eg. `requests.worker-1`.
+//! Unnamed threads use their id instead (eg. `thread-3`), which stays the same for the lifetime of
+//! the thread. Like with the [level suffix](#separating-metrics-by-severity), this applies to the
+//! metrics named by the fields and the suffix comes after all the scope prefixes (and after the
+//! level, if both are enabled). The metrics of a span are named by the thread that creates the
+//! span, even if it is closed elsewhere.
+//!
+//! Beware that every thread creates a separate series of each metric. With many threads (or with
+//! threads that come and go, each getting a new id), the number of metrics can grow large.
+//!
+//! ```rust
+//! use dipstick::AtomicBucket;
+//! use tracing_dipstick::DipstickLayer;
+//! use tracing_subscriber::layer::SubscriberExt;
+//! use tracing_subscriber::Registry;
+//!
+//! let bridge = DipstickLayer::builder(AtomicBucket::new())
+//!     .append_thread_name(true)
+//!     .finish();
+//! tracing::subscriber::set_global_default(Registry::default().with(bridge)).unwrap();
+//! ```
+//!
+//! # Sampling
+//!
+//! Recording every metric on an extremely hot path may be too expensive.
+//! [`DipstickLayerBuilder::sample_rate`] records the counters and timers of only a random
+//! fraction of the spans and events (decided for each separately, by a fast thread-local random
+//! generator). The increments of counters are scaled by the inverse of the rate, so the totals
+//! stay approximately correct. Timers (including `metrics.distribution`) are recorded only by the
+//! sampled spans and events, which still gives a fair picture of their distribution (though with
+//! less precise counts).
+//!
+//! Other kinds of metrics don't mix well with sampling, so they are recorded by all the spans and
+//! events. Gauges and levels would drift if some of their adjustments were missing, markers can't
+//! be scaled and a `metrics.timer.stop` could miss its `metrics.timer.start`. Scopes, tags and
+//! the other attributes are not affected by sampling either.
+//!
+//! ```rust
+//! use dipstick::AtomicBucket;
+//! use tracing_dipstick::DipstickLayer;
+//! use tracing_subscriber::layer::SubscriberExt;
+//! use tracing_subscriber::Registry;
+//!
+//! let bridge = DipstickLayer::builder(AtomicBucket::new())
+//!     .sample_rate(0.1)
+//!     .finish();
+//! tracing::subscriber::set_global_default(Registry::default().with(bridge)).unwrap();
+//! ```
+//!
+//! # Limiting the rate of counters
+//!
+//! A bug in the instrumentation (eg. a counter bumped in a hot loop) can flood the metrics
+//! backend. With [`DipstickLayerBuilder::counter_rate_limit`], each counter is sent at most once
+//! per the given interval. The increments in between are summed together and sent as one, so the
+//! total stays correct, but it may show up with a delay of up to about twice the interval (a
+//! background thread sends the leftovers of counters that are no longer being bumped, see
+//! [`DipstickLayer::tick`]).
+//!
+//! # Detecting bursts
+//!
+//! A counter shows how many times something happened, but not whether it happened steadily or
+//! in bursts. With [`DipstickLayerBuilder::counter_bursts`], the layer additionally sums the
+//! increments of each counter within fixed time windows and at the end of each window records the
+//! sum into a timer named `name.window` (in the same scope as the counter). The windows are
+//! measured by a background thread (see [`DipstickLayer::tick`]), starting when the layer is
+//! created; a counter that is not bumped at all during a window records 0. The distribution of the
+//! timer (eg. with [`stats_all`][dipstick::stats_all] on the bucket) then tells the steady traffic
+//! (all the windows similar) from bursts (mostly zeroes with occasional large values).
+//!
+//! As the outputs present timers in milliseconds, the sums are recorded as if they were
+//! milliseconds, so they show up as the plain numbers. Every counter, once bumped, stays tracked
+//! (and recorded every window) for the lifetime of the layer, therefore this is not suitable for
+//! counters with unbounded number of names.
+//!
+//! # Sharded counters
+//!
+//! Every counter increment normally goes straight to the scope, which (eg. with an
+//! [`AtomicBucket`]) means looking the metric up in a structure shared by
+//! all the threads. When many threads bump the same hot counters, they contend on it. With
+//! [`DipstickLayerBuilder::sharded_counters`], the increments are instead accumulated in one of
+//! several shards (twice as many as there are CPUs). Each thread is assigned a shard the first
+//! time it bumps a counter, in a round-robin fashion, so threads mostly don't share them. A
+//! background thread (see [`DipstickLayer::tick`]) merges the accumulated increments into the
+//! scope once per the given interval (and the leftovers are merged when the layer is dropped).
+//! The counters therefore show up with a delay of up to the interval.
+//!
+//! This takes precedence over [`DipstickLayerBuilder::counter_rate_limit`] (the merging limits the
+//! rate anyway). The `counter_contention` example compares the throughput with and without it.
+//!
+//! # Deferring metrics to span close
+//!
+//! Recording a metric means looking it up in the scope (and possibly sending it somewhere), which
+//! costs some time on the hot path of the instrumented code. With
+//! [`DipstickLayerBuilder::defer_to_close`], the metrics of events inside a span are only stored
+//! in the span and recorded all together once the span closes, moving the cost there. The
+//! metrics therefore become visible only after the span closes (possibly much later for long-lived
+//! spans). The events outside of any span are recorded right away, as are the metrics of the spans
+//! themselves. The stored metrics take memory until the span closes, so this is not suitable for
+//! long-lived spans with many events inside.
+//!
+//! Each event stores its metrics into the innermost span it happens in. Each span records only
+//! the metrics stored into it, in the order they were stored, when it closes ‒ the metrics of a
+//! child span are not passed to the parent, so they are recorded when the child closes even if
+//! the parent is still open. The metrics keep the scope they would have had without the deferring.
+//!
+//! The `deferred_metrics` example compares the hot path cost with and without it.
+//!
+//! # Batching timer values
+//!
+//! Each explicit timer value (`metrics.timer.name=value`, `metrics.distribution.name=value` and
+//! the [timers across events](#timers-across-events)) is normally sent to its timer right away,
+//! looking the timer up in the scope every time. With [`DipstickLayerBuilder::timer_batch`], the
+//! values recorded by the events inside a span are instead collected in the span, separately for
+//! each timer, and sent together once there are as many of them as the batch size. The timer is
+//! looked up only once for the span.
+//!
+//! The values therefore become visible with a delay. Up to one less than the batch size of them
+//! may wait in the span for the next ones, until the span closes (or, if it [follows its dynamic
+//! parent](#following-the-dynamic-parent), until its scope changes on the next enter). A long-lived
+//! span with rare values may hold them for a long time, so the batch size should be small compared
+//! to how many values a span usually gets. The events outside of any span and the fields of the
+//! spans themselves are sent right away. The `metrics.summary` of the span sees the values right
+//! away too. With [`DipstickLayerBuilder::defer_to_close`], the values of events are deferred
+//! instead.
+//!
+//! The `timer_batch` example compares the hot path cost with and without it.
+//!
+//! # Ordering of timers
+//!
+//! For debugging, it can be useful to reconstruct the order in which the timed operations
+//! happened. With [`DipstickLayerBuilder::timer_sequence`], each timed span gets a sequence number
+//! when it's created and it is attached to the recorded time as the `seq` label. The sequence is
+//! global to the layer (not per metric name), so it orders all the timed spans together. Note that
+//! many [`dipstick`] outputs ignore labels.
+//!
+//! # Statistics of timers
+//!
+//! The layer records every single measurement, the statistics are computed by [`dipstick`]. An
+//! [`AtomicBucket`] aggregates each metric into a count, sum, minimum,
+//! maximum, mean and rate; it has no percentiles. Which of these are published is decided for the
+//! whole bucket, by [`AtomicBucket::stats`][dipstick::AtomicBucket::stats]. Therefore, the layer
+//! can't make a single metric produce other statistics (such as percentiles). However, the
+//! function passed to `stats` gets the full name of each metric, so it can make the choice per
+//! metric by a naming convention (or a dedicated `metrics.scope`). For real percentiles, a custom
+//! [`DipstickSink`] can pass the timers to a histogram of another library (see [naming](#naming)).
+//!
+//! The `metrics.timer.percentiles="name"` attribute at least records the intent. It times the span
+//! the same way as `metrics.timer` and, in addition, sets a companion gauge called
+//! `name.percentiles` to 1 (in the same scope) the first time such a span is created. The hint is
+//! sent only once for each timer (by its full name), so a custom [`DipstickSink`] that wants to
+//! find the timers needing percentiles has to remember the ones it got.
+//!
+//! ```rust
+//! let _query = tracing::info_span!("query", metrics.timer.percentiles = "query").entered();
+//! ```
+//!
+//! ```rust
+//! use dipstick::{AtomicBucket, InputKind, ScoreType};
+//! use tracing_dipstick::DipstickLayer;
+//!
+//! let bucket = AtomicBucket::new();
+//! // Only the mean for all the timers, except the latencies get the maximum too
+//! bucket.stats(|kind, name, score| match (kind, score) {
+//!     (InputKind::Timer, ScoreType::Mean(mean)) => Some((kind, name, mean as isize)),
+//!     (InputKind::Timer, ScoreType::Max(max)) if name.join(".").ends_with("latency") => {
+//!         Some((kind, name.make_name("max"), max))
+//!     }
+//!     _ => None,
+//! });
+//! let bridge = DipstickLayer::new(bucket);
+//! # drop(bridge);
+//! ```
+//!
+//! # Following the dynamic parent
+//!
+//! The scope of a span is derived from its parent at the time the span is created. In some async
+//! executors, a span may be created in one context, but then entered (polled) in another one. With
+//! [`DipstickLayerBuilder::dynamic_scopes`] turned on, spans marked with
+//! `metrics.scope.dynamic=true` recompute their scope every time they are entered, based on the
+//! span they are entered in (or the root scope if there's none). Metrics recorded after that (by
+//! the events inside or by newly created child spans) use the new prefix. The metrics of the span
+//! itself (eg. its timers and levels) are already created and keep their original names.
+//!
+//! This is not free. To know the span it is entered in, the layer tracks the spans entered on each
+//! thread, which costs a bit on every enter and exit of every span (even the unmarked ones).
+//! Furthermore, the marked spans look up the parent scope and possibly construct the new name on
+//! each enter. Therefore, this is off by default and the marker is ignored.
+//!
+//! # Detecting misconfiguration
+//!
+//! Discovering only in production that no metrics were recorded at all (because of some wiring
+//! mistake) is unpleasant. With [`DipstickLayerBuilder::watchdog`], the layer checks after the
+//! given time from its creation whether at least one metric was recorded. If not, it emits a
+//! warning through [`tracing`] (and optionally bumps a counter, see
+//! [`DipstickLayerBuilder::watchdog_counter`]). As the check runs in a background thread (see
+//! [`DipstickLayer::tick`]), the warning is seen only by the global default subscriber. The
+//! watchdog is disabled by default.
+//!
+//! # Self-monitoring
+//!
+//! To notice when the instrumentation stops flowing (eg. after a deployment), the layer can count
+//! its own work. With [`DipstickLayerBuilder::self_monitoring`], every metric operation the layer
+//! performs (a counter bumped, a gauge set, a timer recorded, …) also bumps a counter in the root
+//! scope, named `tracing_dipstick.emitted` by default (see
+//! [`DipstickLayerBuilder::self_monitoring_counter`]). The counter itself is not counted, nor are
+//! the metrics the layer produces on its own behalf (eg. the [watchdog
+//! counter][DipstickLayerBuilder::watchdog_counter]). After [replacing the root
+//! scope][DipstickLayer::set_scope], the counter moves to the new one.
+//!
+//! ```rust
+//! use dipstick::AtomicBucket;
+//! use tracing_dipstick::DipstickLayer;
+//! use tracing_subscriber::layer::SubscriberExt;
+//! use tracing_subscriber::Registry;
+//!
+//! let bridge = DipstickLayer::builder(AtomicBucket::new())
+//!     .self_monitoring(true)
+//!     .finish();
+//! tracing::subscriber::set_global_default(Registry::default().with(bridge)).unwrap();
+//! ```
+//!
+//! # Gauges from span fields
+//!
+//! Spans often carry numbers as ordinary fields (eg. `cnt = 10`). With
+//! [`DipstickLayerBuilder::field_gauges`], the fields with the listed names are recorded as gauges
+//! when a span is created, without the need for the `metrics.gauge.*` annotation. The gauge has the
+//! same name as the field and it is placed into the scope of the span (including the span's own
+//! `metrics.scope`). Only numeric fields (integers and floats) are captured, other values are
+//! ignored. The `metrics.*` fields can't be listed, and fields of events are not captured.
+//!
+//! To capture all the fields of a span without listing them, mark the span with
+//! `metrics.auto_gauge = true`. Every numeric field of such span is then recorded as a gauge the
+//! same way, named after the field and placed into the scope of the span. The `bool` fields count
+//! as `1` and `0`, the string and other fields are skipped, as are the `metrics.*` fields
+//! themselves. This affects only the span itself, not its descendants.
+//!
+//! ```rust
+//! // Records the gauges `batch.size` and `batch.ratio` (rounded to 1)
+//! let _span = tracing::info_span!(
+//!     "batch",
+//!     metrics.scope = "batch",
+//!     metrics.auto_gauge = true,
+//!     size = 42,
+//!     ratio = 0.75,
+//!     name = "import",
+//! )
+//! .entered();
+//! ```
+//!
+//! # Ratios of counters
+//!
+//! Derived metrics, like the error rate, are often more interesting than the raw counters. With
+//! [`DipstickLayerBuilder::counter_ratio`], the layer sums the increments of the numerator and
+//! denominator counters within time windows (one second by default, see
+//! [`DipstickLayerBuilder::ratio_window`], measured by a background thread, see
+//! [`DipstickLayer::tick`]) and at the end of each window sets a gauge (in the root scope) to their
+//! ratio. As gauges hold integers, the ratio is multiplied by the given scale first (eg. 1000 to
+//! get the ratio in per-mille). The counters are identified by their full names, including the
+//! prefixes from `metrics.scope` joined by `.`. If the denominator is 0 in a window, the gauge is
+//! left untouched.
+//!
+//! # Stale gauges
+//!
+//! A gauge keeps its last value forever, even if whatever updated it stopped doing so long ago.
+//! With [`DipstickLayerBuilder::stale_gauges`], a gauge (set through `metrics.gauge`) that is not
+//! updated within the given timeout is set to the given reset value (eg. 0), so dashboards don't
+//! show misleadingly fresh data. The check is done by a background thread (see
+//! [`DipstickLayer::tick`]) every half of the timeout, so the reset happens between the timeout
+//! and one and a half of it after the last update. The reset is done only once, the gauge is
+//! tracked again once it is updated.
+//!
+//! # Panics while recording
+//!
+//! The scopes don't report errors, the outputs of [`dipstick`] deal with them on their own. But
+//! a scope (especially a custom one) may panic. Such panic is caught by the layer, so it doesn't
+//! propagate into the instrumented code, and is reported through
+//! [`DipstickLayerBuilder::on_error`] (or by a warning through [`tracing`] the first time, if no
+//! hook is set). The metrics of the span or event being processed at the time may be lost or
+//! incomplete, but the layer keeps working for the others. The scope is not called while the
+//! layer holds a span locked, so the panic doesn't break the span for other layers, and what the
+//! span already holds until it's closed (eg. its levels) is still finished then.
+//!
+//! # Panics in the instrumented code
+//!
+//! When the instrumented code panics (and `panic = "unwind"` is used), the spans are dropped
+//! during the unwinding and their metrics are finished as usual. Timers are stopped at that point
+//! (so they include the time up to the panic and the unwinding up to the span) and levels are
+//! decremented, so nothing stays "in flight" forever.
+//!
+//! A panic in the scope while finishing these metrics is caught too (see
+//! [above](#panics-while-recording)). Some parts of the metrics are finished by destructors that
+//! may run while such panic is already being handled; a panic escaping a destructor at that point
+//! would abort the whole process, so these parts catch the panics themselves and throw them away
+//! without reporting.
+//!
+//! # Flushing on panic
+//!
+//! With `panic = "abort"`, no destructors run when the program panics, so the metrics still
+//! buffered (eg. in an [`AtomicBucket`] waiting for the next scheduled
+//! flush) are lost. With [`DipstickLayerBuilder::flush_on_panic`], the layer installs a panic hook
+//! that flushes the root scope. It is chained after the previously installed hook (which runs
+//! first, so the panic message is not delayed). The hook is installed only once, with the first
+//! such layer, and it flushes the root scopes of all the layers still alive at the time of the
+//! panic. It stays installed for the rest of the program, but it doesn't keep the layers alive.
+//!
+//! Only the root scope is flushed, not the scopes of the spans (from `metrics.scope` and the
+//! like). This is enough for the scopes of [`dipstick`], as the scopes of the spans only prefix
+//! the names of the metrics and share the buffer of the root. A custom [`DipstickSink`] buffering
+//! the metrics of each scope separately needs to flush them from its root.
+//!
+//! The hook doesn't finalize the metrics of the spans open at the time of the panic (their timers
+//! and levels). With `panic = "unwind"`, these are finalized as usual once the spans are dropped
+//! during the unwinding ‒ which happens only after the hook, so they are sent with some later
+//! flush; nothing is recorded twice. With `panic = "abort"`, they are lost.
+//!
+//! # Periodic flushing of scopes
+//!
+//! Usually, the whole bucket is flushed at one cadence (eg. through
+//! [`ScheduleFlush::flush_every`]). Some subsystems may need their metrics sent more often. A span
+//! with `metrics.flush_every = seconds` (an integer or a float, eg. `0.5`) flushes its scope at
+//! that interval, for as long as the span is open; the schedule is cancelled once the span closes.
+//! The flushes are part of the periodic work of the layer, which runs in its background thread on
+//! the schedule of its [clock](DipstickLayerBuilder::clock) and can be run by hand with
+//! [`DipstickLayer::tick`]. Values that are not positive are ignored.
+//!
+//! Every such span schedules its own flushes, so nested spans with the attribute each add theirs
+//! (and many concurrent spans mean many scheduled tasks ‒ this is meant for long-running spans).
+//! What a flush of a scope does depends on the backend. Many (including
+//! [`AtomicBucket`]) flush all the metrics of the bucket, not only the
+//! ones in the scope.
+//!
+//! ```rust
+//! let _sync = tracing::info_span!("sync", metrics.scope = "sync", metrics.flush_every = 0.5)
+//!     .entered();
+//! ```
+//!
+//! # Freshness of metrics
+//!
+//! With [`DipstickLayerBuilder::seen_gauges`], the layer additionally records when each metric
+//! occurred for the first and for the last time, as gauges named `name.first_seen` and
+//! `name.last_seen` (in the same scope as the metric itself). The values are in seconds since the
+//! UNIX epoch, following the [clock](DipstickLayerBuilder::clock) of the layer. To bound the
+//! memory used to remember the metrics already seen, only a limited number of distinct metrics is
+//! tracked. The metrics over the limit get neither of these gauges, so a missing (or stale)
+//! `last_seen` doesn't mean the metric doesn't happen. The layer warns (once) when it hits the
+//! limit; the limit should be set above the number of distinct metrics expected.
+//!
+//! # Counting log messages
+//!
+//! With [`DipstickLayerBuilder::message_counters`], every event (with or without the `metrics.*`
+//! attributes) bumps a counter identifying the place in the code the event comes from. As the
+//! actual message template is not available to the layer (only the formatted message is), the
+//! static name of the callsite is used instead ‒ it has the same cardinality (each template lives
+//! at a single callsite) and it is bounded. The macros of [`tracing`] name the events by the file
+//! and line (`event src/db.rs:42`), unless given an explicit `name:`. The counter is called
+//! `prefix.name`, with the `event ` dropped from the default names and every character except
+//! ASCII letters, digits and `_` replaced by `_`. For example, an event on line 42 of
+//! `src/db.rs` is counted as `prefix.src_db_rs_42`, while
+//! `tracing::warn!(name: "login_failed", "Login failed for {}", user)` is counted as
+//! `prefix.login_failed`, wherever it is.
+//!
+//! # Counting errors
+//!
+//! With [`DipstickLayerBuilder::auto_error_marker`], every event at the `ERROR` level fires a
+//! marker of the given name, without the need to annotate each place that logs an error. The
+//! marker is placed into the scope of the current span, like other metrics of the event (including
+//! the scope added by [`DipstickLayerBuilder::level_scope`]). Any `metrics.*` attributes of the
+//! event are recorded as usual, in addition to the marker.
+//!
+//! ```rust
+//! use dipstick::AtomicBucket;
+//! use tracing_dipstick::DipstickLayer;
+//! use tracing_subscriber::layer::SubscriberExt;
+//! use tracing_subscriber::Registry;
+//!
+//! let bridge = DipstickLayer::builder(AtomicBucket::new())
+//!     .auto_error_marker("errors")
+//!     .finish();
+//! tracing::subscriber::set_global_default(Registry::default().with(bridge)).unwrap();
+//! ```
+//!
+//! # Filtering
+//!
+//! The layer needs to see all the spans and events to gather correct metrics, while other layers
+//! (eg. logging) usually want to see only some of them. A global filter (eg. an
+//! `EnvFilter` added through `.with(filter)`) disables the spans and events for the whole stack,
+//! including this layer. Instead, the other layers should use per-layer filters (through
+//! [`Layer::with_filter`]), which affect only them.
+//!
+//! To make sure this layer is not affected, wrap it with [`DipstickLayer::accept_all`]. It places
+//! the layer under its own per-layer filter that lets everything through (and tells the
+//! subscriber so, even if the other filters give hints about the maximum level they are
+//! interested in).
+//!
+//! A span filtered out for this layer doesn't get a scope. The events inside it are then
+//! recorded into the scope of the nearest ancestor that has one (or the root scope).
+//!
+//! Alternatively, the `tracing`s `log-always` feature can be used to route the logs through the
+//! [`log`](https://docs.rs/log) crate, filtering them there, as in the example below.
+//!
+//! To stop recording for a while (eg. during maintenance), without touching the subscriber, see
+//! [`DipstickLayer::set_enabled`].
 //!
 //! # Naming
 //!
@@ -45,10 +978,98 @@
 //! the attributes are the same ‒ in such case changing the "backend" means only different
 //! initialization while the instrumentation of the whole code stays the same.
 //!
+//! Within this crate, the backend is represented by the [`DipstickSink`] trait (also available as
+//! [`MetricSink`]). While its methods return the [`dipstick`] metric handles, these are just thin
+//! wrappers around a closure (see [`metric::input_metric`]), so the values can be forwarded
+//! anywhere, without any [`dipstick`] output being involved. The attribute parsing is shared.
+//!
+//! The [`dipstick`] dependency itself is behind the `dipstick` feature (on by default). Without it,
+//! the [`metric`] module provides minimal stand-ins for the handles and only such sinks can be
+//! used (eg. the one below, or the one of the `metrics` feature). The [`dipstick`] specific parts
+//! (the blanket implementation of the trait for its scopes, [`DipstickLayer::to_stdout`], the
+//! [`Flat`] scope and the `metrics.flush_every` attribute) are not available then.
+//!
+//! ```rust
+//! use std::sync::{Arc, Mutex};
+//!
+//! use tracing_dipstick::metric::{
+//!     input_metric, Counter, Gauge, InputMetric, Level, Marker, NameParts, Timer,
+//! };
+//! use tracing_dipstick::{DipstickLayer, DipstickSink};
+//! use tracing_subscriber::layer::SubscriberExt;
+//! use tracing_subscriber::Registry;
+//!
+//! /// A stand-in for a different metrics library, receiving the values by full names.
+//! #[derive(Clone, Default)]
+//! struct Backend {
+//!     prefixes: NameParts,
+//!     sent: Arc<Mutex<Vec<(String, isize)>>>,
+//! }
+//!
+//! impl Backend {
+//!     fn metric(&self, name: &str) -> InputMetric {
+//!         let mut full: Vec<_> = self.prefixes.iter().cloned().collect();
+//!         full.push(name.to_owned());
+//!         let full = full.join(".");
+//!         let sent = Arc::clone(&self.sent);
+//!         input_metric(name, move |value, _| {
+//!             sent.lock().unwrap().push((full.clone(), value));
+//!         })
+//!     }
+//!     fn with_prefixes(&self, prefixes: NameParts) -> Self {
+//!         Backend { prefixes, sent: Arc::clone(&self.sent) }
+//!     }
+//! }
+//!
+//! impl DipstickSink for Backend {
+//!     fn counter(&self, name: &str) -> Counter {
+//!         self.metric(name).into()
+//!     }
+//!     fn marker(&self, name: &str) -> Marker {
+//!         self.metric(name).into()
+//!     }
+//!     fn gauge(&self, name: &str) -> Gauge {
+//!         self.metric(name).into()
+//!     }
+//!     fn level(&self, name: &str) -> Level {
+//!         self.metric(name).into()
+//!     }
+//!     fn timer(&self, name: &str) -> Timer {
+//!         self.metric(name).into()
+//!     }
+//!     fn add_name(&self, name: &str) -> Self {
+//!         let mut prefixes = self.prefixes.clone();
+//!         prefixes.push_back(name.to_owned());
+//!         self.with_prefixes(prefixes)
+//!     }
+//!     fn named(&self, name: &str) -> Self {
+//!         self.with_prefixes(NameParts::from(name))
+//!     }
+//!     fn prefixes(&self) -> &NameParts {
+//!         &self.prefixes
+//!     }
+//! }
+//!
+//! let backend = Backend::default();
+//! let layer = DipstickLayer::new(backend.clone());
+//! let subscriber = Registry::default().with(layer);
+//! tracing::subscriber::with_default(subscriber, || {
+//!     let _span = tracing::info_span!("db", metrics.scope = "db").entered();
+//!     tracing::info!(metrics.counter.queries = 2);
+//! });
+//! assert_eq!(vec![("db.queries".to_owned(), 2)], *backend.sent.lock().unwrap());
+//! ```
+//!
+//! With the `metrics` feature, the crate provides such a backend for the [`metrics`
+//! crate](https://docs.rs/metrics) ‒ the `MetricsRs` scope.
+//!
+//! With the `opentelemetry` feature, there's also one for the instruments of an
+//! [OpenTelemetry](https://docs.rs/opentelemetry) meter ‒ the `OpenTelemetry` scope. Neither needs
+//! the `dipstick` feature.
+//!
 //! # Crate status
 //!
-//! * There are some limitations about filtering (see the note at [`DipstickLayer`]). They may be
-//!   fixed either in [`tracing_subscriber`] or by changes in here, but both needs some work.
+//! * Global filters of other layers still limit the metrics (see [filtering](#filtering)).
 //! * There are several performance inefficiencies that need to be eliminated.
 //! * The crate has been tested only lightly and it's possible it might not act correctly in some
 //!   corner cases.
@@ -56,8 +1077,18 @@
 //! So, there's still some work to happen (and help in doing it is welcome). On the other hand, it
 //! is unlikely to cause some _serious_ problems, only incorrect metric readings.
 //!
+//! # Testing
+//!
+//! With the `testing` feature, the [`testing`][mod@testing] module offers an in-memory scope and
+//! helpers to check what metrics the instrumented code produces. To check the exact durations
+//! measured by timers, a mock [`Clock`] (eg. the [`ManualClock`][testing::ManualClock]) can be set
+//! through [`DipstickLayerBuilder::clock`]. The periodic work of the layer follows the clock too
+//! and can be run right away by [`DipstickLayer::tick`].
+//!
 //! # Examples
 //!
+//! The [`prelude`] contains the items needed for the usual setup.
+//!
 //! ```
 //! use std::thread;
 //! use std::time::Duration;
@@ -112,50 +1143,276 @@
 //!
 //! ```
 //!
+//! The setup of the bucket printed to stdout is also available in one call, as
+//! [`DipstickLayer::to_stdout`].
+//!
 //! [`tracing`]: https://docs.rs/tracing
+//! [`InputScope`]: dipstick::InputScope
 #![doc(test(attr(deny(warnings))))]
 #![forbid(unsafe_code)]
 #![warn(missing_docs)]
 
-use std::fmt::Debug;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::hash_map::{Entry, RandomState};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::hash::{BuildHasher, Hasher};
+use std::mem;
+use std::ops::Range;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Once, RwLock, TryLockError, Weak};
+use std::thread::{self, Thread, ThreadId};
+use std::time::{Duration, Instant, SystemTime};
 
-use dipstick::{InputScope, Level, Prefixed, TimeHandle, Timer};
-use once_cell::unsync::Lazy;
+#[cfg(feature = "dipstick")]
+use dipstick::{
+    AtomicBucket, Cancel, CancelGuard, CancelHandle, InputScope, ScheduleFlush, Stream,
+};
+use once_cell::unsync::OnceCell;
+use smallvec::SmallVec;
 use tracing_core::field::{Field, Visit};
-use tracing_core::span::{Attributes, Id};
-use tracing_core::{Event, Subscriber};
+use tracing_core::span::{Attributes, Id, Record};
+use tracing_core::{Event, Level as TracingLevel, Metadata, Subscriber};
+use tracing_subscriber::filter::{Filtered, LevelFilter};
 use tracing_subscriber::layer::{Context, Layer};
-use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::registry::{LookupSpan, SpanRef};
+
+mod macros;
+mod maintenance;
+pub mod metric;
+pub mod prelude;
+mod recorder;
+mod sink;
+mod summary;
+#[cfg(feature = "testing")]
+pub mod testing;
+
+use maintenance::{Maintenance, TaskGuard};
+use metric::{Counter, Gauge, Labels, Level, Timer};
+pub use recorder::Recorder;
+#[cfg(feature = "metrics")]
+pub use sink::metrics_rs::MetricsRs;
+#[cfg(feature = "opentelemetry")]
+pub use sink::opentelemetry::OpenTelemetry;
+/// The sink is not tied to [`dipstick`], this is the backend-neutral name of it.
+pub use sink::DipstickSink as MetricSink;
+#[cfg(feature = "dipstick")]
+pub use sink::Flat;
+pub use sink::{DipstickSink, DynScope, Separated};
+pub use summary::Summary;
+use summary::{SummaryCell, SummaryHook};
+// For the macros
+#[doc(hidden)]
+pub use tracing as __tracing;
 
 const SCOPE_NAME: &str = "metrics.scope";
 const SCOPE_NAME_FULL: &str = "metrics.scope.full";
+const SCOPE_DYNAMIC: &str = "metrics.scope.dynamic";
+const SCOPE_FIELD: &str = "metrics.scope.field";
+const SCOPE_RESET: &str = "metrics.scope.reset";
+const COUNT_CHILDREN: &str = "metrics.count_children";
+const NOT_ENTERED: &str = "metrics.not_entered";
+const TIMER_START: &str = "metrics.timer.start";
+const TIMER_STOP: &str = "metrics.timer.stop";
+const SUMMARY: &str = "metrics.summary";
+const COUNTER_DYNAMIC: &str = "metrics.counter.dynamic";
+const TAG_PREFIX: &str = "metrics.tag.";
+/// The default name of the counter of [`DipstickLayerBuilder::self_monitoring`].
+const SELF_MONITORING_COUNTER: &str = "tracing_dipstick.emitted";
+const METRICS_ON: &str = "metrics.on";
+const METRICS_WHEN: &str = "metrics.when";
+const AUTO_GAUGE: &str = "metrics.auto_gauge";
+const LEVEL_GAUGE: &str = "metrics.level_value";
+const FLUSH_EVERY: &str = "metrics.flush_every";
+const TARGET_NAME: &str = "@target";
+const FIELD_COUNT_VALUE: &str = "@field_count";
+const LEVEL_VALUE: &str = "@level";
 
 #[derive(Copy, Clone, Debug)]
 enum MetricType {
     Counter,
+    Marker,
     Gauge,
+    GaugeDelta,
+    Reset,
     Level,
+    MaxLevel,
+    Peak,
     Timer,
+    TimerFromEnter,
+    TimerPercentiles,
+    TimerBusy,
+    TimerValue,
+    Distribution,
 }
 
 impl MetricType {
-    fn measure<P: MetricPoint>(self, point: &mut P, name: &str, value: i64) {
+    /// Can the metric be recorded on every enter of a span (`metrics.on`)?
+    fn per_enter(self) -> bool {
+        !matches!(self, MetricType::MaxLevel | MetricType::TimerBusy)
+    }
+
+    /// Is the metric subject to the [`DipstickLayerBuilder::sample_rate`]?
+    fn sampled(self) -> bool {
+        matches!(
+            self,
+            MetricType::Counter
+                | MetricType::Timer
+                | MetricType::TimerFromEnter
+                | MetricType::TimerPercentiles
+                | MetricType::TimerBusy
+                | MetricType::TimerValue
+                | MetricType::Distribution
+        )
+    }
+
+    fn measure<P: MetricPoint>(self, point: &mut P, layer: &Inner, name: &str, value: i64) {
+        if point.defer(self, name, value) {
+            return;
+        }
         let scope = point.scope();
         match self {
-            MetricType::Counter => scope.counter(name).count(value as _),
-            MetricType::Gauge => scope.gauge(name).value(value),
+            // Counters can't go down (the value would wrap around)
+            MetricType::Counter if value < 0 => {}
+            MetricType::Counter => {
+                match layer.config.counter_rate_limit {
+                    _ if !layer.counter_shards.0.is_empty() => {
+                        layer.sharded_count(scope, name, value)
+                    }
+                    Some(interval) => layer.limited_count(scope, name, value, interval),
+                    None => point.count(name, value),
+                }
+                if !layer.config.ratios.is_empty() {
+                    layer.ratio_count(scope, name, value);
+                }
+                if layer.config.counter_bursts.is_some() {
+                    layer.window_count(scope, name, value);
+                }
+                if let Some(summary) = point.summary() {
+                    summary.count(name, value);
+                }
+                if layer.config.undo_counters && P::SCOPED {
+                    let counter = scope.counter(name);
+                    point.push_counter(counter, value);
+                }
+            }
+            MetricType::Marker => scope.marker(name).mark(),
+            MetricType::Gauge => {
+                if layer.config.stale_gauges.is_some() {
+                    layer.set_tracked_gauge(scope, name, value);
+                } else {
+                    scope.gauge(name).value(value);
+                }
+                if let Some(reset) = layer.config.span_gauge_reset {
+                    point.push_gauge(scope.gauge(name), reset);
+                }
+            }
+            MetricType::Reset => return MetricType::Gauge.measure(point, layer, name, 0),
+            MetricType::GaugeDelta if scope.relative_gauges() => {
+                scope.level(name).adjust(value);
+            }
+            MetricType::GaugeDelta => {
+                let key = full_name(scope, name);
+                // Keep the lock while setting the gauge, so concurrent updates don't get reordered.
+                let mut totals = layer.gauge_totals.lock().unwrap_or_else(|e| e.into_inner());
+                let total = totals.entry(key).or_default();
+                *total = total.saturating_add(value);
+                scope.gauge(name).value(*total);
+            }
             MetricType::Level => {
+                // i64::MIN has no positive counterpart, the undo on close would be off by one
+                let value = value.max(-i64::MAX);
                 let level = scope.level(name);
                 level.adjust(value);
-                point.push_level(level, value);
+                // Only the spans hold their levels, the ones of events are adjusted manually
+                if P::SCOPED {
+                    let open = layer.config.track_open_levels.then(|| {
+                        let open = layer.open_level(full_name(scope, name));
+                        open.fetch_add(value, Ordering::Relaxed);
+                        open
+                    });
+                    point.push_level(level, value, open);
+                }
+            }
+            MetricType::MaxLevel => {
+                let gauge = scope.gauge(name);
+                point.hold_max_level(name, gauge);
+            }
+            MetricType::Peak => {
+                let peak = layer.peak(full_name(scope, name));
+                let gauge = scope.gauge(name);
+                peak.adjust(1, &gauge);
+                point.push_peak(peak, gauge);
+            }
+            MetricType::TimerValue => {
+                let elapsed = Duration::from_nanos(value.max(0) as u64);
+                point.time(name, layer.config.timer_unit.value(elapsed));
+                if let Some(summary) = point.summary() {
+                    summary.time(name, elapsed);
+                }
+            }
+            MetricType::Distribution => point.time(name, value.max(0) as u64),
+            MetricType::TimerPercentiles => {
+                // The hint for whatever computes the statistics, see the crate docs
+                let hint = full_name(scope, name);
+                let new = layer
+                    .percentile_hints
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .insert(hint);
+                if new {
+                    scope.gauge(&format!("{}.percentiles", name)).value(1);
+                }
+                return MetricType::Timer.measure(point, layer, name, value);
+            }
+            MetricType::Timer | MetricType::TimerFromEnter => {
+                let timer = layer.span_timer(scope.timer(name));
+                let start = match self {
+                    MetricType::Timer => Some(timer.clock.now()),
+                    _ => None,
+                };
+                point.push_timer(name, timer, start);
             }
-            MetricType::Timer => {
-                let timer = scope.timer(name);
-                let start = timer.start();
-                point.push_timer(timer, start);
+            MetricType::TimerBusy => {
+                let timer = layer.span_timer(scope.timer(name));
+                layer.track_exits.store(true, Ordering::Relaxed);
+                point.push_busy_timer(name, timer);
+            }
+        }
+
+        if !layer.recorded.load(Ordering::Relaxed) {
+            layer.recorded.store(true, Ordering::Relaxed);
+        }
+
+        if layer.config.self_monitoring {
+            // Bumped directly, not through here, so it doesn't count itself
+            let counter = layer.self_counter.read().unwrap_or_else(|e| e.into_inner());
+            if let Some(counter) = &*counter {
+                counter.count(1);
             }
         }
+
+        if layer.config.seen_gauges > 0 {
+            layer.record_seen(point.scope(), name);
+        }
+    }
+}
+
+/// The name of the metric including all the prefixes of the scope, as a key to maps.
+fn full_name<S: DipstickSink>(scope: &S, name: &str) -> String {
+    let mut key = String::new();
+    push_full_name(&mut key, scope, name);
+    key
+}
+
+fn push_full_name<S: DipstickSink>(key: &mut String, scope: &S, name: &str) {
+    for part in scope.prefixes().iter() {
+        key.push_str(part);
+        key.push('.');
     }
+    key.push_str(name);
 }
 
 const METRIC_TYPES: &[(&str, &str, MetricType, bool)] = &[
@@ -165,238 +1422,4722 @@ const METRIC_TYPES: &[(&str, &str, MetricType, bool)] = &[
         MetricType::Counter,
         true,
     ),
+    ("metrics.marker", "", MetricType::Marker, true),
+    (
+        "metrics.gauge.delta",
+        "metrics.gauge.delta.",
+        MetricType::GaugeDelta,
+        true,
+    ),
     ("metrics.gauge", "metrics.gauge.", MetricType::Gauge, true),
+    ("metrics.reset", "", MetricType::Reset, true),
+    ("metrics.level.max", "", MetricType::MaxLevel, false),
+    ("metrics.peak", "", MetricType::Peak, false),
     ("metrics.level", "metrics.level.", MetricType::Level, true),
+    (
+        "metrics.timer.from_enter",
+        "",
+        MetricType::TimerFromEnter,
+        false,
+    ),
+    (
+        "metrics.timer.percentiles",
+        "",
+        MetricType::TimerPercentiles,
+        false,
+    ),
+    ("metrics.timer.busy", "", MetricType::TimerBusy, false),
     ("metrics.timer", "", MetricType::Timer, false),
+    // No string form, only the explicit value
+    ("", "metrics.timer.", MetricType::TimerValue, true),
+    ("", "metrics.distribution.", MetricType::Distribution, true),
 ];
 
-trait MetricPoint {
-    const SCOPED: bool;
-    type Scope: InputScope;
-    fn push_timer(&mut self, timer: Timer, start: TimeHandle);
-    fn push_level(&mut self, level: Level, decrement: i64);
-    fn scope(&self) -> &Self::Scope;
+/// Is this one of the `metrics.*` attributes the layer understands?
+fn known_attribute(name: &str) -> bool {
+    [
+        SCOPE_NAME,
+        SCOPE_NAME_FULL,
+        SCOPE_DYNAMIC,
+        SCOPE_FIELD,
+        SCOPE_RESET,
+        COUNT_CHILDREN,
+        NOT_ENTERED,
+        SUMMARY,
+        METRICS_ON,
+        METRICS_WHEN,
+        AUTO_GAUGE,
+        FLUSH_EVERY,
+    ]
+    .contains(&name)
+        || name.starts_with(TAG_PREFIX)
+        || metric_field(name)
 }
 
-struct PointWrap<P>(P);
+/// Is this one of the `metrics.*` attributes naming or carrying a metric?
+fn metric_field(name: &str) -> bool {
+    METRIC_TYPES
+        .iter()
+        .any(|tp| name == tp.0 || (!tp.1.is_empty() && name.starts_with(tp.1)))
+        || name == LEVEL_GAUGE
+}
 
-impl<P: MetricPoint> Visit for PointWrap<P> {
-    fn record_debug(&mut self, _: &Field, _: &dyn Debug) {}
-    fn record_str(&mut self, field: &Field, value: &str) {
+/// Is this one of the attributes taking only a metric name?
+///
+/// Some of them (eg. `metrics.timer.start`) look like the value form of another one, but a number
+/// given to them is a mistake, not a metric called `start`.
+fn name_only(name: &str) -> bool {
+    METRIC_TYPES
+        .iter()
+        .any(|tp| !tp.0.is_empty() && name == tp.0)
+        || [TIMER_START, TIMER_STOP, COUNTER_DYNAMIC, LEVEL_GAUGE].contains(&name)
+}
+
+/// Warns about the unknown `metrics.*` fields, see [`DipstickLayerBuilder::warn_unknown`].
+struct UnknownVisitor<'a>(&'a Inner);
+
+impl Visit for UnknownVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, _: &dyn Debug) {
         let name = field.name();
-        for tp in METRIC_TYPES {
-            if (tp.3 || P::SCOPED) && name == tp.0 {
-                tp.2.measure(&mut self.0, value, 1);
-                break;
-            }
+        if name.starts_with("metrics.")
+            && !known_attribute(name)
+            && self.0.first_warning("unknown", name)
+        {
+            tracing::warn!("Unknown metrics attribute {}, possibly a typo", name);
         }
     }
+}
 
-    fn record_i64(&mut self, field: &Field, value: i64) {
-        let name = field.name();
-        for tp in METRIC_TYPES {
-            if tp.3 && name.starts_with(tp.1) {
-                tp.2.measure(&mut self.0, &name[tp.1.len()..], value);
+trait MetricPoint {
+    const SCOPED: bool;
+    type Scope: DipstickSink;
+    /// Starts measuring a timer, either right away or on the first enter (if `start` is `None`).
+    fn push_timer(&mut self, name: &str, timer: SpanTimer, start: Option<Instant>);
+    /// Starts accumulating the time the span is entered.
+    fn push_busy_timer(&mut self, name: &str, timer: SpanTimer);
+    /// Decrements the level once the span closes, together with its count in
+    /// [`DipstickLayer::open_levels`].
+    fn push_level(&mut self, level: Level, decrement: i64, open: Option<Arc<AtomicI64>>);
+    /// Subtracts the increment from the counter once the span closes.
+    fn push_counter(&mut self, counter: Counter, increment: i64);
+    /// Sets the gauge to the reset value once the span closes.
+    fn push_gauge(&mut self, gauge: Gauge, reset: i64);
+    fn hold_max_level(&mut self, name: &str, gauge: Gauge);
+    /// Releases the peak once the span closes.
+    fn push_peak(&mut self, peak: Arc<Peak>, gauge: Gauge);
+    fn scope(&self) -> &Self::Scope;
+    fn summary(&self) -> Option<&SummaryCell>;
+    /// Stores the metric to be recorded later, instead of recording it now.
+    ///
+    /// Returns `false` if the point doesn't defer metrics.
+    fn defer(&mut self, _tp: MetricType, _name: &str, _value: i64) -> bool {
+        false
+    }
+    /// Bumps a counter in the scope.
+    ///
+    /// The points of events reuse the cached handles of the counters.
+    fn count(&self, name: &str, value: i64) {
+        self.scope().counter(name).count(value as _);
+    }
+    /// Sends an explicit value to a timer in the scope.
+    ///
+    /// The points of events inside spans may batch them, see [`DipstickLayerBuilder::timer_batch`].
+    fn time(&self, name: &str, value: u64) {
+        self.scope().timer(name).interval_us(value);
+    }
+}
+
+/// The counters already looked up in a scope, by their names.
+///
+/// Looking a metric up in [`dipstick`] means allocating its full name and searching for it under
+/// a lock, which is much more expensive than a lookup here.
+#[derive(Debug, Default)]
+struct Counters(Mutex<HashMap<String, Counter>>);
+
+impl Counters {
+    fn count<S: DipstickSink>(&self, scope: &S, name: &str, value: i64) {
+        let mut counters = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        // Kept locked while counting, cloning the handle would allocate
+        match counters.get(name) {
+            Some(counter) => counter.count(value as _),
+            None => {
+                let counter = scope.counter(name);
+                counter.count(value as _);
+                counters.insert(name.to_owned(), counter);
             }
         }
     }
-    fn record_u64(&mut self, field: &Field, value: u64) {
-        self.record_i64(field, value as _);
-    }
 }
 
-#[derive(Clone)]
-struct Scope<S> {
-    scope: S,
-    // TODO: Small vecs? Put into the same vec to save one allocation?
-    timers: Vec<(Timer, TimeHandle)>,
-    levels: Vec<(Level, i64)>,
-    // TODO: CPU timers
+/// The explicit values of timers not sent yet, by the names of the timers.
+///
+/// See [`DipstickLayerBuilder::timer_batch`]. The rest is sent on drop.
+#[derive(Debug, Default)]
+struct TimerBatches {
+    /// How many values of a timer to collect before sending them.
+    size: usize,
+    timers: Mutex<HashMap<String, (Timer, Vec<u64>)>>,
 }
 
-impl<S> Drop for Scope<S> {
-    fn drop(&mut self) {
-        for (timer, start) in self.timers.drain(..) {
-            timer.stop(start);
+impl TimerBatches {
+    fn new(size: usize) -> Self {
+        TimerBatches {
+            size,
+            timers: Mutex::default(),
         }
+    }
 
-        for (level, decrement) in self.levels.drain(..) {
-            level.adjust(-decrement);
+    fn time<S: DipstickSink>(&self, scope: &S, name: &str, value: u64) {
+        if self.size <= 1 {
+            scope.timer(name).interval_us(value);
+            return;
+        }
+        let mut timers = self.timers.lock().unwrap_or_else(|e| e.into_inner());
+        if !timers.contains_key(name) {
+            let batch = (scope.timer(name), Vec::with_capacity(self.size));
+            timers.insert(name.to_owned(), batch);
+        }
+        let (timer, values) = timers.get_mut(name).expect("Inserted above");
+        values.push(value);
+        // Kept locked while sending, so the values of a timer don't get reordered
+        if values.len() >= self.size {
+            for value in values.drain(..) {
+                timer.interval_us(value);
+            }
         }
     }
 }
 
-impl<S: InputScope> MetricPoint for Scope<S> {
-    const SCOPED: bool = true;
-    type Scope = S;
-    fn push_level(&mut self, level: Level, decrement: i64) {
-        self.levels.push((level, decrement));
-    }
-    fn push_timer(&mut self, timer: Timer, start: TimeHandle) {
-        self.timers.push((timer, start));
-    }
-    fn scope(&self) -> &S {
-        &self.scope
+impl Drop for TimerBatches {
+    fn drop(&mut self) {
+        let timers = self.timers.get_mut().unwrap_or_else(|e| e.into_inner());
+        for (timer, values) in timers.values_mut() {
+            for value in values.drain(..) {
+                timer.interval_us(value);
+            }
+        }
     }
 }
 
-impl<S, F> MetricPoint for Lazy<S, F>
-where
-    S: InputScope,
-    F: FnOnce() -> S,
-{
-    const SCOPED: bool = false;
-    type Scope = S;
+#[derive(Clone, Debug, Default)]
+struct Config {
+    default_metric_name: Option<String>,
+    summary_hook: Option<SummaryHook>,
+    error_hook: Option<ErrorHook>,
+    clock: SharedClock,
+    pre_registered: Vec<(String, MetricKind)>,
+    message_counters: Option<String>,
+    level_suffix: bool,
+    thread_suffix: bool,
+    span_name_prefix: bool,
+    sample_rate: Option<f64>,
+    error_marker: Option<String>,
+    seen_gauges: usize,
+    level_scope: Option<TracingLevel>,
+    watchdog: Option<Duration>,
+    watchdog_counter: Option<String>,
+    self_monitoring: bool,
+    self_monitoring_counter: Option<String>,
+    counter_rate_limit: Option<Duration>,
+    timer_sequence: bool,
+    track_open_levels: bool,
+    non_finite: NonFinite,
+    timer_unit: TimerUnit,
+    dynamic_scopes: bool,
+    counter_bursts: Option<Duration>,
+    flush_on_panic: bool,
+    flush_on_scope_close: bool,
+    stale_gauges: Option<(Duration, i64)>,
+    ratios: Vec<Ratio>,
+    ratio_window: Option<Duration>,
+    field_gauges: HashSet<String>,
+    sharded_counters: Option<Duration>,
+    defer_to_close: bool,
+    timer_batch: usize,
+    span_gauge_reset: Option<i64>,
+    undo_counters: bool,
+    default_counter_value: Option<i64>,
+    warn_unknown: bool,
+    parse_string_values: bool,
+}
 
-    fn push_timer(&mut self, _: Timer, _: TimeHandle) {
-        unreachable!("Timers are not supported on events");
+/// A ratio of counters, see [`DipstickLayerBuilder::counter_ratio`].
+#[derive(Clone, Debug)]
+struct Ratio {
+    numerator: String,
+    denominator: String,
+    gauge: String,
+    scale: i64,
+}
+
+impl Config {
+    /// The value the counters without an explicit one are increased by.
+    fn counter_value(&self) -> i64 {
+        self.default_counter_value.unwrap_or(1)
     }
 
-    fn push_level(&mut self, _: Level, _: i64) {
-        // Levels on events are decremented manually, not at the end of some scope
+    /// Resolves the name of a metric given by the attribute value.
+    ///
+    /// Handles the bare metrics (with empty name). These are named after the span, if there's
+    /// one, or get the default name.
+    fn metric_name<'a>(&'a self, value: &'a str, span: Option<&str>) -> Option<Cow<'a, str>> {
+        match span {
+            _ if !value.is_empty() => Some(Cow::Borrowed(value)),
+            Some(span) if !span.is_empty() => {
+                let mut name = String::with_capacity(span.len());
+                push_sanitized(&mut name, span);
+                Some(Cow::Owned(name))
+            }
+            _ => self.default_metric_name.as_deref().map(Cow::Borrowed),
+        }
     }
 
-    fn scope(&self) -> &S {
-        self
+    /// Defines the [pre-registered][DipstickLayerBuilder::pre_register] metrics in the scope.
+    fn pre_register<S: DipstickSink>(&self, scope: &S) {
+        for (name, kind) in &self.pre_registered {
+            kind.register(scope, name);
+        }
     }
 }
 
-/// The bridge from [`tracing`](https://docs.rs/tracing) to [`dipstick`].
-///
-/// This takes information from tracing and propagates them into [`dipstick`] as metrics. It works
-/// as [`Layer`].
-///
-/// # Warning
+/// What to do with metric values that are not finite floats.
 ///
-/// Currently, [`tracing_subscriber`] doesn't allow filtering on per-layer basis. That means if
-/// there's another layer that filters (for example based on the level), it'll impact this layer
-/// too. This would negatively impact the gathered metrics as this expects to get them all.
-///
-/// It has been observed to work together with the `tracing`s `log-always` feature.
-///
-/// Future versions might bypass the [`Layer`] system and wrap a
-/// [`Subscriber`][tracing_core::Subscriber] directly.
+/// Set through [`DipstickLayerBuilder::non_finite`]. Whenever such value is ignored, a warning is
+/// emitted (once per field) through [`tracing`].
 ///
 /// # Examples
 ///
 /// ```rust
-/// use std::time::Duration;
+/// # #[cfg(feature = "testing")] {
+/// use tracing_dipstick::testing::with_test_layer;
 ///
-/// use dipstick::{AtomicBucket, ScheduleFlush, Stream};
-/// use log::LevelFilter;
-/// use tracing::subscriber;
-/// use tracing_dipstick::DipstickLayer;
-/// use tracing_subscriber::layer::SubscriberExt;
-/// use tracing_subscriber::Registry;
+/// let events = with_test_layer(|_| {
+///     tracing::info!(metrics.gauge.x = 3.14);
+///     tracing::info!(metrics.gauge.x = f64::NAN);
+///     tracing::info!(metrics.gauge.x = 1e300);
+/// });
+/// let values: Vec<_> = events.into_iter().map(|e| e.value).collect();
+/// // The finite values are rounded and saturated, the NaN is skipped by default
+/// assert_eq!(vec![3, i64::MAX as isize], values);
+/// # }
+/// ```
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum NonFinite {
+    /// The value is ignored.
+    #[default]
+    Skip,
+    /// The infinities are clamped to the largest or smallest possible value.
+    ///
+    /// NaN is still ignored, as it has no reasonable equivalent.
+    Clamp,
+}
+
+/// The unit of the recorded timers.
 ///
-/// env_logger::builder()
-///     .filter_level(LevelFilter::Info)
-///     .parse_default_env()
-///     .init();
+/// Set through [`DipstickLayerBuilder::timer_unit`]. A [`dipstick`] timer doesn't know its unit,
+/// it just carries a number; its own measurements (eg. [`Timer::time`]) are in microseconds, as
+/// are the ones of this layer by default. The layer converts the measured durations to the
+/// configured unit before passing them to [`dipstick`], so nothing needs to be converted (with
+/// loss of precision) later on.
 ///
-/// let root = AtomicBucket::new();
-/// root.stats(dipstick::stats_all);
-/// root.drain(Stream::write_to_stdout());
-/// let _flush = root.flush_every(Duration::from_secs(5));
+/// Note that the outputs bundled with [`dipstick`] (eg. statsd, graphite or prometheus) assume
+/// microseconds and scale the timers to milliseconds themselves. Other units are therefore meant
+/// for outputs (or custom scopes) passing the values through as they are.
 ///
-/// let bridge = DipstickLayer::new(root);
-/// let subscriber = Registry::default().with(bridge);
+/// The timers of spans are measured by the [`dipstick`] clock, with a resolution of microseconds,
+/// so in [`Nanoseconds`][TimerUnit::Nanoseconds] they are whole multiples of 1000. The explicit
+/// values (`metrics.timer.name=value`) are given in nanoseconds, so they are precise in any unit.
+/// The unit doesn't apply to `metrics.distribution`, which records the values as they are.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum TimerUnit {
+    /// Nanoseconds.
+    Nanoseconds,
+    /// Microseconds, as [`dipstick`] uses natively.
+    #[default]
+    Microseconds,
+    /// Milliseconds.
+    Milliseconds,
+}
+
+impl TimerUnit {
+    /// The duration in this unit, rounded down and saturated.
+    fn value(self, elapsed: Duration) -> u64 {
+        let value = match self {
+            TimerUnit::Nanoseconds => elapsed.as_nanos(),
+            TimerUnit::Microseconds => elapsed.as_micros(),
+            TimerUnit::Milliseconds => elapsed.as_millis(),
+        };
+        value.try_into().unwrap_or(u64::MAX)
+    }
+}
+
+/// A source of time for the timers.
 ///
-/// subscriber::set_global_default(subscriber).unwrap();
-/// ```
-#[derive(Copy, Clone, Debug, Default)]
-pub struct DipstickLayer<S> {
-    scope: S,
+/// The timers measuring spans (`metrics.timer`, `metrics.timer.busy` and the like) and the
+/// [timers across events](crate#timers-across-events) read the current time from the clock. By
+/// default, this is the real monotonic clock ([`Instant::now`]). A different one can be set
+/// through [`DipstickLayerBuilder::clock`], usually a mock one in tests (eg.
+/// [`ManualClock`][crate::testing::ManualClock]), to get exact durations. The periodic work of the
+/// layer (see [`DipstickLayer::tick`]) is scheduled by the clock too, as are the [rate
+/// limiting][DipstickLayerBuilder::counter_rate_limit] and the [stale
+/// gauges][DipstickLayerBuilder::stale_gauges].
+pub trait Clock: Send + Sync {
+    /// The current time.
+    ///
+    /// The time should not go backwards. If it does, the timers record zero.
+    fn now(&self) -> Instant;
 }
 
-impl<S> DipstickLayer<S>
-where
-    S: Clone + InputScope + Prefixed + 'static,
-{
-    /// Creates the bridge.
+/// The clock of the timers, see [`Clock`].
+///
+/// Nothing means the real clock, which is read directly, without going through a trait object.
+#[derive(Clone, Default)]
+struct SharedClock(Option<Arc<dyn Clock>>);
+
+impl SharedClock {
+    fn now(&self) -> Instant {
+        match &self.0 {
+            Some(clock) => clock.now(),
+            None => Instant::now(),
+        }
+    }
+}
+
+impl Debug for SharedClock {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match self.0 {
+            Some(_) => fmt.write_str("SharedClock(custom)"),
+            None => fmt.write_str("SharedClock(real)"),
+        }
+    }
+}
+
+/// The kinds of metrics, for [`DipstickLayerBuilder::pre_register`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum MetricKind {
+    /// A counter (eg. `metrics.counter`).
+    Counter,
+    /// A marker (`metrics.marker`).
+    Marker,
+    /// A gauge (eg. `metrics.gauge`).
+    Gauge,
+    /// A level (eg. `metrics.level`).
+    Level,
+    /// A timer (eg. `metrics.timer`).
+    Timer,
+}
+
+impl MetricKind {
+    /// Defines the metric in the scope, see [`DipstickLayerBuilder::pre_register`].
+    fn register<S: DipstickSink>(self, scope: &S, name: &str) {
+        match self {
+            // Zero doesn't change the totals
+            MetricKind::Counter => scope.counter(name).count(0),
+            MetricKind::Level => scope.level(name).adjust(0),
+            // A value would be a fake occurrence, these are only defined
+            MetricKind::Marker => drop(scope.marker(name)),
+            MetricKind::Gauge => drop(scope.gauge(name)),
+            MetricKind::Timer => drop(scope.timer(name)),
+        }
+    }
+}
+
+/// The level as a number, for `@level` and `metrics.level_value`.
+fn level_value(level: &TracingLevel) -> i64 {
+    match *level {
+        TracingLevel::ERROR => 5,
+        TracingLevel::WARN => 4,
+        TracingLevel::INFO => 3,
+        TracingLevel::DEBUG => 2,
+        TracingLevel::TRACE => 1,
+    }
+}
+
+/// The name of the level for [`DipstickLayerBuilder::level_scope`] and
+/// [`DipstickLayerBuilder::append_level_suffix`].
+fn level_name(level: &TracingLevel) -> &'static str {
+    match *level {
+        TracingLevel::ERROR => "error",
+        TracingLevel::WARN => "warn",
+        TracingLevel::INFO => "info",
+        TracingLevel::DEBUG => "debug",
+        TracingLevel::TRACE => "trace",
+    }
+}
+
+/// Appends the part to the name, with everything except ASCII letters and digits replaced by `_`.
+fn push_sanitized(name: &mut String, part: &str) {
+    for c in part.chars() {
+        let c = if c.is_ascii_alphanumeric() { c } else { '_' };
+        // Collapse runs of replaced characters (eg. the `::` in module paths)
+        if c != '_' || !name.ends_with('_') {
+            name.push(c);
+        }
+    }
+}
+
+/// Creates the name of the counter for [`DipstickLayerBuilder::message_counters`].
+fn callsite_name(prefix: &str, metadata: &Metadata) -> String {
+    // The macros of tracing name the events `event file:line`, the prefix carries no information
+    let callsite = metadata.name();
+    let callsite = callsite.strip_prefix("event ").unwrap_or(callsite);
+    let mut name = String::with_capacity(prefix.len() + callsite.len() + 1);
+    name.push_str(prefix);
+    name.push('.');
+    push_sanitized(&mut name, callsite);
+    name
+}
+
+/// The configuration and runtime state shared by all the clones of the layer.
+#[derive(Debug, Default)]
+struct Inner {
+    config: Config,
+    /// Running totals of the gauges adjusted by `metrics.gauge.delta`.
+    gauge_totals: Mutex<HashMap<String, i64>>,
+    /// The levels held by spans, by their full names, see [`DipstickLayer::open_levels`].
+    open_levels: Mutex<HashMap<String, Arc<AtomicI64>>>,
+    /// The peaks of `metrics.peak`, by their full names.
+    peaks: Mutex<HashMap<String, Arc<Peak>>>,
+    /// The timers that already got their `metrics.timer.percentiles` hint, by their full names.
+    percentile_hints: Mutex<HashSet<String>>,
+    /// The `last_seen` gauges of the metrics already seen, by the full names of the metrics, for
+    /// [`DipstickLayerBuilder::seen_gauges`].
+    seen: RwLock<HashMap<String, Gauge>>,
+    /// The wall clock time matching an instant of the configured clock.
     ///
-    /// Expects the scope into which it will put metrics.
-    pub fn new(input_scope: S) -> Self {
-        DipstickLayer { scope: input_scope }
+    /// Taken on the first use, the `seen_gauges` derive the wall clock time from it.
+    epoch: once_cell::sync::OnceCell<(SystemTime, Instant)>,
+    /// Was any metric recorded already?
+    recorded: AtomicBool,
+    /// The counters limited by [`DipstickLayerBuilder::counter_rate_limit`].
+    limited_counters: Mutex<HashMap<String, LimitedCounter>>,
+    /// The next sequence number for [`DipstickLayerBuilder::timer_sequence`].
+    timer_seq: AtomicU64,
+    /// The counters tracked by [`DipstickLayerBuilder::counter_bursts`].
+    counter_windows: Mutex<HashMap<String, CounterWindow>>,
+    /// The gauges tracked by [`DipstickLayerBuilder::stale_gauges`], with their last updates.
+    gauge_updates: Mutex<HashMap<String, (Gauge, Instant)>>,
+    /// The numerators and denominators of the [ratios][DipstickLayerBuilder::counter_ratio] in the
+    /// current window, in the same order as configured.
+    ratio_windows: Mutex<Vec<(i64, i64)>>,
+    /// The shards of [`DipstickLayerBuilder::sharded_counters`].
+    counter_shards: CounterShards,
+    /// Was any span interested in its exits (with `metrics.timer.busy` or `metrics.on`) created?
+    ///
+    /// If not, the exits of spans need no tracking.
+    track_exits: AtomicBool,
+    /// The start times of `metrics.timer.start`, by the full names of the timers.
+    started_timers: Mutex<HashMap<String, Instant>>,
+    /// Did something panic already, for the warning about it?
+    panicked: AtomicBool,
+    /// Was any span with `metrics.count_children` created?
+    ///
+    /// If not, the spans without metrics don't need to look for their parents.
+    count_children: AtomicBool,
+    /// Warnings already emitted, by their kind and field name.
+    ///
+    /// Used to not flood the logs from hot loops.
+    warned: Mutex<HashSet<(&'static str, &'static str)>>,
+    /// The counts behind [`DipstickLayer::stats`].
+    stats: StatCounts,
+    /// The counter of [`DipstickLayerBuilder::self_monitoring`], in the current root scope.
+    self_counter: RwLock<Option<Counter>>,
+    /// Turned off through [`DipstickLayer::set_enabled`].
+    ///
+    /// Negated, so the default is enabled.
+    disabled: AtomicBool,
+    /// The periodic work, see [`DipstickLayer::tick`].
+    maintenance: Maintenance,
+}
+
+/// The running counts of [`LayerStats`].
+#[derive(Debug, Default)]
+struct StatCounts {
+    emitted: AtomicU64,
+    ignored: AtomicU64,
+}
+
+/// Counts of the metric fields the layer has seen, see [`DipstickLayer::stats`].
+///
+/// Only the fields naming or carrying metrics (eg. `metrics.counter` or `metrics.gauge.size`,
+/// including the unknown `metrics.*` ones) count, the other attributes (eg. `metrics.scope` or
+/// `metrics.tag.*`) don't.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct LayerStats {
+    /// Number of fields turned into a metric.
+    pub emitted: u64,
+    /// Number of fields ignored (unknown attributes, unusable values, kinds of metrics not
+    /// accepted on events, empty names...).
+    pub ignored: u64,
+}
+
+/// The callback receiving the panics caught in the layer, see [`DipstickLayerBuilder::on_error`].
+#[derive(Clone)]
+struct ErrorHook(Arc<dyn Fn(&str) + Send + Sync>);
+
+impl Debug for ErrorHook {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.write_str("ErrorHook")
     }
 }
 
-impl<S, I> Layer<I> for DipstickLayer<S>
-where
-    S: Clone + InputScope + Prefixed + Send + Sync + 'static,
-    I: Subscriber,
-    for<'l> I: LookupSpan<'l>,
-{
-    fn on_new_span(&self, attrs: &Attributes, id: &Id, ctx: Context<I>) {
-        let named = |scope: &S| -> S {
-            let mut named: Option<S> = None;
-            struct NameVisitor<'a, S> {
-                target: &'a mut Option<S>,
-                src: &'a S,
-            }
-            impl<S> Visit for NameVisitor<'_, S>
-            where
-                S: Prefixed,
-            {
-                fn record_debug(&mut self, _: &Field, _: &dyn Debug) {}
-                fn record_str(&mut self, field: &Field, value: &str) {
-                    let name = field.name();
-                    if name == SCOPE_NAME {
-                        *self.target = Some(self.src.add_name(value));
-                    } else if name == SCOPE_NAME_FULL {
-                        *self.target = Some(self.src.named(value));
-                    }
-                }
-            }
-            attrs.record(&mut NameVisitor {
-                target: &mut named,
-                src: scope,
-            });
-            named.unwrap_or_else(|| scope.clone())
+/// The root scope of the layer, replaced as a whole by [`DipstickLayer::set_scope`].
+#[derive(Debug, Default)]
+struct Root<S> {
+    scope: S,
+    /// The counters of the events recorded directly into the root scope.
+    counters: Counters,
+}
+
+/// The current [`Root`], shared by the clones of the layer and its background threads.
+#[derive(Debug, Default)]
+struct SharedRoot<S>(Arc<RwLock<Arc<Root<S>>>>);
+
+impl<S> SharedRoot<S> {
+    fn new(scope: S) -> Self {
+        let root = Root {
+            scope,
+            counters: Counters::default(),
         };
-        let scope = ctx
-            .lookup_current()
-            .and_then(|current| {
-                current
-                    .extensions()
-                    .get::<Scope<S>>()
-                    .map(|Scope { scope: s, .. }| named(s))
-            })
-            .unwrap_or_else(|| named(&self.scope));
+        SharedRoot(Arc::new(RwLock::new(Arc::new(root))))
+    }
 
-        let mut scope = PointWrap(Scope {
+    fn get(&self) -> Arc<Root<S>> {
+        Arc::clone(&self.0.read().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    fn set(&self, scope: S) {
+        let root = Arc::new(Root {
             scope,
-            timers: Vec::new(),
-            levels: Vec::new(),
+            counters: Counters::default(),
         });
-        attrs.record(&mut scope);
+        // The old one is dropped outside of the lock
+        let _old = mem::replace(
+            &mut *self.0.write().unwrap_or_else(|e| e.into_inner()),
+            root,
+        );
+    }
+}
 
-        ctx.span(id)
-            .expect("Missing newly created span")
-            .extensions_mut()
-            .insert(scope.0);
+// Derived Clone would require S: Clone
+impl<S> Clone for SharedRoot<S> {
+    fn clone(&self) -> Self {
+        SharedRoot(Arc::clone(&self.0))
     }
-    // TODO: How about cloning/creating new IDs for spans?
-    fn on_event(&self, event: &Event, ctx: Context<I>) {
-        // TODO: Currently, we store a scope in each span. Instead we should store it only in the
-        // ones that are interesting. In particular:
-        // * Score on creation only if the span itself touches metrics (either has some or has a
-        //   metric scope).
-        // * Initialize it lazily on the first access. But extensions_mut might be slower?
-        let scope = Lazy::new(|| {
-            ctx.lookup_current()
-                .map(|c| {
-                    // FIXME: It would be nice to avoid the clone. That should be possible, in
-                    // theory.
-                    c.extensions()
-                        .get::<Scope<S>>()
-                        .expect("Missing prepared scope")
-                        .scope
-                        .clone()
-                })
-                .unwrap_or_else(|| self.scope.clone())
-        });
+}
 
-        event.record(&mut PointWrap(scope));
+/// A counter coalescing its increments, see [`DipstickLayerBuilder::counter_rate_limit`].
+#[derive(Debug)]
+struct LimitedCounter {
+    counter: Counter,
+    pending: i64,
+    last_sent: Option<Instant>,
+}
+
+impl LimitedCounter {
+    fn send(&mut self, now: Instant) {
+        self.counter.count(self.pending as _);
+        self.pending = 0;
+        self.last_sent = Some(now);
+    }
+}
+
+/// The increments of a counter within the current window, see
+/// [`DipstickLayerBuilder::counter_bursts`].
+#[derive(Debug)]
+struct CounterWindow {
+    timer: Timer,
+    count: i64,
+}
+
+/// The shards of [`DipstickLayerBuilder::sharded_counters`], with the increments not merged yet.
+///
+/// The leftovers are merged when dropped.
+#[derive(Debug, Default)]
+struct CounterShards(Vec<Mutex<HashMap<String, (Counter, i64)>>>);
+
+impl CounterShards {
+    /// Sends the increments accumulated in all the shards.
+    fn merge(&self) {
+        for shard in &self.0 {
+            let mut counters = shard.lock().unwrap_or_else(|e| e.into_inner());
+            for (counter, pending) in counters.values_mut() {
+                if *pending != 0 {
+                    counter.count(*pending as _);
+                    *pending = 0;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for CounterShards {
+    fn drop(&mut self) {
+        self.merge();
+    }
+}
+
+impl Inner {
+    /// Creates the counter of [`DipstickLayerBuilder::self_monitoring`] in a new root scope.
+    fn monitor_root<S: DipstickSink>(&self, root: &S) {
+        if self.config.self_monitoring {
+            let name = self
+                .config
+                .self_monitoring_counter
+                .as_deref()
+                .unwrap_or(SELF_MONITORING_COUNTER);
+            let counter = root.counter(name);
+            *self.self_counter.write().unwrap_or_else(|e| e.into_inner()) = Some(counter);
+        }
+    }
+
+    /// The running total of a level held by spans, see [`DipstickLayer::open_levels`].
+    fn open_level(&self, name: String) -> Arc<AtomicI64> {
+        let mut open = self.open_levels.lock().unwrap_or_else(|e| e.into_inner());
+        Arc::clone(open.entry(name).or_default())
+    }
+
+    fn peak(&self, name: String) -> Arc<Peak> {
+        let mut peaks = self.peaks.lock().unwrap_or_else(|e| e.into_inner());
+        Arc::clone(peaks.entry(name).or_default())
+    }
+
+    fn span_timer(&self, timer: Timer) -> SpanTimer {
+        SpanTimer {
+            timer,
+            unit: self.config.timer_unit,
+            name: None,
+            seq: self
+                .config
+                .timer_sequence
+                .then(|| self.timer_seq.fetch_add(1, Ordering::Relaxed)),
+            clock: self.config.clock.clone(),
+        }
+    }
+
+    /// Decides if the metrics of a span or event are recorded, see
+    /// [`DipstickLayerBuilder::sample_rate`].
+    fn sampled(&self) -> bool {
+        self.config.sample_rate.is_none_or(|rate| random() < rate)
+    }
+
+    /// Checks if this is the first warning of the given kind about the given field.
+    fn first_warning(&self, kind: &'static str, field: &'static str) -> bool {
+        self.warned
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert((kind, field))
+    }
+
+    fn limited_count<S: DipstickSink>(
+        &self,
+        scope: &S,
+        name: &str,
+        value: i64,
+        interval: Duration,
+    ) {
+        let key = full_name(scope, name);
+        let now = self.config.clock.now();
+        let mut counters = self
+            .limited_counters
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let counter = counters.entry(key).or_insert_with(|| LimitedCounter {
+            counter: scope.counter(name),
+            pending: 0,
+            last_sent: None,
+        });
+        counter.pending = counter.pending.saturating_add(value);
+        if counter.last_sent.is_none_or(|last| now - last >= interval) {
+            counter.send(now);
+        }
+    }
+
+    fn sharded_count<S: DipstickSink>(&self, scope: &S, name: &str, value: i64) {
+        let shards = &self.counter_shards.0;
+        let shard = SHARD.with(|shard| *shard) % shards.len();
+        let key = full_name(scope, name);
+        let mut counters = shards[shard].lock().unwrap_or_else(|e| e.into_inner());
+        let (_, pending) = counters
+            .entry(key)
+            .or_insert_with(|| (scope.counter(name), 0));
+        *pending = pending.saturating_add(value);
+    }
+
+    /// Sends the increments left behind by the counters no longer being bumped.
+    fn send_limited_leftovers(&self, interval: Duration) {
+        let now = self.config.clock.now();
+        let mut counters = self
+            .limited_counters
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        for counter in counters.values_mut() {
+            let due = counter.last_sent.is_none_or(|last| now - last >= interval);
+            if counter.pending != 0 && due {
+                counter.send(now);
+            }
+        }
+    }
+
+    fn window_count<S: DipstickSink>(&self, scope: &S, name: &str, value: i64) {
+        let key = full_name(scope, name);
+        let mut windows = self
+            .counter_windows
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let window = windows.entry(key).or_insert_with(|| CounterWindow {
+            timer: scope.timer(&format!("{}.window", name)),
+            count: 0,
+        });
+        window.count = window.count.saturating_add(value);
+    }
+
+    /// Records the sums of all the tracked counters and starts new windows.
+    fn close_counter_windows(&self) {
+        let mut windows = self
+            .counter_windows
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        for window in windows.values_mut() {
+            // Outputs show timers in milliseconds, make it come out as the plain number
+            let count = u64::try_from(window.count).unwrap_or_default();
+            window.timer.interval_us(count.saturating_mul(1000));
+            window.count = 0;
+        }
+    }
+
+    fn set_tracked_gauge<S: DipstickSink>(&self, scope: &S, name: &str, value: i64) {
+        let key = full_name(scope, name);
+        // Keep the lock while setting the gauge, so it doesn't race with a reset.
+        let mut updates = self.gauge_updates.lock().unwrap_or_else(|e| e.into_inner());
+        let gauge = scope.gauge(name);
+        gauge.value(value);
+        updates.insert(key, (gauge, self.config.clock.now()));
+    }
+
+    /// Resets the gauges not updated within the timeout and stops tracking them.
+    fn reset_stale_gauges(&self, timeout: Duration, reset: i64) {
+        let now = self.config.clock.now();
+        let mut updates = self.gauge_updates.lock().unwrap_or_else(|e| e.into_inner());
+        updates.retain(|_, (gauge, updated)| {
+            let stale = now - *updated >= timeout;
+            if stale {
+                gauge.value(reset);
+            }
+            !stale
+        });
+    }
+
+    fn ratio_count<S: DipstickSink>(&self, scope: &S, name: &str, value: i64) {
+        let key = full_name(scope, name);
+        let mut windows = self.ratio_windows.lock().unwrap_or_else(|e| e.into_inner());
+        windows.resize(self.config.ratios.len(), (0, 0));
+        for (ratio, (numerator, denominator)) in self.config.ratios.iter().zip(windows.iter_mut()) {
+            if ratio.numerator == key {
+                *numerator = numerator.saturating_add(value);
+            }
+            if ratio.denominator == key {
+                *denominator = denominator.saturating_add(value);
+            }
+        }
+    }
+
+    /// Sets the ratio gauges from the current window and starts a new one.
+    fn close_ratio_windows<S: DipstickSink>(&self, scope: &S) {
+        let mut windows = self.ratio_windows.lock().unwrap_or_else(|e| e.into_inner());
+        for (ratio, (numerator, denominator)) in self.config.ratios.iter().zip(windows.iter_mut()) {
+            if *denominator != 0 {
+                let value =
+                    i128::from(*numerator) * i128::from(ratio.scale) / i128::from(*denominator);
+                let value =
+                    i64::try_from(value).unwrap_or(if value < 0 { i64::MIN } else { i64::MAX });
+                scope.gauge(&ratio.gauge).value(value);
+            }
+            *numerator = 0;
+            *denominator = 0;
+        }
+    }
+
+    /// Seconds since the UNIX epoch, by the configured clock.
+    fn unix_time(&self) -> u64 {
+        let now = self.config.clock.now();
+        let (wall, instant) = *self
+            .epoch
+            .get_or_init(|| (SystemTime::now(), self.config.clock.now()));
+        (wall + now.saturating_duration_since(instant))
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    }
+
+    fn record_seen<S: DipstickSink>(&self, scope: &S, name: &str) {
+        let now = self.unix_time();
+        // The metrics seen before (and the ones over the limit) need neither an allocation nor an
+        // exclusive lock
+        let (known, full) = SEEN_KEY.with(|key| {
+            let mut key = key.borrow_mut();
+            key.clear();
+            push_full_name(&mut key, scope, name);
+            let seen = self.seen.read().unwrap_or_else(|e| e.into_inner());
+            match seen.get(key.as_str()) {
+                Some(last) => {
+                    last.value(now);
+                    (true, false)
+                }
+                None => (false, seen.len() >= self.config.seen_gauges),
+            }
+        });
+        if full {
+            self.warn_seen_limit(name);
+        }
+        if known || full {
+            return;
+        }
+        let mut seen = self.seen.write().unwrap_or_else(|e| e.into_inner());
+        let full = seen.len() >= self.config.seen_gauges;
+        match seen.entry(full_name(scope, name)) {
+            // Some other thread was faster
+            Entry::Occupied(last) => last.get().value(now),
+            // Or took the last free place
+            Entry::Vacant(_) if full => {
+                drop(seen);
+                self.warn_seen_limit(name);
+            }
+            Entry::Vacant(entry) => {
+                scope.gauge(&format!("{}.first_seen", name)).value(now);
+                entry
+                    .insert(scope.gauge(&format!("{}.last_seen", name)))
+                    .value(now);
+            }
+        }
+    }
+
+    fn warn_seen_limit(&self, name: &str) {
+        if self.first_warning("limit", "seen_gauges") {
+            tracing::warn!(
+                "Too many distinct metrics for seen_gauges ({}), not tracking {} and others",
+                self.config.seen_gauges,
+                name
+            );
+        }
+    }
+}
+
+struct PointWrap<'a, P> {
+    point: P,
+    layer: &'a Inner,
+    /// Of the span or event being visited.
+    metadata: &'static Metadata<'static>,
+    /// Warnings to emit once the visit is done.
+    ///
+    /// The warning is an event too, which must not happen while the point of an event holds the
+    /// span locked.
+    warnings: Vec<String>,
+    /// The metrics collected to be recorded at the end of the visit, if batching.
+    batch: Option<Batch>,
+    /// Fields naming the dynamic counters, see `metrics.counter.dynamic`.
+    dynamic: Vec<String>,
+    /// Are all the numeric fields recorded as gauges (`metrics.auto_gauge`)?
+    auto_gauges: bool,
+    /// Are the counters and timers recorded, see [`DipstickLayerBuilder::sample_rate`]?
+    sampled: bool,
+    /// The span naming the bare metrics, see [`DipstickLayerBuilder::default_metric_name`].
+    ///
+    /// The one being visited or, for an event, the span it happens in.
+    span_name: Option<&'static str>,
+}
+
+/// The metrics of an event, collected during the visit and recorded at its end.
+///
+/// The names share a single buffer, so collecting them doesn't allocate for each metric.
+#[derive(Default)]
+struct Batch {
+    names: String,
+    metrics: SmallVec<[(MetricType, Range<usize>, i64); 4]>,
+}
+
+impl Batch {
+    fn push(&mut self, tp: MetricType, name: &str, value: i64) {
+        let start = self.names.len();
+        self.names.push_str(name);
+        self.metrics.push((tp, start..self.names.len(), value));
+    }
+}
+
+/// Collects the values of the fields naming the dynamic counters.
+struct FieldValues<'a> {
+    names: &'a [String],
+    values: Vec<Option<String>>,
+}
+
+impl FieldValues<'_> {
+    fn set(&mut self, field: &Field, value: impl FnOnce() -> String) {
+        let name = field.name();
+        if let Some(pos) = self.names.iter().position(|n| n == name) {
+            let value = value();
+            for (n, slot) in self.names[pos..].iter().zip(&mut self.values[pos..]) {
+                if n == name {
+                    *slot = Some(value.clone());
+                }
+            }
+        }
+    }
+}
+
+impl Visit for FieldValues<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field, || value.to_owned());
+    }
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.set(field, || format!("{:?}", value));
+    }
+}
+
+impl<'a, P: MetricPoint> PointWrap<'a, P> {
+    fn new(point: P, layer: &'a Inner, metadata: &'static Metadata<'static>) -> Self {
+        Self {
+            point,
+            layer,
+            metadata,
+            warnings: Vec::new(),
+            dynamic: Vec::new(),
+            auto_gauges: false,
+            batch: None,
+            sampled: layer.sampled(),
+            span_name: metadata.is_span().then(|| metadata.name()),
+        }
+    }
+
+    /// Resolves the name of a metric given by the attribute value, see [`Config::metric_name`].
+    fn metric_name<'n>(&self, value: &'n str) -> Option<Cow<'n, str>>
+    where
+        'a: 'n,
+    {
+        let layer: &'a Inner = self.layer;
+        layer.config.metric_name(value, self.span_name)
+    }
+
+    /// Counts the counters named by the fields, once the whole visit is done.
+    ///
+    /// The fields may come in any order, so this needs another pass over them.
+    fn record_dynamic<R: FnOnce(&mut dyn Visit)>(&mut self, record: R) {
+        if self.dynamic.is_empty() {
+            return;
+        }
+        let names = mem::take(&mut self.dynamic);
+        let mut values = FieldValues {
+            names: &names,
+            values: vec![None; names.len()],
+        };
+        record(&mut values);
+        let value = self.layer.config.counter_value();
+        for name in values.values.into_iter().flatten() {
+            if let Some(metric) = self.metric_name(&name) {
+                self.measure(MetricType::Counter, &metric, value);
+            }
+        }
+    }
+
+    /// Adds the level of the span or event to the name, see
+    /// [`DipstickLayerBuilder::append_level_suffix`].
+    ///
+    /// Also adds the name of the thread, see [`DipstickLayerBuilder::append_thread_name`].
+    fn suffixed<'n>(&self, name: &'n str) -> Cow<'n, str> {
+        let config = &self.layer.config;
+        if !config.level_suffix && !config.thread_suffix {
+            return Cow::Borrowed(name);
+        }
+        let mut name = name.to_owned();
+        if config.level_suffix {
+            name.push('.');
+            name.push_str(level_name(self.metadata.level()));
+        }
+        if config.thread_suffix {
+            THREAD_NAME.with(|thread| {
+                name.push('.');
+                name.push_str(thread);
+            });
+        }
+        Cow::Owned(name)
+    }
+
+    fn measure(&mut self, tp: MetricType, name: &str, value: i64) {
+        let name = self.suffixed(name);
+        if !self.sampled && tp.sampled() {
+            return;
+        }
+        let value = match (tp, self.layer.config.sample_rate) {
+            // Make up for the skipped spans and events
+            (MetricType::Counter, Some(rate)) => (value as f64 / rate).round() as i64,
+            _ => value,
+        };
+        match &mut self.batch {
+            Some(batch) => batch.push(tp, &name, value),
+            None => tp.measure(&mut self.point, self.layer, &name, value),
+        }
+    }
+
+    /// Records the metrics collected so far, if batching.
+    fn apply_batch(&mut self) {
+        if let Some(batch) = self.batch.take() {
+            for (tp, range, value) in batch.metrics {
+                tp.measure(&mut self.point, self.layer, &batch.names[range], value);
+            }
+        }
+    }
+
+    /// Starts or stops a timer of `metrics.timer.start` and `metrics.timer.stop`.
+    ///
+    /// Returns `false` for a stop without a running timer.
+    fn explicit_timer(&mut self, field: &Field, stop: bool, name: &str) -> bool {
+        let name = &*self.suffixed(name);
+        let scope = self.point.scope();
+        let key = full_name(scope, name);
+        let mut started = self
+            .layer
+            .started_timers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if !stop {
+            // Restarts the timer if it's already running
+            started.insert(key, self.layer.config.clock.now());
+            return true;
+        }
+        match started.remove(&key) {
+            Some(start) => {
+                drop(started);
+                let mut timer = self.layer.span_timer(scope.timer(name));
+                let summary = self.point.summary();
+                timer.name = summary.map(|_| name.to_owned());
+                timer.stop(start, summary);
+                self.layer.recorded.store(true, Ordering::Relaxed);
+                true
+            }
+            None => {
+                if self.layer.first_warning("orphaned stop", field.name()) {
+                    self.warnings.push(format!(
+                        "Ignoring the stop of timer {}, it was not started",
+                        key
+                    ));
+                }
+                false
+            }
+        }
+    }
+
+    /// Computes one of the special values (eg. `@level`) from the metadata.
+    fn meta_value(&self, token: &str) -> Option<i64> {
+        match token {
+            FIELD_COUNT_VALUE => Some(self.metadata.fields().len() as i64),
+            LEVEL_VALUE => Some(level_value(self.metadata.level())),
+            _ => None,
+        }
+    }
+
+    /// Is this a field captured by [`DipstickLayerBuilder::field_gauges`] or `metrics.auto_gauge`?
+    fn field_gauge(&self, name: &str) -> bool {
+        P::SCOPED
+            && !name.starts_with("metrics.")
+            && (self.auto_gauges || self.layer.config.field_gauges.contains(name))
+    }
+}
+
+impl<P: MetricPoint> PointWrap<'_, P> {
+    /// Counts the field into the [`LayerStats`], once it's been handled.
+    ///
+    /// Only the fields naming metrics count, not eg. `metrics.scope`.
+    fn tally(&self, name: &str, measured: bool) {
+        let stats = &self.layer.stats;
+        if measured {
+            stats.emitted.fetch_add(1, Ordering::Relaxed);
+        } else if name.starts_with("metrics.") && (!known_attribute(name) || metric_field(name)) {
+            stats.ignored.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Handles a string field.
+    ///
+    /// Returns if the field was turned into a metric (this goes for all the `*_value` methods).
+    fn str_value(&mut self, field: &Field, value: &str) -> bool {
+        let name = field.name();
+        if name == COUNTER_DYNAMIC {
+            self.dynamic.push(value.to_owned());
+            return true;
+        }
+        let target;
+        let value = if value == TARGET_NAME {
+            let mut name = String::new();
+            push_sanitized(&mut name, self.metadata.target());
+            target = name;
+            &target
+        } else {
+            value
+        };
+        if name == TIMER_START || name == TIMER_STOP {
+            return match self.metric_name(value) {
+                Some(metric) => self.explicit_timer(field, name == TIMER_STOP, &metric),
+                None => false,
+            };
+        }
+        if name == LEVEL_GAUGE {
+            return match self.metric_name(value) {
+                Some(metric) => {
+                    let level = level_value(self.metadata.level());
+                    self.measure(MetricType::Gauge, &metric, level);
+                    true
+                }
+                None => false,
+            };
+        }
+        for tp in METRIC_TYPES {
+            if (tp.3 || P::SCOPED) && name == tp.0 {
+                return match self.metric_name(value) {
+                    Some(metric) => {
+                        let value = match tp.2 {
+                            MetricType::Counter => self.layer.config.counter_value(),
+                            _ => 1,
+                        };
+                        self.measure(tp.2, &metric, value);
+                        true
+                    }
+                    None => false,
+                };
+            }
+        }
+        let value_form = METRIC_TYPES
+            .iter()
+            .any(|tp| tp.3 && !tp.1.is_empty() && name.starts_with(tp.1));
+        if value_form {
+            if let Some(number) = self.meta_value(value) {
+                return self.i64_value(field, number);
+            }
+        }
+        if self.layer.config.parse_string_values && value_form {
+            if let Ok(number) = value.parse::<i64>() {
+                return self.i64_value(field, number);
+            } else if let Ok(number) = value.parse::<f64>() {
+                return self.f64_value(field, number);
+            } else if self.layer.first_warning("unparsable", name) {
+                self.warnings.push(format!(
+                    "Ignoring non-numeric value {:?} of metric field {}",
+                    value, name
+                ));
+            }
+        }
+        false
+    }
+
+    fn i64_value(&mut self, field: &Field, value: i64) -> bool {
+        let name = field.name();
+        if self.field_gauge(name) {
+            self.measure(MetricType::Gauge, name, value);
+            return true;
+        }
+        if name_only(name) {
+            if self.layer.first_warning("reserved", name) {
+                self.warnings.push(format!(
+                    "Ignoring numeric value {} of field {}, it takes a metric name",
+                    value, name
+                ));
+            }
+            return false;
+        }
+        for tp in METRIC_TYPES {
+            // The empty prefix means there's no value form
+            if tp.3 && !tp.1.is_empty() && name.starts_with(tp.1) {
+                if matches!(tp.2, MetricType::Counter) && value < 0 {
+                    if self.layer.first_warning("negative", name) {
+                        self.warnings.push(format!(
+                            "Ignoring negative value {} of counter field {}",
+                            value, name
+                        ));
+                    }
+                    return false;
+                }
+                self.measure(tp.2, &name[tp.1.len()..], value);
+                return true;
+            }
+        }
+        false
+    }
+
+    fn i128_value(&mut self, field: &Field, value: i128) -> bool {
+        let name = field.name();
+        if !name.starts_with("metrics.") && !self.field_gauge(name) {
+            return false;
+        }
+        let clamped = value.clamp(i64::MIN.into(), i64::MAX.into()) as i64;
+        if i128::from(clamped) != value && self.layer.first_warning("clamped", name) {
+            self.warnings.push(format!(
+                "Clamping value {} of metric field {} to {}",
+                value, name, clamped
+            ));
+        }
+        self.i64_value(field, clamped)
+    }
+
+    fn f64_value(&mut self, field: &Field, value: f64) -> bool {
+        let name = field.name();
+        if !name.starts_with("metrics.") && !self.field_gauge(name) {
+            return false;
+        }
+        let value = match (self.layer.config.non_finite, value) {
+            // The cast saturates on overflow
+            (_, value) if value.is_finite() => value.round() as i64,
+            (NonFinite::Clamp, f64::INFINITY) => i64::MAX,
+            (NonFinite::Clamp, f64::NEG_INFINITY) => i64::MIN,
+            (_, value) => {
+                if self.layer.first_warning("non-finite", name) {
+                    self.warnings.push(format!(
+                        "Ignoring non-finite value {} of metric field {}",
+                        value, name
+                    ));
+                }
+                return false;
+            }
+        };
+        self.i64_value(field, value)
+    }
+}
+
+impl<P: MetricPoint> Visit for PointWrap<'_, P> {
+    fn record_debug(&mut self, field: &Field, _: &dyn Debug) {
+        self.tally(field.name(), false);
+    }
+    fn record_str(&mut self, field: &Field, value: &str) {
+        let measured = self.str_value(field, value);
+        self.tally(field.name(), measured);
+    }
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        let measured = self.i64_value(field, value);
+        self.tally(field.name(), measured);
+    }
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        // Too large values are clamped, not wrapped into negative ones
+        let measured = self.i128_value(field, value.into());
+        self.tally(field.name(), measured);
+    }
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        let measured = if METRIC_TYPES.iter().any(|tp| tp.0 == field.name()) {
+            // A bare metric, without a name (eg. `metrics.counter = true`)
+            !value || self.str_value(field, "")
+        } else {
+            self.i64_value(field, value.into())
+        };
+        self.tally(field.name(), measured);
+    }
+    fn record_i128(&mut self, field: &Field, value: i128) {
+        let measured = self.i128_value(field, value);
+        self.tally(field.name(), measured);
+    }
+    fn record_u128(&mut self, field: &Field, value: u128) {
+        let measured = self.i128_value(field, i128::try_from(value).unwrap_or(i128::MAX));
+        self.tally(field.name(), measured);
+    }
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        let measured = self.f64_value(field, value);
+        self.tally(field.name(), measured);
+    }
+}
+
+fn emit_warnings(warnings: Vec<String>) {
+    for warning in warnings {
+        tracing::warn!("{}", warning);
+    }
+}
+
+/// Runs the body of a destructor, making sure a panic can't escape it while already panicking.
+///
+/// The destructors of the parts of a [`Scope`] may run while unwinding from a panic in the
+/// scope's own destructor. Another panic escaping at that point would abort the process, so it is
+/// thrown away instead.
+pub(crate) fn drop_guarded<F: FnOnce()>(body: F) {
+    if thread::panicking() {
+        let _ = panic::catch_unwind(AssertUnwindSafe(body));
+    } else {
+        body();
+    }
+}
+
+/// Tracking of a `metrics.level.max` within one subtree.
+///
+/// Shared by the spans of the subtree, sets the gauge once the last of them goes away.
+struct MaxLevel {
+    current: AtomicI64,
+    max: AtomicI64,
+    gauge: Gauge,
+}
+
+impl MaxLevel {
+    fn hold(&self) {
+        let current = self.current.fetch_add(1, Ordering::Relaxed) + 1;
+        self.max.fetch_max(current, Ordering::Relaxed);
+    }
+
+    fn release(&self) {
+        self.current.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for MaxLevel {
+    fn drop(&mut self) {
+        let max = *self.max.get_mut();
+        drop_guarded(|| self.gauge.value(max));
+    }
+}
+
+/// Tracking of a `metrics.peak`, shared by all the spans with the same full name.
+#[derive(Debug, Default)]
+struct Peak {
+    /// The current and the highest number of spans holding it.
+    ///
+    /// Locked while setting the gauge, so concurrent updates can't make the gauge go down.
+    state: Mutex<(i64, i64)>,
+}
+
+impl Peak {
+    fn adjust(&self, delta: i64, gauge: &Gauge) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.0 += delta;
+        state.1 = state.1.max(state.0);
+        gauge.value(state.1);
+    }
+}
+
+/// The metrics stored for later by [`DipstickLayerBuilder::defer_to_close`].
+///
+/// Held by a span, records the metrics once the span goes away.
+struct DeferredMetrics<S> {
+    inner: Arc<Inner>,
+    metrics: Mutex<Vec<DeferredMetric<S>>>,
+    /// Records the metrics (a way to get the [`DipstickSink`] bound into the [`Drop`]).
+    record: fn(&Inner, Vec<DeferredMetric<S>>),
+}
+
+struct DeferredMetric<S> {
+    scope: S,
+    summary: Option<Arc<SummaryCell>>,
+    tp: MetricType,
+    name: String,
+    value: i64,
+}
+
+impl<S: DipstickSink> DeferredMetrics<S> {
+    fn new(inner: Arc<Inner>) -> Self {
+        DeferredMetrics {
+            inner,
+            metrics: Mutex::new(Vec::new()),
+            record: Self::record,
+        }
+    }
+
+    fn record(inner: &Inner, metrics: Vec<DeferredMetric<S>>) {
+        for metric in metrics {
+            let mut point = EventPoint {
+                scope: &metric.scope,
+                name: ScopeName::Inherit,
+                tags: Tags::new(),
+                level: None,
+                derived: OnceCell::new(),
+                summary: metric.summary.as_ref(),
+                deferred: None,
+                counters: None,
+                timer_batches: None,
+            };
+            metric
+                .tp
+                .measure(&mut point, inner, &metric.name, metric.value);
+        }
+    }
+}
+
+impl<S> Drop for DeferredMetrics<S> {
+    fn drop(&mut self) {
+        let metrics = self.metrics.get_mut().unwrap_or_else(|e| e.into_inner());
+        let metrics = mem::take(metrics);
+        drop_guarded(|| (self.record)(&self.inner, metrics));
+    }
+}
+
+/// A timer measuring the lifetime of a span.
+#[derive(Clone)]
+struct SpanTimer {
+    timer: Timer,
+    unit: TimerUnit,
+    /// The name is kept only if there's a summary to report into.
+    name: Option<String>,
+    /// The sequence number, for [`DipstickLayerBuilder::timer_sequence`].
+    seq: Option<u64>,
+    clock: SharedClock,
+}
+
+impl SpanTimer {
+    fn stop(self, start: Instant, summary: Option<&SummaryCell>) {
+        let elapsed = self.elapsed_us(start);
+        self.record(elapsed, summary);
+    }
+
+    /// The time since the start, in microseconds, according to the clock.
+    fn elapsed_us(&self, start: Instant) -> u64 {
+        let elapsed = self.clock.now().saturating_duration_since(start);
+        elapsed.as_micros().try_into().unwrap_or(u64::MAX)
+    }
+
+    /// Records the elapsed time, given in microseconds.
+    fn record(self, elapsed: u64, summary: Option<&SummaryCell>) {
+        let elapsed = Duration::from_micros(elapsed);
+        let value = self.unit.value(elapsed);
+        match self.seq {
+            Some(seq) => {
+                let labels = HashMap::from([("seq".to_owned(), Arc::new(seq.to_string()))]);
+                self.timer.write(value as _, Labels::from(labels));
+            }
+            None => {
+                self.timer.interval_us(value);
+            }
+        }
+        if let (Some(summary), Some(name)) = (summary, self.name) {
+            summary.time(&name, elapsed);
+        }
+    }
+}
+
+/// A timer accumulating the time a span is entered, for `metrics.timer.busy`.
+struct BusyTimer {
+    timer: SpanTimer,
+    state: Mutex<BusyState>,
+}
+
+#[derive(Default)]
+struct BusyState {
+    /// The time accumulated so far, in microseconds.
+    total: u64,
+    /// The enters not exited yet, from all the threads.
+    entered: Vec<(ThreadId, Instant)>,
+}
+
+impl BusyTimer {
+    fn enter(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state
+            .entered
+            .push((thread::current().id(), self.timer.clock.now()));
+    }
+
+    fn exit(&self) {
+        let thread = thread::current().id();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(pos) = state.entered.iter().rposition(|(t, _)| *t == thread) {
+            let (_, start) = state.entered.remove(pos);
+            state.total += self.timer.elapsed_us(start);
+        }
+    }
+
+    fn stop(&self, summary: Option<&SummaryCell>) {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        // Spans are not closed while entered, but just in case, count them up to now
+        let open = state
+            .entered
+            .iter()
+            .map(|(_, start)| self.timer.elapsed_us(*start));
+        let total = state.total + open.sum::<u64>();
+        self.timer.clone().record(total, summary);
+    }
+}
+
+/// The next shard to assign to a thread, for [`DipstickLayerBuilder::sharded_counters`].
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// The shard of the counters this thread uses.
+    static SHARD: usize = NEXT_SHARD.fetch_add(1, Ordering::Relaxed);
+    /// The spans entered on this thread, for [`DipstickLayerBuilder::dynamic_scopes`].
+    ///
+    /// Tagged by the layer that pushed them, as there may be multiple layers.
+    static ENTERED: RefCell<Vec<(usize, Id)>> = const { RefCell::new(Vec::new()) };
+    /// The buffer for the full names of metrics, for [`DipstickLayerBuilder::seen_gauges`].
+    static SEEN_KEY: RefCell<String> = const { RefCell::new(String::new()) };
+    /// The name of this thread, for [`DipstickLayerBuilder::append_thread_name`].
+    static THREAD_NAME: String = thread_name(&thread::current());
+    /// The state of the random generator for [`DipstickLayerBuilder::sample_rate`].
+    ///
+    /// Seeded from the random keys std uses for hash maps, so each thread gets its own sequence.
+    static RNG: Cell<u64> = Cell::new(RandomState::new().build_hasher().finish() | 1);
+}
+
+/// The name of the thread, or `thread-<id>` for unnamed ones.
+fn thread_name(thread: &Thread) -> String {
+    match thread.name() {
+        Some(name) => name.to_owned(),
+        None => {
+            // The numeric value of the id is not stable API, but the Debug format is `ThreadId(N)`
+            let id = format!("{:?}", thread.id());
+            let id: String = id.chars().filter(char::is_ascii_digit).collect();
+            format!("thread-{}", id)
+        }
+    }
+}
+
+/// Restarts the random sequence of the current thread, see [`testing::seed_sampling`].
+#[cfg(feature = "testing")]
+fn seed_random(seed: u64) {
+    // Xorshift gets stuck at zero
+    RNG.with(|rng| rng.set(seed | 1));
+}
+
+/// A random number in `[0, 1)`, for [`DipstickLayerBuilder::sample_rate`].
+///
+/// Xorshift is not of a great quality, but it is fast and good enough for sampling.
+fn random() -> f64 {
+    RNG.with(|rng| {
+        let mut x = rng.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        rng.set(x);
+        // The top 53 bits fit exactly into the mantissa
+        (x.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11) as f64 / (1u64 << 53) as f64
+    })
+}
+
+/// How a span names its scope, relative to its parent.
+#[derive(Clone, Debug, Default)]
+enum ScopeName {
+    #[default]
+    Inherit,
+    Add(String),
+    Full(String),
+}
+
+impl ScopeName {
+    fn apply<S: DipstickSink>(&self, parent: &S) -> S {
+        match self {
+            ScopeName::Inherit => parent.clone(),
+            // A dotted name is a shorthand for multiple nested scopes
+            ScopeName::Add(name) => name
+                .split('.')
+                .filter(|segment| !segment.is_empty())
+                .fold(parent.clone(), |scope, segment| scope.add_name(segment)),
+            ScopeName::Full(name) => parent.named(name),
+        }
+    }
+}
+
+/// The longest scope name made from a non-string value, in bytes.
+const DEBUG_SCOPE_LEN: usize = 64;
+
+/// Formats a non-string value of `metrics.scope` (eg. a numeric id), truncated to a sane length.
+fn debug_scope_name(value: &dyn Debug) -> String {
+    let mut name = format!("{:?}", value);
+    if name.len() > DEBUG_SCOPE_LEN {
+        let mut end = DEBUG_SCOPE_LEN;
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        name.truncate(end);
+    }
+    name
+}
+
+/// Collects the `metrics.scope` (or `metrics.scope.full`) of an event.
+#[derive(Default)]
+struct ScopeNameVisitor(ScopeName);
+
+impl ScopeNameVisitor {
+    fn collect<R: FnOnce(&mut dyn Visit)>(metadata: &Metadata, record: R) -> ScopeName {
+        let mut visitor = ScopeNameVisitor::default();
+        let named = metadata
+            .fields()
+            .iter()
+            .any(|field| field.name() == SCOPE_NAME || field.name() == SCOPE_NAME_FULL);
+        if named {
+            record(&mut visitor);
+        }
+        visitor.0
+    }
+}
+
+impl Visit for ScopeNameVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == SCOPE_NAME || field.name() == SCOPE_NAME_FULL {
+            self.record_str(field, &debug_scope_name(value));
+        }
+    }
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            SCOPE_NAME => self.0 = ScopeName::Add(value.to_owned()),
+            SCOPE_NAME_FULL => self.0 = ScopeName::Full(value.to_owned()),
+            _ => (),
+        }
+    }
+}
+
+/// The `metrics.tag.*` keys and values, sorted by the keys.
+type Tags = Vec<(&'static str, String)>;
+
+/// Looks for a `true` value of a single flag field.
+struct FlagVisitor(&'static str, bool);
+
+impl Visit for FlagVisitor {
+    fn record_debug(&mut self, _: &Field, _: &dyn Debug) {}
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        if field.name() == self.0 {
+            self.1 = value;
+        }
+    }
+}
+
+/// Reads the field guarding the metrics of an event, see `metrics.when`.
+///
+/// Stays `None` if the field is not a `bool`.
+struct GuardVisitor(&'static str, Option<bool>);
+
+impl Visit for GuardVisitor {
+    fn record_debug(&mut self, _: &Field, _: &dyn Debug) {}
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        if field.name() == self.0 {
+            self.1 = Some(value);
+        }
+    }
+}
+
+/// Collects the tags of a span or event.
+#[derive(Default)]
+struct TagVisitor(Tags);
+
+impl TagVisitor {
+    fn collect<R: FnOnce(&mut dyn Visit)>(metadata: &Metadata, record: R) -> Tags {
+        let mut visitor = TagVisitor::default();
+        let tagged = metadata
+            .fields()
+            .iter()
+            .any(|field| field.name().starts_with(TAG_PREFIX));
+        if tagged {
+            record(&mut visitor);
+            visitor.0.sort_by_key(|(key, _)| *key);
+        }
+        visitor.0
+    }
+}
+
+impl Visit for TagVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if let Some(key) = field.name().strip_prefix(TAG_PREFIX) {
+            self.0.push((key, value.to_owned()));
+        }
+    }
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if let Some(key) = field.name().strip_prefix(TAG_PREFIX) {
+            self.0.push((key, format!("{:?}", value)));
+        }
+    }
+}
+
+/// Adds the tags to the scope, each as the key and value segments.
+fn tagged<S: DipstickSink>(scope: S, tags: &[(&str, String)]) -> S {
+    tags.iter().fold(scope, |scope, (key, value)| {
+        scope.add_name(key).add_name(value)
+    })
+}
+
+/// Everything a span names its scope by.
+#[derive(Clone, Debug)]
+struct SpanNaming {
+    /// The name of the span itself, for [`DipstickLayerBuilder::use_span_name_prefix`].
+    span_name: Option<&'static str>,
+    name: ScopeName,
+    /// The value of the field named by `metrics.scope.field`.
+    field: Option<String>,
+    tags: Tags,
+}
+
+impl SpanNaming {
+    /// Does the span use the scope of its parent as it is?
+    fn inherits(&self) -> bool {
+        self.span_name.is_none()
+            && matches!(self.name, ScopeName::Inherit)
+            && self.field.is_none()
+            && self.tags.is_empty()
+    }
+
+    /// The scope of the span, sharing the one of the parent if possible.
+    fn apply_shared<S: DipstickSink>(&self, parent: &Arc<S>) -> Arc<S> {
+        if self.inherits() {
+            Arc::clone(parent)
+        } else {
+            Arc::new(self.apply(parent))
+        }
+    }
+
+    fn apply<S: DipstickSink>(&self, parent: &S) -> S {
+        let scope = match self.span_name {
+            Some(span_name) => self.name.apply(&parent.add_name(span_name)),
+            None => self.name.apply(parent),
+        };
+        let scope = match &self.field {
+            Some(field) => scope.add_name(field),
+            None => scope,
+        };
+        tagged(scope, &self.tags)
+    }
+}
+
+struct Scope<S> {
+    /// Shared with the parent, if the span doesn't change it.
+    scope: Arc<S>,
+    /// Kept for recomputing the scope on enter, if the span follows its dynamic parent.
+    dynamic: Option<SpanNaming>,
+    /// The running timers and the held levels, finished on drop.
+    ///
+    /// Usually, there's at most one of each, so they fit inline. The timers are kept before the
+    /// levels, so they are stopped first.
+    on_drop: SmallVec<[OnDrop; 2]>,
+    /// Timers waiting for the first enter of the span.
+    pending_timers: Vec<SpanTimer>,
+    /// Gauges with their reset values, for [`DipstickLayerBuilder::span_gauge_reset`].
+    gauges: Vec<(Gauge, i64)>,
+    /// The `metrics.timer.busy` of the span.
+    busy: Option<Arc<BusyTimer>>,
+    /// The metrics recorded on every enter, if the span has `metrics.on = "enter"`.
+    per_enter: Option<Box<PerEnter>>,
+    // TODO: CPU timers
+    summary: Option<Arc<SummaryCell>>,
+    /// All the max levels tracked in this subtree, by their names.
+    ///
+    /// Passed down to the descendants.
+    max_levels: Vec<(String, Arc<MaxLevel>)>,
+    /// The max levels this span holds.
+    held_max_levels: Vec<Arc<MaxLevel>>,
+    /// The metrics of the events inside, for [`DipstickLayerBuilder::defer_to_close`].
+    deferred: Option<Arc<DeferredMetrics<S>>>,
+    /// Flush the scope once closed, see [`DipstickLayerBuilder::flush_on_scope_close`].
+    flush: bool,
+    /// The periodic flushes of `metrics.flush_every`, cancelled by dropping once the span closes.
+    _flush_schedule: Option<TaskGuard>,
+    /// The children counts of this span and its ancestors with `metrics.count_children`.
+    ///
+    /// Passed down to the descendants.
+    child_counts: Vec<Arc<AtomicU64>>,
+    /// The children count of this span, recorded on close.
+    counted_children: Option<(Counter, Arc<AtomicU64>)>,
+    /// The counter of `metrics.not_entered`, removed on the first enter.
+    not_entered: Option<Counter>,
+    /// The counters of the events inside this span (but not inside a descendant with a scope).
+    counters: Arc<Counters>,
+    /// The timer values of the events inside this span, sent in batches.
+    timer_batches: Arc<TimerBatches>,
+    /// Does the span have `metrics.auto_gauge`, for the fields recorded later?
+    auto_gauges: bool,
+}
+
+/// Something to finish once a span closes.
+#[derive(Clone)]
+enum OnDrop {
+    Timer(SpanTimer, Instant),
+    Level(Level, i64, Option<Arc<AtomicI64>>),
+    Counter(Counter, i64),
+    Peak(Arc<Peak>, Gauge),
+}
+
+impl OnDrop {
+    fn finish(self, summary: Option<&SummaryCell>) {
+        match self {
+            OnDrop::Timer(timer, start) => timer.stop(start, summary),
+            OnDrop::Level(level, decrement, open) => {
+                level.adjust(decrement.saturating_neg());
+                if let Some(open) = open {
+                    open.fetch_sub(decrement, Ordering::Relaxed);
+                }
+            }
+            OnDrop::Counter(counter, increment) => {
+                // Counter::count takes only unsigned amounts, but the metric underneath is signed
+                // and the backends sum what is written to it. The increment is never negative
+                // (such are ignored), so the negation can't overflow.
+                let increment = isize::try_from(increment).unwrap_or(isize::MAX);
+                counter.write(-increment, Labels::default());
+            }
+            OnDrop::Peak(peak, gauge) => peak.adjust(-1, &gauge),
+        }
+    }
+}
+
+/// The metrics of a span recorded on every enter, see `metrics.on`.
+#[derive(Clone, Default)]
+struct PerEnter {
+    /// Shared with the enters in progress, which record them without the span locked.
+    metrics: Arc<Vec<(MetricType, String, i64)>>,
+    /// What to finish on exit, one entry for each enter not exited yet.
+    on_exit: Vec<(ThreadId, Vec<OnDrop>)>,
+}
+
+/// Records the metrics of a span on enter.
+///
+/// The span is not locked meanwhile, so a scope calling back into [`tracing`] or panicking can't
+/// break it. What is to be finished on exit is handed over to the span on drop, even if the
+/// recording panics.
+struct EnterPoint<'a, S, I>
+where
+    S: DipstickSink,
+    I: for<'l> LookupSpan<'l>,
+{
+    span: SpanRef<'a, I>,
+    scope: Arc<S>,
+    summary: Option<Arc<SummaryCell>>,
+    on_exit: Vec<OnDrop>,
+}
+
+impl<S, I> Drop for EnterPoint<'_, S, I>
+where
+    S: DipstickSink,
+    I: for<'l> LookupSpan<'l>,
+{
+    fn drop(&mut self) {
+        let on_exit = mem::take(&mut self.on_exit);
+        drop_guarded(|| {
+            let mut extensions = self.span.extensions_mut();
+            let scope = extensions.get_mut::<Scope<S>>();
+            if let Some(per_enter) = scope.and_then(|scope| scope.per_enter.as_mut()) {
+                per_enter.on_exit.push((thread::current().id(), on_exit));
+            }
+        });
+    }
+}
+
+impl<S, I> MetricPoint for EnterPoint<'_, S, I>
+where
+    S: DipstickSink,
+    I: for<'l> LookupSpan<'l>,
+{
+    const SCOPED: bool = true;
+    type Scope = S;
+
+    fn push_timer(&mut self, name: &str, mut timer: SpanTimer, start: Option<Instant>) {
+        timer.name = self.summary.as_ref().map(|_| name.to_owned());
+        let start = start.unwrap_or_else(|| timer.clock.now());
+        // Timers before levels, as on close
+        let pos = self
+            .on_exit
+            .iter()
+            .position(|item| matches!(item, OnDrop::Level(..)))
+            .unwrap_or(self.on_exit.len());
+        self.on_exit.insert(pos, OnDrop::Timer(timer, start));
+    }
+
+    fn push_busy_timer(&mut self, _: &str, _: SpanTimer) {
+        unreachable!("Busy timers are recorded on creation");
+    }
+
+    fn push_level(&mut self, level: Level, decrement: i64, open: Option<Arc<AtomicI64>>) {
+        self.on_exit.push(OnDrop::Level(level, decrement, open));
+    }
+
+    fn push_counter(&mut self, counter: Counter, increment: i64) {
+        self.on_exit.push(OnDrop::Counter(counter, increment));
+    }
+
+    fn push_gauge(&mut self, _: Gauge, _: i64) {
+        // Gauges set on enter are not reset
+    }
+
+    fn hold_max_level(&mut self, _: &str, _: Gauge) {
+        unreachable!("Max levels are recorded on creation");
+    }
+
+    fn push_peak(&mut self, peak: Arc<Peak>, gauge: Gauge) {
+        self.on_exit.push(OnDrop::Peak(peak, gauge));
+    }
+
+    fn scope(&self) -> &S {
+        &self.scope
+    }
+
+    fn summary(&self) -> Option<&SummaryCell> {
+        self.summary.as_deref()
+    }
+}
+
+/// Something a span holds until it's closed, see [`RecordPoint`].
+enum Held {
+    Timer(String, SpanTimer, Option<Instant>),
+    BusyTimer(String, SpanTimer),
+    Level(Level, i64, Option<Arc<AtomicI64>>),
+    Counter(Counter, i64),
+    Gauge(Gauge, i64),
+    MaxLevel(String, Gauge),
+    Peak(Arc<Peak>, Gauge),
+    PerEnter(MetricType, String, i64),
+}
+
+impl Held {
+    fn hand_over<S: DipstickSink>(self, scope: &mut Scope<S>) {
+        match self {
+            Held::Timer(name, timer, start) => scope.push_timer(&name, timer, start),
+            Held::BusyTimer(name, timer) => scope.push_busy_timer(&name, timer),
+            Held::Level(level, decrement, open) => scope.push_level(level, decrement, open),
+            Held::Counter(counter, increment) => scope.push_counter(counter, increment),
+            Held::Gauge(gauge, reset) => scope.push_gauge(gauge, reset),
+            Held::MaxLevel(name, gauge) => scope.hold_max_level(&name, gauge),
+            Held::Peak(peak, gauge) => scope.push_peak(peak, gauge),
+            Held::PerEnter(tp, name, value) => {
+                scope.defer(tp, &name, value);
+            }
+        }
+    }
+}
+
+/// Records the fields of a span recorded after its creation.
+///
+/// Like with [`EnterPoint`], the span is not locked meanwhile. What the scope of the span needs to
+/// finish once it closes is collected and handed over to it on drop, even if the recording panics.
+struct RecordPoint<'a, S, I>
+where
+    S: DipstickSink,
+    I: for<'l> LookupSpan<'l>,
+{
+    span: SpanRef<'a, I>,
+    scope: Arc<S>,
+    summary: Option<Arc<SummaryCell>>,
+    /// Does the span record its metrics on enter (`metrics.on`)?
+    per_enter: bool,
+    held: Vec<Held>,
+}
+
+impl<S, I> Drop for RecordPoint<'_, S, I>
+where
+    S: DipstickSink,
+    I: for<'l> LookupSpan<'l>,
+{
+    fn drop(&mut self) {
+        let held = mem::take(&mut self.held);
+        drop_guarded(|| {
+            let mut extensions = self.span.extensions_mut();
+            if let Some(scope) = extensions.get_mut::<Scope<S>>() {
+                for item in held {
+                    item.hand_over(scope);
+                }
+            }
+        });
+    }
+}
+
+impl<S, I> MetricPoint for RecordPoint<'_, S, I>
+where
+    S: DipstickSink,
+    I: for<'l> LookupSpan<'l>,
+{
+    const SCOPED: bool = true;
+    type Scope = S;
+
+    fn push_timer(&mut self, name: &str, timer: SpanTimer, start: Option<Instant>) {
+        self.held.push(Held::Timer(name.to_owned(), timer, start));
+    }
+
+    fn push_busy_timer(&mut self, name: &str, timer: SpanTimer) {
+        self.held.push(Held::BusyTimer(name.to_owned(), timer));
+    }
+
+    fn push_level(&mut self, level: Level, decrement: i64, open: Option<Arc<AtomicI64>>) {
+        self.held.push(Held::Level(level, decrement, open));
+    }
+
+    fn push_counter(&mut self, counter: Counter, increment: i64) {
+        self.held.push(Held::Counter(counter, increment));
+    }
+
+    fn push_gauge(&mut self, gauge: Gauge, reset: i64) {
+        self.held.push(Held::Gauge(gauge, reset));
+    }
+
+    fn hold_max_level(&mut self, name: &str, gauge: Gauge) {
+        self.held.push(Held::MaxLevel(name.to_owned(), gauge));
+    }
+
+    fn push_peak(&mut self, peak: Arc<Peak>, gauge: Gauge) {
+        self.held.push(Held::Peak(peak, gauge));
+    }
+
+    fn scope(&self) -> &S {
+        &self.scope
+    }
+
+    fn summary(&self) -> Option<&SummaryCell> {
+        self.summary.as_deref()
+    }
+
+    fn defer(&mut self, tp: MetricType, name: &str, value: i64) -> bool {
+        let defer = self.per_enter && tp.per_enter();
+        if defer {
+            self.held.push(Held::PerEnter(tp, name.to_owned(), value));
+        }
+        defer
+    }
+}
+
+impl<S> Scope<S> {
+    fn push_running_timer(&mut self, timer: SpanTimer, start: Instant) {
+        let pos = self
+            .on_drop
+            .iter()
+            .position(|item| matches!(item, OnDrop::Level(..)))
+            .unwrap_or(self.on_drop.len());
+        self.on_drop.insert(pos, OnDrop::Timer(timer, start));
+    }
+
+    /// Counts a new descendant span, see `metrics.count_children`.
+    fn count_child(&self) {
+        for count in &self.child_counts {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<S> Drop for Scope<S> {
+    fn drop(&mut self) {
+        // Stopped with the other timers, before the levels
+        if let Some(busy) = self.busy.take() {
+            busy.stop(self.summary.as_deref());
+        }
+        for item in self.on_drop.drain(..) {
+            item.finish(self.summary.as_deref());
+        }
+        if let Some(per_enter) = self.per_enter.take() {
+            for (_, items) in per_enter.on_exit {
+                for item in items {
+                    item.finish(self.summary.as_deref());
+                }
+            }
+        }
+
+        for (gauge, reset) in self.gauges.drain(..) {
+            gauge.value(reset);
+        }
+
+        for max_level in self.held_max_levels.drain(..) {
+            max_level.release();
+        }
+
+        if let Some((counter, count)) = self.counted_children.take() {
+            counter.count(count.load(Ordering::Relaxed) as _);
+        }
+
+        if let Some(counter) = self.not_entered.take() {
+            counter.count(1);
+        }
+    }
+}
+
+impl<S: DipstickSink> MetricPoint for Scope<S> {
+    const SCOPED: bool = true;
+    type Scope = S;
+    fn push_level(&mut self, level: Level, decrement: i64, open: Option<Arc<AtomicI64>>) {
+        self.on_drop.push(OnDrop::Level(level, decrement, open));
+    }
+    fn push_counter(&mut self, counter: Counter, increment: i64) {
+        self.on_drop.push(OnDrop::Counter(counter, increment));
+    }
+    fn push_gauge(&mut self, gauge: Gauge, reset: i64) {
+        self.gauges.push((gauge, reset));
+    }
+    fn hold_max_level(&mut self, name: &str, gauge: Gauge) {
+        let max_level = match self.max_levels.iter().find(|(n, _)| n == name) {
+            Some((_, max_level)) => Arc::clone(max_level),
+            None => {
+                let max_level = Arc::new(MaxLevel {
+                    current: AtomicI64::new(0),
+                    max: AtomicI64::new(0),
+                    gauge,
+                });
+                self.max_levels
+                    .push((name.to_owned(), Arc::clone(&max_level)));
+                max_level
+            }
+        };
+        max_level.hold();
+        self.held_max_levels.push(max_level);
+    }
+    fn push_peak(&mut self, peak: Arc<Peak>, gauge: Gauge) {
+        self.on_drop.push(OnDrop::Peak(peak, gauge));
+    }
+    fn push_timer(&mut self, name: &str, mut timer: SpanTimer, start: Option<Instant>) {
+        timer.name = self.summary.as_ref().map(|_| name.to_owned());
+        match start {
+            Some(start) => self.push_running_timer(timer, start),
+            None => self.pending_timers.push(timer),
+        }
+    }
+    fn push_busy_timer(&mut self, name: &str, mut timer: SpanTimer) {
+        timer.name = self.summary.as_ref().map(|_| name.to_owned());
+        self.busy = Some(Arc::new(BusyTimer {
+            timer,
+            state: Mutex::default(),
+        }));
+    }
+    fn scope(&self) -> &S {
+        &self.scope
+    }
+    fn summary(&self) -> Option<&SummaryCell> {
+        self.summary.as_deref()
+    }
+    fn defer(&mut self, tp: MetricType, name: &str, value: i64) -> bool {
+        match &mut self.per_enter {
+            Some(per_enter) if tp.per_enter() => {
+                Arc::make_mut(&mut per_enter.metrics).push((tp, name.to_owned(), value));
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The point of an event.
+///
+/// Borrows from the nearest span with a scope, so the events without metrics don't have to clone
+/// anything.
+struct EventPoint<'a, S> {
+    scope: &'a S,
+    /// The `metrics.scope` of the event itself.
+    name: ScopeName,
+    /// The tags of the event itself.
+    tags: Tags,
+    /// The name to add to the scope, see [`DipstickLayerBuilder::level_scope`].
+    level: Option<&'static str>,
+    /// The scope with the name, tags and level added, created on the first metric.
+    derived: OnceCell<S>,
+    summary: Option<&'a Arc<SummaryCell>>,
+    deferred: Option<&'a Arc<DeferredMetrics<S>>>,
+    /// The cache of counters of the `scope` (not the derived one).
+    counters: Option<&'a Counters>,
+    /// The timer values of the `scope` (not the derived one) waiting to be sent.
+    timer_batches: Option<&'a TimerBatches>,
+}
+
+impl<S> EventPoint<'_, S> {
+    /// Does the event change the scope of its span?
+    fn derives(&self) -> bool {
+        !matches!(self.name, ScopeName::Inherit) || !self.tags.is_empty() || self.level.is_some()
+    }
+}
+
+impl<S: DipstickSink> MetricPoint for EventPoint<'_, S> {
+    const SCOPED: bool = false;
+    type Scope = S;
+
+    fn push_timer(&mut self, _: &str, _: SpanTimer, _: Option<Instant>) {
+        unreachable!("Timers are not supported on events");
+    }
+
+    fn push_busy_timer(&mut self, _: &str, _: SpanTimer) {
+        unreachable!("Timers are not supported on events");
+    }
+
+    fn push_level(&mut self, _: Level, _: i64, _: Option<Arc<AtomicI64>>) {
+        // Levels on events are decremented manually, not at the end of some scope
+    }
+
+    fn push_counter(&mut self, _: Counter, _: i64) {
+        unreachable!("Counters are undone only on spans");
+    }
+
+    fn push_gauge(&mut self, _: Gauge, _: i64) {
+        // Gauges on events are not reset
+    }
+
+    fn hold_max_level(&mut self, _: &str, _: Gauge) {
+        unreachable!("Max levels are not supported on events");
+    }
+
+    fn push_peak(&mut self, _: Arc<Peak>, _: Gauge) {
+        unreachable!("Peaks are not supported on events");
+    }
+
+    fn scope(&self) -> &S {
+        if !self.derives() {
+            return self.scope;
+        }
+        self.derived.get_or_init(|| {
+            let scope = tagged(self.name.apply(self.scope), &self.tags);
+            match self.level {
+                Some(level) => scope.add_name(level),
+                None => scope,
+            }
+        })
+    }
+
+    fn summary(&self) -> Option<&SummaryCell> {
+        self.summary.map(|summary| &**summary)
+    }
+
+    fn count(&self, name: &str, value: i64) {
+        match self.counters {
+            Some(counters) if !self.derives() => counters.count(self.scope, name, value),
+            // The derived scope lives only for this event, caching would not help
+            _ => self.scope().counter(name).count(value as _),
+        }
+    }
+
+    fn time(&self, name: &str, value: u64) {
+        match self.timer_batches {
+            Some(batches) if !self.derives() => batches.time(self.scope, name, value),
+            // The derived scope lives only for this event, there's nothing to batch with
+            _ => {
+                self.scope().timer(name).interval_us(value);
+            }
+        }
+    }
+
+    fn defer(&mut self, tp: MetricType, name: &str, value: i64) -> bool {
+        match self.deferred {
+            Some(deferred) => {
+                deferred
+                    .metrics
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .push(DeferredMetric {
+                        scope: self.scope().clone(),
+                        summary: self.summary.cloned(),
+                        tp,
+                        name: name.to_owned(),
+                        value,
+                    });
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// The bridge from [`tracing`](https://docs.rs/tracing) to [`dipstick`].
+///
+/// This takes information from tracing and propagates them into [`dipstick`] as metrics. It works
+/// as [`Layer`].
+///
+/// # Warning
+///
+/// The layer expects to get all the spans and events. If there's another layer that filters
+/// globally (for example based on the level), it'll impact this layer too and the gathered
+/// metrics would be incomplete. See the [crate level documentation](crate#filtering) for how to
+/// avoid that.
+///
+/// # Examples
+///
+/// ```rust
+/// use std::time::Duration;
+///
+/// use dipstick::{AtomicBucket, ScheduleFlush, Stream};
+/// use log::LevelFilter;
+/// use tracing::subscriber;
+/// use tracing_dipstick::DipstickLayer;
+/// use tracing_subscriber::layer::SubscriberExt;
+/// use tracing_subscriber::Registry;
+///
+/// env_logger::builder()
+///     .filter_level(LevelFilter::Info)
+///     .parse_default_env()
+///     .init();
+///
+/// let root = AtomicBucket::new();
+/// root.stats(dipstick::stats_all);
+/// root.drain(Stream::write_to_stdout());
+/// let _flush = root.flush_every(Duration::from_secs(5));
+///
+/// let bridge = DipstickLayer::new(root);
+/// let subscriber = Registry::default().with(bridge);
+///
+/// subscriber::set_global_default(subscriber).unwrap();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct DipstickLayer<S> {
+    root: SharedRoot<S>,
+    inner: Arc<Inner>,
+}
+
+impl<S> DipstickLayer<S>
+where
+    S: DipstickSink,
+{
+    /// Creates the bridge.
+    ///
+    /// Expects the scope into which it will put metrics.
+    pub fn new(input_scope: S) -> Self {
+        Self::builder(input_scope).finish()
+    }
+
+    /// Starts building a customized bridge.
+    ///
+    /// Expects the scope into which it will put metrics. Using the builder with all the default
+    /// settings is equivalent to [`new`][DipstickLayer::new].
+    pub fn builder(input_scope: S) -> DipstickLayerBuilder<S> {
+        DipstickLayerBuilder {
+            scope: input_scope,
+            config: Config::default(),
+        }
+    }
+
+    /// Does the span need its own [`Scope`]?
+    ///
+    /// That is, does it have any metrics attributes (or fields captured as gauges)? With
+    /// [`DipstickLayerBuilder::defer_to_close`], all the spans need one, to store the metrics.
+    fn touches_metrics(&self, metadata: &Metadata) -> bool {
+        let config = &self.inner.config;
+        config.defer_to_close
+            || metadata.fields().iter().any(|field| {
+                field.name().starts_with("metrics.") || config.field_gauges.contains(field.name())
+            })
+    }
+
+    /// Schedules the periodic flushes of a span with `metrics.flush_every`.
+    fn schedule_flush(&self, scope: &Arc<S>, period: Duration) -> TaskGuard {
+        let scope = Arc::clone(scope);
+        Maintenance::add_running(&self.inner, period, move |_| {
+            let _ = scope.flush();
+        })
+    }
+
+    /// Identifies this layer (and its clones) in the [`ENTERED`] spans.
+    fn tag(&self) -> usize {
+        Arc::as_ptr(&self.inner) as usize
+    }
+
+    /// Wraps the layer into a per-layer filter that lets everything through.
+    ///
+    /// Filters of other layers then don't influence this one. See the [crate level
+    /// documentation](crate#filtering).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "testing")] {
+    /// use std::io;
+    ///
+    /// use tracing::subscriber;
+    /// use tracing_dipstick::DipstickLayer;
+    /// use tracing_dipstick::testing::RecordingScope;
+    /// use tracing_subscriber::layer::SubscriberExt;
+    /// use tracing_subscriber::{fmt, EnvFilter, Layer, Registry};
+    ///
+    /// let recording = RecordingScope::new();
+    /// let logging = fmt::layer()
+    ///     .with_writer(io::sink)
+    ///     .with_filter(EnvFilter::new("error"));
+    /// let subscriber = Registry::default()
+    ///     .with(logging)
+    ///     .with(DipstickLayer::new(recording.clone()).accept_all());
+    /// subscriber::with_default(subscriber, || {
+    ///     tracing::debug!(metrics.counter = "hits", "Not logged, but counted");
+    /// });
+    /// assert_eq!(1, recording.events().len());
+    /// # }
+    /// ```
+    pub fn accept_all<I>(self) -> Filtered<Self, LevelFilter, I>
+    where
+        I: Subscriber,
+        for<'l> I: LookupSpan<'l>,
+    {
+        self.with_filter(LevelFilter::TRACE)
+    }
+
+    /// The name of the scope the metrics of the current span go to.
+    ///
+    /// This is the accumulated prefix from the `metrics.scope` (and `metrics.tag`) attributes of
+    /// the current span and all its ancestors, joined by the separator (`.` by default, see
+    /// [`DipstickLayerBuilder::scope_separator`]). The name of the root scope is empty, unless it
+    /// has prefixes of its own. The additions done for individual events (eg. by
+    /// [`DipstickLayerBuilder::level_scope`]) are not included.
+    ///
+    /// This is meant for debugging and tests, eg. for other layers that want to log where the
+    /// metrics go.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "testing")] {
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// use tracing::{subscriber, Event, Subscriber};
+    /// use tracing_dipstick::testing::RecordingScope;
+    /// use tracing_dipstick::DipstickLayer;
+    /// use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+    /// use tracing_subscriber::registry::LookupSpan;
+    /// use tracing_subscriber::Registry;
+    ///
+    /// struct ScopeNames(DipstickLayer<RecordingScope>, Arc<Mutex<Vec<String>>>);
+    ///
+    /// impl<S: Subscriber + for<'l> LookupSpan<'l>> Layer<S> for ScopeNames {
+    ///     fn on_event(&self, _: &Event, ctx: Context<S>) {
+    ///         let name = self.0.current_scope_name(&ctx);
+    ///         self.1.lock().unwrap().push(name);
+    ///     }
+    /// }
+    ///
+    /// let bridge = DipstickLayer::new(RecordingScope::new());
+    /// let names = Arc::new(Mutex::new(Vec::new()));
+    /// let subscriber = Registry::default()
+    ///     .with(bridge.clone())
+    ///     .with(ScopeNames(bridge, Arc::clone(&names)));
+    /// subscriber::with_default(subscriber, || {
+    ///     tracing::info!("Outside");
+    ///     let _outer = tracing::info_span!("outer", metrics.scope = "outer").entered();
+    ///     let _inner = tracing::info_span!("inner", metrics.scope = "inner").entered();
+    ///     tracing::info!("Inside");
+    /// });
+    /// assert_eq!(vec!["", "outer.inner"], *names.lock().unwrap());
+    /// # }
+    /// ```
+    pub fn current_scope_name<I>(&self, ctx: &Context<'_, I>) -> String
+    where
+        I: Subscriber,
+        for<'l> I: LookupSpan<'l>,
+    {
+        nearest_scope(ctx.lookup_current(), |scope: &Scope<S>| {
+            scope.scope.scope_name()
+        })
+        .unwrap_or_else(|| self.root.get().scope.scope_name())
+    }
+
+    /// Creates a [`Recorder`] for recording metrics without [`tracing`](https://docs.rs/tracing).
+    ///
+    /// The recorder puts the metrics into the same root scope and shares the configuration with
+    /// this layer.
+    pub fn recorder(&self) -> Recorder<S> {
+        Recorder {
+            scope: self.root.get().scope.clone(),
+            inner: Arc::clone(&self.inner),
+        }
+    }
+
+    /// Replaces the root scope.
+    ///
+    /// The metrics of the spans created and events recorded afterwards (outside of the scoped
+    /// spans) go into the new scope. This can be used for example to switch to a fresh bucket
+    /// between requests or tenants, without rebuilding the whole subscriber. All the clones of the
+    /// layer share the root scope, so replacing it in one affects all of them.
+    ///
+    /// The change is atomic, but it doesn't affect anything already in flight. The spans that
+    /// exist at the time keep the scope they got on their creation, including their timers and
+    /// levels finished when they close, and so do the events inside them. Similarly, the already
+    /// created [recorders][DipstickLayer::recorder] keep the old scope.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "testing")] {
+    /// use tracing::subscriber;
+    /// use tracing_dipstick::DipstickLayer;
+    /// use tracing_dipstick::testing::RecordingScope;
+    /// use tracing_subscriber::layer::SubscriberExt;
+    /// use tracing_subscriber::Registry;
+    ///
+    /// let old = RecordingScope::new();
+    /// let new = RecordingScope::new();
+    /// let bridge = DipstickLayer::new(old.clone());
+    /// subscriber::with_default(Registry::default().with(bridge.clone()), || {
+    ///     let span = tracing::info_span!("request", metrics.scope = "request");
+    ///     tracing::info!(metrics.counter = "hits");
+    ///     bridge.set_scope(new.clone());
+    ///     tracing::info!(metrics.counter = "hits");
+    ///     // The existing span still records into the old scope
+    ///     span.in_scope(|| tracing::info!(metrics.counter = "inside"));
+    /// });
+    /// assert_eq!(vec![1], old.values("hits"));
+    /// assert_eq!(vec![1], old.values("request.inside"));
+    /// assert_eq!(vec![1], new.values("hits"));
+    /// # }
+    /// ```
+    pub fn set_scope(&self, scope: S) {
+        self.inner.config.pre_register(&scope);
+        self.inner.monitor_root(&scope);
+        self.root.set(scope);
+    }
+
+    /// A snapshot of the counts of metric fields emitted and ignored so far.
+    ///
+    /// The counts are shared by all the clones of the layer and are always on. They can help
+    /// validating the coverage of the instrumentation ‒ a growing ignored count means some
+    /// `metrics.*` fields don't do what they were meant to.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "testing")] {
+    /// use tracing::subscriber;
+    /// use tracing_dipstick::DipstickLayer;
+    /// use tracing_dipstick::testing::RecordingScope;
+    /// use tracing_subscriber::layer::SubscriberExt;
+    /// use tracing_subscriber::Registry;
+    ///
+    /// let bridge = DipstickLayer::new(RecordingScope::new());
+    /// subscriber::with_default(Registry::default().with(bridge.clone()), || {
+    ///     tracing::info!(metrics.counter = "hits", metrics.gauge.size = 42);
+    ///     assert_eq!((2, 0), (bridge.stats().emitted, bridge.stats().ignored));
+    ///     // A typo
+    ///     tracing::info!(metrics.countre = "hits");
+    ///     assert_eq!((2, 1), (bridge.stats().emitted, bridge.stats().ignored));
+    ///     // Not a metric, doesn't count
+    ///     tracing::info!(metrics.scope = "request", metrics.counter = "hits");
+    ///     assert_eq!((3, 1), (bridge.stats().emitted, bridge.stats().ignored));
+    /// });
+    /// # }
+    /// ```
+    pub fn stats(&self) -> LayerStats {
+        let stats = &self.inner.stats;
+        LayerStats {
+            emitted: stats.emitted.load(Ordering::Relaxed),
+            ignored: stats.ignored.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Lists the levels currently held by open spans.
+    ///
+    /// A `metrics.level` of a span is decremented once the span closes. If a span is never closed
+    /// (eg. it is leaked or stored somewhere by a mistake), the level stays up forever. This
+    /// returns the names and the outstanding amounts of the levels the layer still holds, sorted
+    /// by the names, so such leaks can be detected in tests or at shutdown. The levels adjusted
+    /// manually by events are not included. Once all the spans are closed, the result is empty.
+    ///
+    /// The accounting needs to be turned on by [`DipstickLayerBuilder::track_open_levels`],
+    /// otherwise the result is always empty.
+    ///
+    /// The names include all the prefixes of the scopes, joined by `.` (regardless of the
+    /// [separator][DipstickLayerBuilder::scope_separator]). The accounting is shared by all the
+    /// clones of the layer and is safe to use from multiple threads, though the result is only a
+    /// snapshot while other threads open and close spans.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "testing")] {
+    /// use tracing::subscriber;
+    /// use tracing_dipstick::DipstickLayer;
+    /// use tracing_dipstick::testing::RecordingScope;
+    /// use tracing_subscriber::layer::SubscriberExt;
+    /// use tracing_subscriber::Registry;
+    ///
+    /// let bridge = DipstickLayer::builder(RecordingScope::new())
+    ///     .track_open_levels(true)
+    ///     .finish();
+    /// subscriber::with_default(Registry::default().with(bridge.clone()), || {
+    ///     let _outer = tracing::info_span!("outer", metrics.scope = "requests").entered();
+    ///     drop(tracing::info_span!("closed", metrics.level = "active"));
+    ///     // Oops, this one is never closed
+    ///     std::mem::forget(tracing::info_span!("leaked", metrics.level.active = 2));
+    ///     let expected = vec![("requests.active".to_owned(), 2)];
+    ///     assert_eq!(expected, bridge.open_levels());
+    /// });
+    /// # }
+    /// ```
+    pub fn open_levels(&self) -> Vec<(String, i64)> {
+        let open = self
+            .inner
+            .open_levels
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let mut levels: Vec<_> = open
+            .iter()
+            .map(|(name, value)| (name.clone(), value.load(Ordering::Relaxed)))
+            .filter(|(_, value)| *value != 0)
+            .collect();
+        levels.sort();
+        levels
+    }
+
+    /// Turns the recording of metrics on or off at runtime.
+    ///
+    /// While disabled, the new spans and the events are ignored (as if they had no `metrics.*`
+    /// attributes), which costs only a check of a flag. The layer stays in the subscriber, so
+    /// recording can be turned on again at any time. The flag is shared by all the clones of the
+    /// layer. The layer is enabled by default.
+    ///
+    /// The spans created while enabled keep their metrics until they close, even if the layer is
+    /// disabled in the meantime. Their levels are decreased, the timers are recorded and so on,
+    /// so the levels don't leak. Conversely, the spans created while disabled don't record
+    /// anything even after enabling the layer again.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "testing")] {
+    /// use tracing::subscriber;
+    /// use tracing_dipstick::DipstickLayer;
+    /// use tracing_dipstick::testing::RecordingScope;
+    /// use tracing_subscriber::layer::SubscriberExt;
+    /// use tracing_subscriber::Registry;
+    ///
+    /// let recording = RecordingScope::new();
+    /// let bridge = DipstickLayer::new(recording.clone());
+    /// subscriber::with_default(Registry::default().with(bridge.clone()), || {
+    ///     let before = tracing::info_span!("before", metrics.level = "active");
+    ///     bridge.set_enabled(false);
+    ///     assert!(!bridge.is_enabled());
+    ///     tracing::info!(metrics.counter = "hits");
+    ///     let during = tracing::info_span!("during", metrics.level = "active");
+    ///     // Created while enabled, the level still goes down
+    ///     drop(before);
+    ///     bridge.set_enabled(true);
+    ///     drop(during);
+    ///     tracing::info!(metrics.counter = "hits");
+    /// });
+    /// assert_eq!(vec![1, -1], recording.values("active"));
+    /// assert_eq!(vec![1], recording.values("hits"));
+    /// # }
+    /// ```
+    pub fn set_enabled(&self, enabled: bool) {
+        self.inner.disabled.store(!enabled, Ordering::Relaxed);
+    }
+
+    /// Is the layer recording the metrics?
+    ///
+    /// See [`set_enabled`][DipstickLayer::set_enabled].
+    pub fn is_enabled(&self) -> bool {
+        !self.inner.disabled.load(Ordering::Relaxed)
+    }
+
+    /// Runs the periodic work of the layer that is due by now.
+    ///
+    /// Some features (eg. the [watchdog][DipstickLayerBuilder::watchdog] or the [counter
+    /// bursts][DipstickLayerBuilder::counter_bursts]) need some work done periodically. This is
+    /// done by a single background thread, shared by all of them, started with the layer (if any
+    /// of them is configured) and terminated once the layer is dropped. There's usually no need to
+    /// call this.
+    ///
+    /// The schedule follows the [`Clock`] of the layer. Therefore, tests can set a mock clock,
+    /// advance it and run the work right away, without waiting for the background thread. The
+    /// work not due yet is left for later and no work runs twice for the same period, no matter
+    /// if it is run by the thread or by this method.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "testing")] {
+    /// use std::time::Duration;
+    ///
+    /// use tracing_dipstick::DipstickLayer;
+    /// use tracing_dipstick::testing::{ManualClock, RecordingScope};
+    ///
+    /// let clock = ManualClock::new();
+    /// let recording = RecordingScope::new();
+    /// let bridge = DipstickLayer::builder(recording.clone())
+    ///     .clock(clock.clone())
+    ///     .watchdog(Duration::from_secs(60))
+    ///     .watchdog_counter("misconfigured")
+    ///     .finish();
+    /// bridge.tick();
+    /// assert!(recording.events().is_empty());
+    /// clock.advance(Duration::from_secs(60));
+    /// bridge.tick();
+    /// assert_eq!(vec![1], recording.values("misconfigured"));
+    /// # }
+    /// ```
+    pub fn tick(&self) {
+        self.inner.maintenance.tick(&self.inner);
+    }
+}
+
+#[cfg(feature = "dipstick")]
+impl DipstickLayer<AtomicBucket> {
+    /// Creates the bridge with the simplest setup, printing the metrics to stdout.
+    ///
+    /// The metrics are aggregated in an [`AtomicBucket`] (with all the statistics, see
+    /// [`stats_all`][dipstick::stats_all]), which is printed to stdout every 5 seconds. This is
+    /// the same as the setup in the [crate level example](crate#examples), in one call. For
+    /// anything else (eg. a different period or output), do the setup manually.
+    ///
+    /// The printing is done by a background task, stopped once the returned guard is dropped. It
+    /// therefore needs to be kept alive for as long as the metrics should be printed. Whatever was
+    /// recorded since the last print is not printed after the guard is dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use tracing_dipstick::DipstickLayer;
+    /// use tracing_subscriber::layer::SubscriberExt;
+    /// use tracing_subscriber::Registry;
+    ///
+    /// let (bridge, _flush) = DipstickLayer::to_stdout();
+    /// tracing::subscriber::with_default(Registry::default().with(bridge), || {
+    ///     tracing::info!(metrics.counter = "hits");
+    /// });
+    /// ```
+    pub fn to_stdout() -> (Self, CancelGuard<CancelHandle>) {
+        let root = AtomicBucket::new();
+        root.stats(dipstick::stats_all);
+        root.drain(Stream::write_to_stdout());
+        let flush = root.flush_every(Duration::from_secs(5)).into_guard();
+        (DipstickLayer::new(root), flush)
+    }
+}
+
+#[cfg(feature = "dipstick")]
+impl<S> DipstickLayer<Flat<S>>
+where
+    S: Clone + InputScope + Send + Sync + 'static,
+{
+    /// Creates the bridge for a scope that doesn't support prefixes.
+    ///
+    /// Unlike [`new`][DipstickLayer::new], this doesn't need the scope to implement [`Prefixed`].
+    /// On the other hand, the scope names of spans (eg. `metrics.scope`) are ignored and all the
+    /// metrics end up directly in the given scope, see [`Flat`]. Use
+    /// [`builder`][DipstickLayer::builder] with [`Flat::new`] to customize such layer.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use std::io::Result;
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// use dipstick::{Flush, InputKind, InputMetric, InputScope, MetricId, MetricName};
+    /// use tracing_dipstick::DipstickLayer;
+    /// use tracing_subscriber::layer::SubscriberExt;
+    /// use tracing_subscriber::Registry;
+    ///
+    /// /// Remembers the names of the metrics, with no support for prefixes.
+    /// #[derive(Clone, Default)]
+    /// struct Names(Arc<Mutex<Vec<String>>>);
+    ///
+    /// impl Flush for Names {
+    ///     fn flush(&self) -> Result<()> {
+    ///         Ok(())
+    ///     }
+    /// }
+    ///
+    /// impl InputScope for Names {
+    ///     fn new_metric(&self, name: MetricName, _: InputKind) -> InputMetric {
+    ///         let names = Arc::clone(&self.0);
+    ///         let full = name.join(".");
+    ///         InputMetric::new(MetricId::forge("names", name), move |_, _| {
+    ///             names.lock().unwrap().push(full.clone());
+    ///         })
+    ///     }
+    /// }
+    ///
+    /// let names = Names::default();
+    /// let subscriber = Registry::default().with(DipstickLayer::new_flat(names.clone()));
+    /// tracing::subscriber::with_default(subscriber, || {
+    ///     let _span = tracing::info_span!("db", metrics.scope = "db").entered();
+    ///     tracing::info!(metrics.counter = "queries");
+    /// });
+    /// assert_eq!(vec!["queries"], *names.0.lock().unwrap());
+    /// ```
+    ///
+    /// [`Prefixed`]: dipstick::Prefixed
+    pub fn new_flat(input_scope: S) -> Self {
+        DipstickLayer::new(Flat::new(input_scope))
+    }
+}
+
+/// A builder for the [`DipstickLayer`].
+///
+/// Created through [`DipstickLayer::builder`]. Allows tweaking the behaviour of the layer before
+/// creating it with [`finish`][DipstickLayerBuilder::finish].
+///
+/// # Examples
+///
+/// ```rust
+/// use dipstick::AtomicBucket;
+/// use tracing_dipstick::DipstickLayer;
+///
+/// let bridge = DipstickLayer::builder(AtomicBucket::new())
+///     .default_metric_name("misc")
+///     .finish();
+/// # drop(bridge);
+/// ```
+#[derive(Clone, Debug)]
+pub struct DipstickLayerBuilder<S> {
+    scope: S,
+    config: Config,
+}
+
+impl<S> DipstickLayerBuilder<S>
+where
+    S: DipstickSink,
+{
+    /// Sets the name used for bare metrics outside of spans.
+    ///
+    /// A metric attribute with an empty name (eg. `metrics.counter = ""`) is named after its span.
+    /// Outside of any span, it is ignored by default. With this set, it uses the provided name
+    /// instead. An explicit name and the name of the span always take precedence. See the [crate
+    /// level documentation](crate#recognized-attributes).
+    pub fn default_metric_name<N: Into<String>>(mut self, name: N) -> Self {
+        self.config.default_metric_name = Some(name.into());
+        self
+    }
+
+    /// Sets the value the counters are increased by in the `metrics.counter = "name"` form.
+    ///
+    /// The default is 1. This is useful eg. when each event stands for a batch of several
+    /// occurrences. It applies to the `metrics.counter.dynamic` counters too. The explicit value in
+    /// the `metrics.counter.name = value` form always overrides it. For sampled events, use
+    /// [`sample_rate`][DipstickLayerBuilder::sample_rate] instead, which scales all the counter
+    /// values (including this one).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "testing")] {
+    /// use tracing::subscriber;
+    /// use tracing_dipstick::testing::RecordingScope;
+    /// use tracing_dipstick::DipstickLayer;
+    /// use tracing_subscriber::layer::SubscriberExt;
+    /// use tracing_subscriber::Registry;
+    ///
+    /// let recording = RecordingScope::new();
+    /// let bridge = DipstickLayer::builder(recording.clone())
+    ///     .default_counter_value(10)
+    ///     .finish();
+    /// subscriber::with_default(Registry::default().with(bridge), || {
+    ///     tracing::info!(metrics.counter = "x");
+    ///     tracing::info!(metrics.counter.x = 5);
+    /// });
+    /// let values: Vec<_> = recording.events().iter().map(|e| e.value).collect();
+    /// assert_eq!(vec![10, 5], values);
+    /// # }
+    /// ```
+    pub fn default_counter_value(mut self, value: i64) -> Self {
+        self.config.default_counter_value = Some(value);
+        self
+    }
+
+    /// Joins the names of nested scopes with the given separator.
+    ///
+    /// By default, the nested scopes are [`dipstick`] prefixes and the output joins them (usually
+    /// with `.`). With this, the layer joins them itself, see [`Separated`] for details.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "testing")] {
+    /// use tracing::{info, info_span, subscriber};
+    /// use tracing_dipstick::DipstickLayer;
+    /// use tracing_dipstick::testing::RecordingScope;
+    /// use tracing_subscriber::layer::SubscriberExt;
+    /// use tracing_subscriber::Registry;
+    ///
+    /// let recording = RecordingScope::new();
+    /// let bridge = DipstickLayer::builder(recording.clone())
+    ///     .scope_separator('/')
+    ///     .finish();
+    /// subscriber::with_default(Registry::default().with(bridge), || {
+    ///     let _outer = info_span!("outer", metrics.scope = "scope").entered();
+    ///     let _inner = info_span!("inner", metrics.scope = "inner").entered();
+    ///     info!(metrics.counter = "name");
+    /// });
+    /// assert_eq!("scope/inner/name", recording.events()[0].name);
+    /// # }
+    /// ```
+    pub fn scope_separator(self, separator: char) -> DipstickLayerBuilder<Separated<S>> {
+        DipstickLayerBuilder {
+            scope: Separated::new(self.scope, separator),
+            config: self.config,
+        }
+    }
+
+    /// Undoes the counters of spans once the spans close.
+    ///
+    /// The levels (`metrics.level`) are always undone. With this turned on, the counters
+    /// incremented by spans (not events) subtract their increments again once the span closes, so
+    /// the net effect of a closed span is zero. This turns the counters into levels
+    /// from the point of view of the backend, while keeping their name and kind (eg. for backends
+    /// that treat counters specially). Only the counter itself is affected, not the derived metrics
+    /// (eg. [`DipstickLayerBuilder::counter_ratio`] or the summaries). For gauges, see
+    /// [`DipstickLayerBuilder::span_gauge_reset`]. Markers and timers record events in time that
+    /// can't be undone.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "testing")] {
+    /// use tracing::{info_span, subscriber};
+    /// use tracing_dipstick::DipstickLayer;
+    /// use tracing_dipstick::testing::RecordingScope;
+    /// use tracing_subscriber::layer::SubscriberExt;
+    /// use tracing_subscriber::Registry;
+    ///
+    /// let recording = RecordingScope::new();
+    /// let bridge = DipstickLayer::builder(recording.clone())
+    ///     .undo_on_close(true)
+    ///     .finish();
+    /// subscriber::with_default(Registry::default().with(bridge), || {
+    ///     let _span = info_span!("work", metrics.counter.in_flight = 3).entered();
+    /// });
+    /// let values = recording.values("in_flight");
+    /// assert_eq!(vec![3, -3], values);
+    /// assert_eq!(0, values.iter().sum::<isize>());
+    /// # }
+    /// ```
+    ///
+    /// An aggregating backend sees the counter net to zero as well:
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "testing")] {
+    /// use dipstick::{AtomicBucket, ScoreType};
+    /// use tracing::{info_span, subscriber};
+    /// use tracing_dipstick::DipstickLayer;
+    /// use tracing_dipstick::testing::RecordingScope;
+    /// use tracing_subscriber::layer::SubscriberExt;
+    /// use tracing_subscriber::Registry;
+    ///
+    /// let bucket = AtomicBucket::new();
+    /// bucket.stats(|kind, name, score| match score {
+    ///     ScoreType::Sum(sum) => Some((kind, name, sum)),
+    ///     _ => None,
+    /// });
+    /// let bridge = DipstickLayer::builder(bucket.clone())
+    ///     .undo_on_close(true)
+    ///     .finish();
+    /// subscriber::with_default(Registry::default().with(bridge), || {
+    ///     let _first = info_span!("work", metrics.counter.in_flight = 3).entered();
+    ///     let _second = info_span!("work", metrics.counter.in_flight = 2).entered();
+    /// });
+    /// let published = RecordingScope::new();
+    /// bucket.flush_to(&published).unwrap();
+    /// assert_eq!(vec![0], published.values("in_flight"));
+    /// # }
+    /// ```
+    pub fn undo_on_close(mut self, enabled: bool) -> Self {
+        self.config.undo_counters = enabled;
+        self
+    }
+
+    /// Sets the gauges set by spans to the reset value (eg. 0) once the spans close.
+    ///
+    /// By default, a gauge set by a span (through `metrics.gauge`) keeps the value after the span
+    /// closes. This is misleading for readings like "currently active". As [`dipstick`] doesn't
+    /// provide the previous value of the gauge, it can't be restored; the gauge is set to the
+    /// given reset value instead. This applies only to gauges on spans, not on events, and not to
+    /// `metrics.gauge.delta` (use `metrics.level` for counting the active spans).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "testing")] {
+    /// use tracing::{info_span, subscriber};
+    /// use tracing_dipstick::DipstickLayer;
+    /// use tracing_dipstick::testing::RecordingScope;
+    /// use tracing_subscriber::layer::SubscriberExt;
+    /// use tracing_subscriber::Registry;
+    ///
+    /// let recording = RecordingScope::new();
+    /// let bridge = DipstickLayer::builder(recording.clone())
+    ///     .span_gauge_reset(0)
+    ///     .finish();
+    /// subscriber::with_default(Registry::default().with(bridge), || {
+    ///     let _span = info_span!("work", metrics.gauge.active = 5).entered();
+    /// });
+    /// let values: Vec<_> = recording.events().into_iter().map(|e| e.value).collect();
+    /// assert_eq!(vec![5, 0], values);
+    /// # }
+    /// ```
+    pub fn span_gauge_reset(mut self, reset: i64) -> Self {
+        self.config.span_gauge_reset = Some(reset);
+        self
+    }
+
+    /// Warns about unrecognized `metrics.*` fields, which are likely typos.
+    ///
+    /// See the [crate level documentation](crate#recognized-attributes).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use std::sync::Arc;
+    ///
+    /// use dipstick::AtomicBucket;
+    /// use tracing::{Event, Level, Subscriber};
+    /// use tracing_dipstick::DipstickLayer;
+    /// use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+    /// use tracing_subscriber::Registry;
+    ///
+    /// struct CountWarnings(Arc<AtomicUsize>);
+    ///
+    /// impl<S: Subscriber> Layer<S> for CountWarnings {
+    ///     fn on_event(&self, event: &Event, _: Context<S>) {
+    ///         if *event.metadata().level() == Level::WARN {
+    ///             self.0.fetch_add(1, Ordering::Relaxed);
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// let warnings = Arc::new(AtomicUsize::new(0));
+    /// let bridge = DipstickLayer::builder(AtomicBucket::new())
+    ///     .warn_unknown(true)
+    ///     .finish();
+    /// let subscriber = Registry::default()
+    ///     .with(bridge)
+    ///     .with(CountWarnings(Arc::clone(&warnings)));
+    /// tracing::subscriber::set_global_default(subscriber).unwrap();
+    ///
+    /// for _ in 0..10 {
+    ///     tracing::info!(metrics.gage = "x");
+    /// }
+    /// // Only once for the same field
+    /// assert_eq!(1, warnings.load(Ordering::Relaxed));
+    /// ```
+    pub fn warn_unknown(mut self, enabled: bool) -> Self {
+        self.config.warn_unknown = enabled;
+        self
+    }
+
+    /// Counts every event under a name derived from its callsite.
+    ///
+    /// The counters are named `prefix.callsite`, see the [crate level
+    /// documentation](crate#counting-log-messages) for details. They are placed into the scope of
+    /// the current span, like other metrics of the event.
+    pub fn message_counters<N: Into<String>>(mut self, prefix: N) -> Self {
+        self.config.message_counters = Some(prefix.into());
+        self
+    }
+
+    /// Sets the clock the timers measure the time by.
+    ///
+    /// See [`Clock`] for details.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "testing")] {
+    /// use std::sync::{Arc, Mutex};
+    /// use std::time::{Duration, Instant};
+    ///
+    /// use tracing::subscriber;
+    /// use tracing_dipstick::{Clock, DipstickLayer};
+    /// use tracing_dipstick::testing::RecordingScope;
+    /// use tracing_subscriber::layer::SubscriberExt;
+    /// use tracing_subscriber::Registry;
+    ///
+    /// /// A clock that moves only when told to.
+    /// #[derive(Clone)]
+    /// struct MockClock(Arc<Mutex<Instant>>);
+    ///
+    /// impl MockClock {
+    ///     fn advance(&self, by: Duration) {
+    ///         *self.0.lock().unwrap() += by;
+    ///     }
+    /// }
+    ///
+    /// impl Clock for MockClock {
+    ///     fn now(&self) -> Instant {
+    ///         *self.0.lock().unwrap()
+    ///     }
+    /// }
+    ///
+    /// let clock = MockClock(Arc::new(Mutex::new(Instant::now())));
+    /// let recording = RecordingScope::new();
+    /// let bridge = DipstickLayer::builder(recording.clone())
+    ///     .clock(clock.clone())
+    ///     .finish();
+    /// subscriber::with_default(Registry::default().with(bridge), || {
+    ///     let span = tracing::info_span!("work", metrics.timer = "work");
+    ///     clock.advance(Duration::from_millis(5));
+    ///     drop(span);
+    /// });
+    /// // In microseconds
+    /// assert_eq!(vec![5000], recording.values("work"));
+    /// # }
+    /// ```
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.config.clock = SharedClock(Some(Arc::new(clock)));
+        self
+    }
+
+    /// Defines the metrics up front, when the layer is created.
+    ///
+    /// Usually, a metric comes into existence only when it is recorded for the first time. Some
+    /// exporters (eg. Prometheus) work better when the metrics exist from the start. The listed
+    /// metrics are defined in the root scope (the names may still contain dots, eg.
+    /// `requests.failed`) once the layer is created, and again whenever the root scope is
+    /// [replaced][DipstickLayer::set_scope].
+    ///
+    /// Whether a defined metric shows up before it is recorded depends on the backend. Many
+    /// aggregating backends (including [`AtomicBucket`]) report only the metrics with some values.
+    /// Therefore, counters and levels are recorded with a zero (which doesn't change their totals),
+    /// so they get reported at least once. Gauges, markers and timers are only defined, as any
+    /// value would be a fake one.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "testing")] {
+    /// use tracing_dipstick::{DipstickLayer, MetricKind};
+    /// use tracing_dipstick::testing::RecordingScope;
+    ///
+    /// let recording = RecordingScope::new();
+    /// let _bridge = DipstickLayer::builder(recording.clone())
+    ///     .pre_register([
+    ///         ("requests", MetricKind::Counter),
+    ///         ("requests.active", MetricKind::Level),
+    ///         ("queue", MetricKind::Gauge),
+    ///     ])
+    ///     .finish();
+    /// // Nothing recorded yet, but the counter and level exist
+    /// assert_eq!(vec![0], recording.values("requests"));
+    /// assert_eq!(vec![0], recording.values("requests.active"));
+    /// assert!(recording.values("queue").is_empty());
+    /// # }
+    /// ```
+    pub fn pre_register<I, N>(mut self, metrics: I) -> Self
+    where
+        I: IntoIterator<Item = (N, MetricKind)>,
+        N: Into<String>,
+    {
+        self.config
+            .pre_registered
+            .extend(metrics.into_iter().map(|(name, kind)| (name.into(), kind)));
+        self
+    }
+
+    /// Adds the names of the spans to the scopes of their metrics.
+    ///
+    /// The name of the span (the first argument of [`info_span!`][tracing::info_span] and
+    /// similar) becomes a segment of the scope, just before the `metrics.scope` of the span (if
+    /// any). Nested spans accumulate their names, so the metrics inside don't need to repeat them
+    /// in `metrics.scope`. Spans of the same name share the prefix, therefore their metrics are
+    /// aggregated together. The name is used as it is, it is not [split on
+    /// dots](crate#dotted-scopes).
+    ///
+    /// Only the spans with some `metrics.*` attributes are affected, the others are transparent
+    /// for the metrics (as usual). A `metrics.scope.full` replaces the whole scope, including the
+    /// span names (even the span's own one).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "testing")] {
+    /// use tracing::{info, info_span, subscriber};
+    /// use tracing_dipstick::DipstickLayer;
+    /// use tracing_dipstick::testing::RecordingScope;
+    /// use tracing_subscriber::layer::SubscriberExt;
+    /// use tracing_subscriber::Registry;
+    ///
+    /// let recording = RecordingScope::new();
+    /// let bridge = DipstickLayer::builder(recording.clone())
+    ///     .use_span_name_prefix(true)
+    ///     .finish();
+    /// subscriber::with_default(Registry::default().with(bridge), || {
+    ///     let _yaks = info_span!("shaving", metrics.counter = "started").entered();
+    ///     for _ in 0..2 {
+    ///         let _yak = info_span!("yak", metrics.scope = "big").entered();
+    ///         // Not touching metrics, so not part of the names
+    ///         let _razor = info_span!("razor").entered();
+    ///         info!(metrics.counter = "shaved");
+    ///     }
+    /// });
+    /// let names: Vec<_> = recording.events().into_iter().map(|e| e.name).collect();
+    /// let expected = ["shaving.started", "shaving.yak.big.shaved", "shaving.yak.big.shaved"];
+    /// assert_eq!(expected.as_slice(), names);
+    /// # }
+    /// ```
+    pub fn use_span_name_prefix(mut self, enabled: bool) -> Self {
+        self.config.span_name_prefix = enabled;
+        self
+    }
+
+    /// Appends the lowercased level of the span or event to the names of its metrics.
+    ///
+    /// See the [crate level documentation](crate#separating-metrics-by-severity).
+    pub fn append_level_suffix(mut self, enabled: bool) -> Self {
+        self.config.level_suffix = enabled;
+        self
+    }
+
+    /// Appends the name of the current thread to the names of the metrics.
+    ///
+    /// See the [crate level documentation](crate#per-thread-metrics).
+    pub fn append_thread_name(mut self, enabled: bool) -> Self {
+        self.config.thread_suffix = enabled;
+        self
+    }
+
+    /// Fires a marker of this name for every event at the `ERROR` level.
+    ///
+    /// This happens even for events without any `metrics.*` attributes. See the [crate level
+    /// documentation](crate#counting-errors).
+    pub fn auto_error_marker<N: Into<String>>(mut self, name: N) -> Self {
+        self.config.error_marker = Some(name.into());
+        self
+    }
+
+    /// Records the times of the first and last occurrences of the metrics.
+    ///
+    /// At most `limit` distinct metrics are tracked, see the [crate level
+    /// documentation](crate#freshness-of-metrics) for details. Setting it to 0 (the default) turns
+    /// the feature off.
+    pub fn seen_gauges(mut self, limit: usize) -> Self {
+        self.config.seen_gauges = limit;
+        self
+    }
+
+    /// Places metrics of events at this level (or more severe) into a scope named by the level.
+    ///
+    /// See the [crate level documentation](crate#separating-metrics-by-severity).
+    pub fn level_scope(mut self, level: TracingLevel) -> Self {
+        self.config.level_scope = Some(level);
+        self
+    }
+
+    /// Warns if no metric gets recorded within the given time after creating the layer.
+    ///
+    /// See the [crate level documentation](crate#detecting-misconfiguration).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "testing")] {
+    /// use std::time::Duration;
+    ///
+    /// use tracing_dipstick::DipstickLayer;
+    /// use tracing_dipstick::testing::{ManualClock, RecordingScope};
+    ///
+    /// let clock = ManualClock::new();
+    /// let recording = RecordingScope::new();
+    /// let bridge = DipstickLayer::builder(recording.clone())
+    ///     .clock(clock.clone())
+    ///     .watchdog(Duration::from_secs(60))
+    ///     .watchdog_counter("misconfigured")
+    ///     .finish();
+    /// bridge.recorder().counter("requests", 1);
+    /// clock.advance(Duration::from_secs(60));
+    /// bridge.tick();
+    /// // Some metric was recorded, all is fine
+    /// assert!(recording.values("misconfigured").is_empty());
+    /// # }
+    /// ```
+    pub fn watchdog(mut self, window: Duration) -> Self {
+        self.config.watchdog = Some(window);
+        self
+    }
+
+    /// Bumps a counter of this name (in the root scope) when the [watchdog] fires.
+    ///
+    /// This has effect only if the [watchdog] is enabled.
+    ///
+    /// [watchdog]: DipstickLayerBuilder::watchdog
+    pub fn watchdog_counter<N: Into<String>>(mut self, name: N) -> Self {
+        self.config.watchdog_counter = Some(name.into());
+        self
+    }
+
+    /// Counts all the metric operations the layer performs, in a counter of its own.
+    ///
+    /// See the [crate level documentation](crate#self-monitoring).
+    pub fn self_monitoring(mut self, enabled: bool) -> Self {
+        self.config.self_monitoring = enabled;
+        self
+    }
+
+    /// Names the counter of [self monitoring].
+    ///
+    /// The default is `tracing_dipstick.emitted`. This has effect only if the [self monitoring] is
+    /// enabled.
+    ///
+    /// [self monitoring]: DipstickLayerBuilder::self_monitoring
+    pub fn self_monitoring_counter<N: Into<String>>(mut self, name: N) -> Self {
+        self.config.self_monitoring_counter = Some(name.into());
+        self
+    }
+
+    /// Records the counters and timers of only a random fraction of the spans and events.
+    ///
+    /// The rate is the probability of recording, eg. `0.1` records them for about every tenth span
+    /// or event. Other kinds of metrics are always recorded. See the [crate level
+    /// documentation](crate#sampling) for details.
+    ///
+    /// # Panics
+    ///
+    /// If the rate is not in the `(0, 1]` range.
+    pub fn sample_rate(mut self, rate: f64) -> Self {
+        assert!(
+            rate > 0.0 && rate <= 1.0,
+            "Sample rate {} out of range",
+            rate
+        );
+        self.config.sample_rate = Some(rate).filter(|&rate| rate < 1.0);
+        self
+    }
+
+    /// Sends each counter at most once per the interval, summing the increments in between.
+    ///
+    /// See the [crate level documentation](crate#limiting-the-rate-of-counters).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "testing")] {
+    /// use std::time::Duration;
+    ///
+    /// use tracing_dipstick::DipstickLayer;
+    /// use tracing_dipstick::testing::{ManualClock, RecordingScope};
+    ///
+    /// let clock = ManualClock::new();
+    /// let recording = RecordingScope::new();
+    /// let bridge = DipstickLayer::builder(recording.clone())
+    ///     .clock(clock.clone())
+    ///     .counter_rate_limit(Duration::from_secs(1))
+    ///     .finish();
+    /// let recorder = bridge.recorder();
+    /// for _ in 0..1000 {
+    ///     recorder.counter("hits", 1);
+    /// }
+    /// // The first one goes right away, the rest waits for the end of the interval
+    /// assert_eq!(vec![1], recording.values("hits"));
+    /// clock.advance(Duration::from_secs(1));
+    /// bridge.tick();
+    /// assert_eq!(vec![1, 999], recording.values("hits"));
+    /// # }
+    /// ```
+    pub fn counter_rate_limit(mut self, interval: Duration) -> Self {
+        self.config.counter_rate_limit = Some(interval);
+        self
+    }
+
+    /// Records the sums of counter increments within windows of the given length as timers.
+    ///
+    /// See the [crate level documentation](crate#detecting-bursts).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "testing")] {
+    /// use std::time::Duration;
+    ///
+    /// use tracing_dipstick::DipstickLayer;
+    /// use tracing_dipstick::testing::{ManualClock, RecordingScope};
+    ///
+    /// let clock = ManualClock::new();
+    /// let recording = RecordingScope::new();
+    /// let bridge = DipstickLayer::builder(recording.clone())
+    ///     .clock(clock.clone())
+    ///     .counter_bursts(Duration::from_secs(1))
+    ///     .finish();
+    /// let recorder = bridge.recorder();
+    /// // A steady one and a bursty one
+    /// for window in 0..4 {
+    ///     recorder.counter("steady", 5);
+    ///     if window == 2 {
+    ///         recorder.counter("bursty", 20);
+    ///     }
+    ///     clock.advance(Duration::from_secs(1));
+    ///     bridge.tick();
+    /// }
+    /// // Recorded as milliseconds, the timers are in microseconds
+    /// assert_eq!(vec![5000; 4], recording.values("steady.window"));
+    /// // Not tracked until first bumped
+    /// assert_eq!(vec![20_000, 0], recording.values("bursty.window"));
+    /// # }
+    /// ```
+    pub fn counter_bursts(mut self, window: Duration) -> Self {
+        self.config.counter_bursts = Some(window);
+        self
+    }
+
+    /// Records the span fields with these names as gauges.
+    ///
+    /// See the [crate level documentation](crate#gauges-from-span-fields).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "testing")] {
+    /// use tracing::{info_span, subscriber};
+    /// use tracing_dipstick::DipstickLayer;
+    /// use tracing_dipstick::testing::RecordingScope;
+    /// use tracing_subscriber::layer::SubscriberExt;
+    /// use tracing_subscriber::Registry;
+    ///
+    /// let recording = RecordingScope::new();
+    /// let bridge = DipstickLayer::builder(recording.clone())
+    ///     .field_gauges(["cnt"])
+    ///     .finish();
+    /// subscriber::with_default(Registry::default().with(bridge), || {
+    ///     let _span = info_span!("Shaving yaks", cnt = 10, metrics.scope = "shaving").entered();
+    /// });
+    /// let events = recording.events();
+    /// assert_eq!(1, events.len());
+    /// assert_eq!("shaving.cnt", events[0].name);
+    /// assert_eq!(10, events[0].value);
+    /// # }
+    /// ```
+    pub fn field_gauges<F, N>(mut self, fields: F) -> Self
+    where
+        F: IntoIterator<Item = N>,
+        N: Into<String>,
+    {
+        self.config
+            .field_gauges
+            .extend(fields.into_iter().map(Into::into));
+        self
+    }
+
+    /// Sets a gauge to the ratio of two counters, multiplied by the scale.
+    ///
+    /// Can be called multiple times to track multiple ratios. See the [crate level
+    /// documentation](crate#ratios-of-counters).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "testing")] {
+    /// use std::time::Duration;
+    ///
+    /// use tracing_dipstick::DipstickLayer;
+    /// use tracing_dipstick::testing::{ManualClock, RecordingScope};
+    ///
+    /// let clock = ManualClock::new();
+    /// let recording = RecordingScope::new();
+    /// let bridge = DipstickLayer::builder(recording.clone())
+    ///     .clock(clock.clone())
+    ///     .counter_ratio("api.errors", "api.requests", "api.error_permille", 1000)
+    ///     .ratio_window(Duration::from_secs(10))
+    ///     .finish();
+    /// let api = bridge.recorder().scope("api");
+    /// api.counter("requests", 4);
+    /// api.counter("errors", 1);
+    /// clock.advance(Duration::from_secs(10));
+    /// bridge.tick();
+    /// // Nothing in this window, the ratio is left alone
+    /// clock.advance(Duration::from_secs(10));
+    /// bridge.tick();
+    /// assert_eq!(vec![250], recording.values("api.error_permille"));
+    /// # }
+    /// ```
+    pub fn counter_ratio<N, D, G>(
+        mut self,
+        numerator: N,
+        denominator: D,
+        gauge: G,
+        scale: i64,
+    ) -> Self
+    where
+        N: Into<String>,
+        D: Into<String>,
+        G: Into<String>,
+    {
+        self.config.ratios.push(Ratio {
+            numerator: numerator.into(),
+            denominator: denominator.into(),
+            gauge: gauge.into(),
+            scale,
+        });
+        self
+    }
+
+    /// Sets the length of the windows the [ratios][DipstickLayerBuilder::counter_ratio] are
+    /// computed over.
+    ///
+    /// The default is one second.
+    pub fn ratio_window(mut self, window: Duration) -> Self {
+        self.config.ratio_window = Some(window);
+        self
+    }
+
+    /// Sets the gauges not updated within the timeout to the reset value.
+    ///
+    /// See the [crate level documentation](crate#stale-gauges).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "testing")] {
+    /// use std::time::Duration;
+    ///
+    /// use tracing_dipstick::DipstickLayer;
+    /// use tracing_dipstick::testing::{ManualClock, RecordingScope};
+    ///
+    /// let clock = ManualClock::new();
+    /// let recording = RecordingScope::new();
+    /// let bridge = DipstickLayer::builder(recording.clone())
+    ///     .clock(clock.clone())
+    ///     .stale_gauges(Duration::from_secs(10), 0)
+    ///     .finish();
+    /// bridge.recorder().gauge("queue_size", 42);
+    /// clock.advance(Duration::from_secs(5));
+    /// bridge.tick();
+    /// // Still fresh
+    /// assert_eq!(vec![42], recording.values("queue_size"));
+    /// clock.advance(Duration::from_secs(5));
+    /// bridge.tick();
+    /// assert_eq!(vec![42, 0], recording.values("queue_size"));
+    /// # }
+    /// ```
+    pub fn stale_gauges(mut self, timeout: Duration, reset: i64) -> Self {
+        self.config.stale_gauges = Some((timeout, reset));
+        self
+    }
+
+    /// Flushes the root scope when the program panics.
+    ///
+    /// See the [crate level documentation](crate#flushing-on-panic).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "testing")] {
+    /// use std::panic;
+    ///
+    /// use tracing_dipstick::DipstickLayer;
+    /// use tracing_dipstick::testing::RecordingScope;
+    ///
+    /// let recording = RecordingScope::new();
+    /// let _bridge = DipstickLayer::builder(recording.clone())
+    ///     .flush_on_panic(true)
+    ///     .finish();
+    /// let other = RecordingScope::new();
+    /// let _other_bridge = DipstickLayer::builder(other.clone())
+    ///     .flush_on_panic(true)
+    ///     .finish();
+    /// let gone = RecordingScope::new();
+    /// drop(DipstickLayer::builder(gone.clone()).flush_on_panic(true).finish());
+    /// assert!(panic::catch_unwind(|| panic!("Oops")).is_err());
+    /// // Each living one exactly once
+    /// assert_eq!(1, recording.flush_count());
+    /// assert_eq!(1, other.flush_count());
+    /// assert_eq!(0, gone.flush_count());
+    /// # }
+    /// ```
+    pub fn flush_on_panic(mut self, enabled: bool) -> Self {
+        self.config.flush_on_panic = enabled;
+        self
+    }
+
+    /// Flushes the scope of a span once it closes.
+    ///
+    /// This applies to the spans naming their own scope (through `metrics.scope`,
+    /// `metrics.scope.full` or `metrics.scope.field`). Their final metrics (eg. timers) are
+    /// recorded first, so they become visible right away instead of waiting for the next periodic
+    /// flush. This is meant for long-running spans; flushing is not free (with most outputs, it
+    /// means sending all the metrics buffered in the whole bucket), so it is better avoided on
+    /// frequent spans.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "testing")] {
+    /// use tracing::subscriber;
+    /// use tracing_dipstick::DipstickLayer;
+    /// use tracing_dipstick::testing::RecordingScope;
+    /// use tracing_subscriber::layer::SubscriberExt;
+    /// use tracing_subscriber::Registry;
+    ///
+    /// let recording = RecordingScope::new();
+    /// let bridge = DipstickLayer::builder(recording.clone())
+    ///     .flush_on_scope_close(true)
+    ///     .finish();
+    /// subscriber::with_default(Registry::default().with(bridge), || {
+    ///     let job = tracing::info_span!("job", metrics.scope = "job", metrics.timer = "time");
+    ///     drop(tracing::info_span!("step", metrics.counter = "steps"));
+    ///     assert_eq!(0, recording.flush_count());
+    ///     drop(job);
+    ///     assert_eq!(1, recording.flush_count());
+    /// });
+    /// # }
+    /// ```
+    pub fn flush_on_scope_close(mut self, enabled: bool) -> Self {
+        self.config.flush_on_scope_close = enabled;
+        self
+    }
+
+    /// Accumulates the counter increments in per-thread shards, merged once per the interval.
+    ///
+    /// See the [crate level documentation](crate#sharded-counters).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "testing")] {
+    /// use std::time::Duration;
+    ///
+    /// use tracing_dipstick::DipstickLayer;
+    /// use tracing_dipstick::testing::{ManualClock, RecordingScope};
+    ///
+    /// let clock = ManualClock::new();
+    /// let recording = RecordingScope::new();
+    /// let bridge = DipstickLayer::builder(recording.clone())
+    ///     .clock(clock.clone())
+    ///     .sharded_counters(Duration::from_secs(1))
+    ///     .finish();
+    /// let recorder = bridge.recorder();
+    /// for _ in 0..3 {
+    ///     recorder.counter("hits", 1);
+    /// }
+    /// // Not merged yet
+    /// assert!(recording.events().is_empty());
+    /// clock.advance(Duration::from_secs(1));
+    /// bridge.tick();
+    /// assert_eq!(vec![3], recording.values("hits"));
+    /// recorder.counter("hits", 2);
+    /// // The leftovers are merged once the layer is gone
+    /// drop((bridge, recorder));
+    /// assert_eq!(vec![3, 2], recording.values("hits"));
+    /// # }
+    /// ```
+    pub fn sharded_counters(mut self, interval: Duration) -> Self {
+        self.config.sharded_counters = Some(interval);
+        self
+    }
+
+    /// Records the metrics of events inside spans only once the spans close.
+    ///
+    /// See the [crate level documentation](crate#deferring-metrics-to-span-close).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "testing")] {
+    /// use tracing::{info, info_span, subscriber};
+    /// use tracing_dipstick::DipstickLayer;
+    /// use tracing_dipstick::testing::RecordingScope;
+    /// use tracing_subscriber::layer::SubscriberExt;
+    /// use tracing_subscriber::Registry;
+    ///
+    /// let recording = RecordingScope::new();
+    /// let bridge = DipstickLayer::builder(recording.clone())
+    ///     .defer_to_close(true)
+    ///     .finish();
+    /// subscriber::with_default(Registry::default().with(bridge), || {
+    ///     let span = info_span!("request", metrics.scope = "request").entered();
+    ///     info!(metrics.counter = "hits");
+    ///     info!(metrics.gauge.size = 42);
+    ///     assert!(recording.events().is_empty());
+    ///     drop(span);
+    /// });
+    /// let names: Vec<_> = recording.events().into_iter().map(|e| e.name).collect();
+    /// assert_eq!(vec!["request.hits", "request.size"], names);
+    /// # }
+    /// ```
+    pub fn defer_to_close(mut self, enabled: bool) -> Self {
+        self.config.defer_to_close = enabled;
+        self
+    }
+
+    /// Sends the explicit timer values of events inside spans in batches of this size.
+    ///
+    /// See the [crate level documentation](crate#batching-timer-values). Setting it to 0 or 1 (the
+    /// default) sends each value right away.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "testing")] {
+    /// use tracing::{info, info_span, subscriber};
+    /// use tracing_dipstick::DipstickLayer;
+    /// use tracing_dipstick::testing::RecordingScope;
+    /// use tracing_subscriber::layer::SubscriberExt;
+    /// use tracing_subscriber::Registry;
+    ///
+    /// let recording = RecordingScope::new();
+    /// let bridge = DipstickLayer::builder(recording.clone())
+    ///     .timer_batch(2)
+    ///     .finish();
+    /// subscriber::with_default(Registry::default().with(bridge), || {
+    ///     let span = info_span!("request", metrics.scope = "request").entered();
+    ///     info!(metrics.distribution.size = 10);
+    ///     // Waiting for the second one
+    ///     assert!(recording.values("request.size").is_empty());
+    ///     info!(metrics.distribution.size = 20);
+    ///     assert_eq!(vec![10, 20], recording.values("request.size"));
+    ///     info!(metrics.distribution.size = 30);
+    ///     // Events outside of spans are not batched
+    ///     drop(span);
+    ///     info!(metrics.distribution.size = 40);
+    /// });
+    /// // The rest is sent once the span closes
+    /// assert_eq!(vec![10, 20, 30], recording.values("request.size"));
+    /// assert_eq!(vec![40], recording.values("size"));
+    /// # }
+    /// ```
+    pub fn timer_batch(mut self, size: usize) -> Self {
+        self.config.timer_batch = size;
+        self
+    }
+
+    /// Attaches a sequence number to the timed spans.
+    ///
+    /// See the [crate level documentation](crate#ordering-of-timers).
+    pub fn timer_sequence(mut self, enabled: bool) -> Self {
+        self.config.timer_sequence = enabled;
+        self
+    }
+
+    /// Keeps account of the levels held by the open spans, for [`DipstickLayer::open_levels`].
+    ///
+    /// Off by default, as it costs a lookup of the full name of the level under a lock shared by
+    /// all the threads each time a span adjusts a level.
+    pub fn track_open_levels(mut self, enabled: bool) -> Self {
+        self.config.track_open_levels = enabled;
+        self
+    }
+
+    /// Sets the unit the timers are recorded in.
+    ///
+    /// The default is [`TimerUnit::Microseconds`]. See [`TimerUnit`] for details.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "testing")] {
+    /// use std::thread;
+    /// use std::time::Duration;
+    ///
+    /// use tracing::subscriber;
+    /// use tracing_dipstick::testing::RecordingScope;
+    /// use tracing_dipstick::{DipstickLayer, TimerUnit};
+    /// use tracing_subscriber::layer::SubscriberExt;
+    /// use tracing_subscriber::Registry;
+    ///
+    /// let recording = RecordingScope::new();
+    /// let bridge = DipstickLayer::builder(recording.clone())
+    ///     .timer_unit(TimerUnit::Milliseconds)
+    ///     .finish();
+    /// subscriber::with_default(Registry::default().with(bridge), || {
+    ///     let span = tracing::info_span!("request", metrics.timer = "time");
+    ///     thread::sleep(Duration::from_millis(20));
+    ///     drop(span);
+    ///     tracing::info!(metrics.timer.lookup = 3_500_000);
+    /// });
+    /// let time = recording.values("time")[0];
+    /// assert!((20..1000).contains(&time), "{}", time);
+    /// assert_eq!(vec![3], recording.values("lookup"));
+    /// # }
+    /// ```
+    pub fn timer_unit(mut self, unit: TimerUnit) -> Self {
+        self.config.timer_unit = unit;
+        self
+    }
+
+    /// Sets how to handle floating point metric values that are not finite.
+    ///
+    /// The default is [`NonFinite::Skip`].
+    pub fn non_finite(mut self, policy: NonFinite) -> Self {
+        self.config.non_finite = policy;
+        self
+    }
+
+    /// Parses the string values of the `metrics.type.name = value` form as numbers.
+    ///
+    /// Some instrumentation records everything as strings. By default, such values (eg.
+    /// `metrics.gauge.size = "42"`) are ignored. With this turned on, they are parsed as integers
+    /// or floating point numbers (which are then handled like any other floating point value). If
+    /// the value is not a number, it is still ignored and a warning is emitted through [`tracing`]
+    /// (once for each such field).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "testing")] {
+    /// use tracing::subscriber;
+    /// use tracing_dipstick::testing::RecordingScope;
+    /// use tracing_dipstick::DipstickLayer;
+    /// use tracing_subscriber::layer::SubscriberExt;
+    /// use tracing_subscriber::Registry;
+    ///
+    /// let recording = RecordingScope::new();
+    /// let bridge = DipstickLayer::builder(recording.clone())
+    ///     .parse_string_values(true)
+    ///     .finish();
+    /// subscriber::with_default(Registry::default().with(bridge), || {
+    ///     tracing::info!(metrics.gauge.size = "42");
+    ///     tracing::info!(metrics.gauge.size = "2.6");
+    ///     tracing::info!(metrics.gauge.size = "many");
+    /// });
+    /// assert_eq!(vec![42, 3], recording.values("size"));
+    /// # }
+    /// ```
+    pub fn parse_string_values(mut self, enabled: bool) -> Self {
+        self.config.parse_string_values = enabled;
+        self
+    }
+
+    /// Lets the spans marked with `metrics.scope.dynamic = true` follow the span they are entered
+    /// in.
+    ///
+    /// See the [crate level documentation](crate#following-the-dynamic-parent), including the
+    /// costs.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "testing")] {
+    /// use tracing::{info, info_span, subscriber};
+    /// use tracing_dipstick::DipstickLayer;
+    /// use tracing_dipstick::testing::RecordingScope;
+    /// use tracing_subscriber::layer::SubscriberExt;
+    /// use tracing_subscriber::Registry;
+    ///
+    /// let recording = RecordingScope::new();
+    /// let bridge = DipstickLayer::builder(recording.clone())
+    ///     .dynamic_scopes(true)
+    ///     .finish();
+    /// subscriber::with_default(Registry::default().with(bridge), || {
+    ///     // Created outside of anything, like a future spawned from the top level
+    ///     let task = info_span!("task", metrics.scope = "task", metrics.scope.dynamic = true);
+    ///     task.in_scope(|| info!(metrics.counter = "polled"));
+    ///     // Now polled from within another span
+    ///     info_span!("worker", metrics.scope = "worker").in_scope(|| {
+    ///         task.in_scope(|| info!(metrics.counter = "polled"));
+    ///     });
+    /// });
+    /// let names: Vec<_> = recording.events().into_iter().map(|e| e.name).collect();
+    /// assert_eq!(vec!["task.polled", "worker.task.polled"], names);
+    /// # }
+    /// ```
+    pub fn dynamic_scopes(mut self, enabled: bool) -> Self {
+        self.config.dynamic_scopes = enabled;
+        self
+    }
+
+    /// Sets the hook receiving the [`Summary`] of spans marked with `metrics.summary = true`.
+    ///
+    /// The hook is called once the marked span and all its descendants are closed. Without the
+    /// hook, the summaries are not collected at all.
+    pub fn on_summary<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&Summary) + Send + Sync + 'static,
+    {
+        self.config.summary_hook = Some(SummaryHook(Arc::new(hook)));
+        self
+    }
+
+    /// Sets the hook receiving the panics caught while recording the metrics.
+    ///
+    /// See the [crate level documentation](crate#panics-while-recording). The hook gets the
+    /// message of each caught panic. Without the hook, a warning is emitted through
+    /// [`tracing`](https://docs.rs/tracing) for the first one.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "testing")] {
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use std::sync::Arc;
+    ///
+    /// use dipstick::{Counter, Gauge, Level, Marker, NameParts, Timer};
+    /// use tracing::subscriber;
+    /// use tracing_dipstick::testing::RecordingScope;
+    /// use tracing_dipstick::{DipstickLayer, DipstickSink};
+    /// use tracing_subscriber::layer::SubscriberExt;
+    /// use tracing_subscriber::Registry;
+    ///
+    /// /// A scope that can't handle one of the metrics.
+    /// #[derive(Clone)]
+    /// struct Fragile(RecordingScope);
+    ///
+    /// impl DipstickSink for Fragile {
+    ///     fn counter(&self, name: &str) -> Counter {
+    ///         assert_ne!("boom", name, "Can't count booms");
+    ///         DipstickSink::counter(&self.0, name)
+    ///     }
+    ///     fn marker(&self, name: &str) -> Marker {
+    ///         DipstickSink::marker(&self.0, name)
+    ///     }
+    ///     fn gauge(&self, name: &str) -> Gauge {
+    ///         DipstickSink::gauge(&self.0, name)
+    ///     }
+    ///     fn level(&self, name: &str) -> Level {
+    ///         DipstickSink::level(&self.0, name)
+    ///     }
+    ///     fn timer(&self, name: &str) -> Timer {
+    ///         DipstickSink::timer(&self.0, name)
+    ///     }
+    ///     fn add_name(&self, name: &str) -> Self {
+    ///         Fragile(DipstickSink::add_name(&self.0, name))
+    ///     }
+    ///     fn named(&self, name: &str) -> Self {
+    ///         Fragile(DipstickSink::named(&self.0, name))
+    ///     }
+    ///     fn prefixes(&self) -> &NameParts {
+    ///         DipstickSink::prefixes(&self.0)
+    ///     }
+    /// }
+    ///
+    /// let recording = RecordingScope::new();
+    /// let errors = Arc::new(AtomicUsize::new(0));
+    /// let errors_cp = Arc::clone(&errors);
+    /// let bridge = DipstickLayer::builder(Fragile(recording.clone()))
+    ///     .on_error(move |_| {
+    ///         errors_cp.fetch_add(1, Ordering::Relaxed);
+    ///     })
+    ///     .finish();
+    /// subscriber::with_default(Registry::default().with(bridge), || {
+    ///     tracing::info!(metrics.counter = "boom");
+    ///     // Still alive
+    ///     tracing::info!(metrics.counter = "hits");
+    /// });
+    /// assert_eq!(1, errors.load(Ordering::Relaxed));
+    /// assert_eq!(vec![1], recording.values("hits"));
+    /// # }
+    /// ```
+    pub fn on_error<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        self.config.error_hook = Some(ErrorHook(Arc::new(hook)));
+        self
+    }
+
+    /// Creates the configured bridge.
+    pub fn finish(self) -> DipstickLayer<S> {
+        let shards = match self.config.sharded_counters {
+            Some(_) => {
+                let cpus = thread::available_parallelism().map_or(1, |cpus| cpus.get());
+                (0..cpus * 2).map(|_| Mutex::default()).collect()
+            }
+            None => Vec::new(),
+        };
+        let inner = Arc::new(Inner {
+            config: self.config,
+            counter_shards: CounterShards(shards),
+            ..Inner::default()
+        });
+        inner.config.pre_register(&self.scope);
+        inner.monitor_root(&self.scope);
+        let root = SharedRoot::new(self.scope);
+        let start = inner.config.clock.now();
+        if let Some(window) = inner.config.watchdog {
+            let scope = root.clone();
+            inner.maintenance.add(start + window, None, move |inner| {
+                watchdog(window, inner, &scope)
+            });
+        }
+        if let Some(interval) = inner.config.counter_rate_limit {
+            inner
+                .maintenance
+                .add(start + interval, Some(interval), move |inner| {
+                    inner.send_limited_leftovers(interval)
+                });
+        }
+        if let Some(interval) = inner.config.sharded_counters {
+            inner
+                .maintenance
+                .add(start + interval, Some(interval), |inner| {
+                    inner.counter_shards.merge()
+                });
+        }
+        if let Some(window) = inner.config.counter_bursts {
+            inner
+                .maintenance
+                .add(start + window, Some(window), Inner::close_counter_windows);
+        }
+        if let Some((timeout, reset)) = inner.config.stale_gauges {
+            let period = timeout / 2;
+            inner
+                .maintenance
+                .add(start + period, Some(period), move |inner| {
+                    inner.reset_stale_gauges(timeout, reset)
+                });
+        }
+        if !inner.config.ratios.is_empty() {
+            let window = inner.config.ratio_window.unwrap_or(Duration::from_secs(1));
+            let scope = root.clone();
+            inner
+                .maintenance
+                .add(start + window, Some(window), move |inner| {
+                    inner.close_ratio_windows(&scope.get().scope)
+                });
+        }
+        if inner.config.flush_on_panic {
+            let root: Arc<dyn PanicFlush> = root.0.clone();
+            flush_on_panic(Arc::downgrade(&root));
+        }
+        Maintenance::start(&inner);
+        DipstickLayer { root, inner }
+    }
+}
+
+fn watchdog<S: DipstickSink>(window: Duration, inner: &Inner, root: &SharedRoot<S>) {
+    if !inner.recorded.load(Ordering::Relaxed) {
+        tracing::warn!(
+            "No metrics recorded within {:?}, the tracing-dipstick bridge may be misconfigured",
+            window
+        );
+        if let Some(name) = &inner.config.watchdog_counter {
+            root.get().scope.counter(name).count(1);
+        }
+    }
+}
+
+/// A root scope to flush from the panic hook, see [`DipstickLayerBuilder::flush_on_panic`].
+///
+/// Erases the type of the scope, so the roots of all the layers can be kept together.
+trait PanicFlush: Send + Sync {
+    fn flush(&self);
+}
+
+impl<S: DipstickSink> PanicFlush for RwLock<Arc<Root<S>>> {
+    fn flush(&self) {
+        // The panic may have happened while replacing the root, don't wait for that
+        let root = match self.try_read() {
+            Ok(root) => Arc::clone(&root),
+            Err(TryLockError::Poisoned(e)) => Arc::clone(&e.into_inner()),
+            Err(TryLockError::WouldBlock) => return,
+        };
+        // Nothing much to do about the error while panicking
+        let _ = root.scope.flush();
+    }
+}
+
+/// The roots of all the layers with [`DipstickLayerBuilder::flush_on_panic`].
+///
+/// Weak, so the panic hook doesn't keep the layers (and their scopes) alive.
+static PANIC_ROOTS: Mutex<Vec<Weak<dyn PanicFlush>>> = Mutex::new(Vec::new());
+/// The panic hook flushing the [`PANIC_ROOTS`] is installed only once, for the first layer.
+static PANIC_HOOK: Once = Once::new();
+
+/// Registers the root to be flushed when the program panics.
+fn flush_on_panic(root: Weak<dyn PanicFlush>) {
+    PANIC_HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            previous(info);
+            // The panic may have happened while registering a root, don't wait for that
+            let roots = match PANIC_ROOTS.try_lock() {
+                Ok(roots) => roots,
+                Err(TryLockError::Poisoned(e)) => e.into_inner(),
+                Err(TryLockError::WouldBlock) => return,
+            };
+            for root in roots.iter().filter_map(Weak::upgrade) {
+                root.flush();
+            }
+        }));
+    });
+    let mut roots = PANIC_ROOTS.lock().unwrap_or_else(|e| e.into_inner());
+    // Forget the layers that are gone
+    roots.retain(|root| root.strong_count() > 0);
+    roots.push(root);
+}
+
+/// Runs the closure with the scope of the span or its nearest ancestor that has one.
+///
+/// Returns `None` if there's no such span.
+fn nearest_scope<S, I, R, F>(span: Option<SpanRef<I>>, f: F) -> Option<R>
+where
+    S: DipstickSink,
+    I: for<'l> LookupSpan<'l>,
+    F: FnOnce(&Scope<S>) -> R,
+{
+    let span = span?
+        .scope()
+        .find(|span| span.extensions().get::<Scope<S>>().is_some())?;
+    let extensions = span.extensions();
+    extensions.get::<Scope<S>>().map(f)
+}
+
+/// The implementation of the [`Layer`], with the panics caught by the wrappers below.
+impl<S> DipstickLayer<S>
+where
+    S: DipstickSink,
+{
+    /// Runs the body, reporting its panic instead of passing it on.
+    fn catching<F: FnOnce()>(&self, body: F) {
+        let payload = match panic::catch_unwind(AssertUnwindSafe(body)) {
+            Ok(()) => return,
+            Err(payload) => payload,
+        };
+        let message = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown panic");
+        match &self.inner.config.error_hook {
+            Some(hook) => (hook.0)(message),
+            None if !self.inner.panicked.swap(true, Ordering::Relaxed) => {
+                tracing::warn!("Recording metrics panicked: {}", message);
+            }
+            None => (),
+        }
+    }
+
+    fn new_span<I>(&self, attrs: &Attributes, id: &Id, ctx: Context<I>)
+    where
+        I: Subscriber + for<'l> LookupSpan<'l>,
+    {
+        #[derive(Default)]
+        struct NameVisitor {
+            name: ScopeName,
+            field: Option<String>,
+            summary_root: bool,
+            dynamic: bool,
+            per_enter: bool,
+            auto_gauge: bool,
+            count_children: Option<String>,
+            not_entered: Option<String>,
+            flush_every: Option<Duration>,
+        }
+        impl Visit for NameVisitor {
+            fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+                if field.name() == SCOPE_NAME || field.name() == SCOPE_NAME_FULL {
+                    self.record_str(field, &debug_scope_name(value));
+                }
+            }
+            fn record_str(&mut self, field: &Field, value: &str) {
+                let name = field.name();
+                if name == SCOPE_NAME {
+                    self.name = ScopeName::Add(value.to_owned());
+                } else if name == SCOPE_NAME_FULL {
+                    self.name = ScopeName::Full(value.to_owned());
+                } else if name == SCOPE_FIELD {
+                    self.field = Some(value.to_owned());
+                } else if name == METRICS_ON {
+                    self.per_enter = value == "enter";
+                } else if name == COUNT_CHILDREN {
+                    self.count_children = Some(value.to_owned());
+                } else if name == NOT_ENTERED {
+                    self.not_entered = Some(value.to_owned());
+                }
+            }
+            fn record_bool(&mut self, field: &Field, value: bool) {
+                let name = field.name();
+                if name == SUMMARY {
+                    self.summary_root = value;
+                } else if name == SCOPE_DYNAMIC {
+                    self.dynamic = value;
+                } else if name == AUTO_GAUGE {
+                    self.auto_gauge = value;
+                }
+            }
+            // The numbers still go to record_debug, for the non-string scopes
+            fn record_u64(&mut self, field: &Field, value: u64) {
+                if field.name() == FLUSH_EVERY {
+                    self.flush_every = Some(Duration::from_secs(value)).filter(|p| !p.is_zero());
+                } else {
+                    self.record_debug(field, &value);
+                }
+            }
+            fn record_i64(&mut self, field: &Field, value: i64) {
+                if field.name() == FLUSH_EVERY {
+                    self.flush_every = u64::try_from(value)
+                        .ok()
+                        .map(Duration::from_secs)
+                        .filter(|p| !p.is_zero());
+                } else {
+                    self.record_debug(field, &value);
+                }
+            }
+            fn record_f64(&mut self, field: &Field, value: f64) {
+                if field.name() == FLUSH_EVERY {
+                    self.flush_every = Duration::try_from_secs_f64(value)
+                        .ok()
+                        .filter(|p| !p.is_zero());
+                } else {
+                    self.record_debug(field, &value);
+                }
+            }
+        }
+        // The spans not touching metrics don't get their own scope, the nearest ancestor's is used
+        if !self.touches_metrics(attrs.metadata()) {
+            if self.inner.count_children.load(Ordering::Relaxed) {
+                nearest_scope(ctx.lookup_current(), Scope::<S>::count_child);
+            }
+            return;
+        }
+        if self.inner.config.warn_unknown {
+            attrs.record(&mut UnknownVisitor(&self.inner));
+        }
+        let mut naming = NameVisitor::default();
+        attrs.record(&mut naming);
+        let summary_root = naming.summary_root;
+        let field = naming.field.and_then(|field| {
+            let names = [field];
+            let mut values = FieldValues {
+                names: &names,
+                values: vec![None],
+            };
+            attrs.record(&mut values);
+            let value = values.values.pop().flatten();
+            let metadata = attrs.metadata();
+            if value.is_none()
+                && self.inner.config.warn_unknown
+                && self
+                    .inner
+                    .first_warning("missing scope field", metadata.name())
+            {
+                tracing::warn!(
+                    "Span {} has no field {} to name its metrics scope by",
+                    metadata.name(),
+                    names[0]
+                );
+            }
+            value
+        });
+        let span_naming = SpanNaming {
+            span_name: self
+                .inner
+                .config
+                .span_name_prefix
+                .then(|| attrs.metadata().name()),
+            name: naming.name,
+            field,
+            tags: TagVisitor::collect(attrs.metadata(), |visitor| attrs.record(visitor)),
+        };
+        let flush = self.inner.config.flush_on_scope_close
+            && (!matches!(span_naming.name, ScopeName::Inherit)
+                || span_naming.field.is_some()
+                || span_naming.span_name.is_some());
+        // Copied out of the parent, the scope is not called while it is locked
+        let parent = nearest_scope(ctx.lookup_current(), |parent: &Scope<S>| {
+            parent.count_child();
+            (
+                Arc::clone(&parent.scope),
+                parent.summary.clone(),
+                parent.max_levels.clone(),
+                parent.child_counts.clone(),
+            )
+        });
+        let (scope, summary, max_levels, mut child_counts) = match parent {
+            Some((parent, summary, max_levels, child_counts)) => (
+                span_naming.apply_shared(&parent),
+                summary,
+                max_levels,
+                child_counts,
+            ),
+            None => {
+                let scope = Arc::new(span_naming.apply(&self.root.get().scope));
+                (scope, None, Vec::new(), Vec::new())
+            }
+        };
+        let counted_children = naming
+            .count_children
+            .as_deref()
+            .and_then(|name| {
+                let span = attrs.metadata().name();
+                self.inner.config.metric_name(name, Some(span))
+            })
+            .map(|name| {
+                self.inner.count_children.store(true, Ordering::Relaxed);
+                let count = Arc::new(AtomicU64::new(0));
+                child_counts.push(Arc::clone(&count));
+                (scope.counter(&name), count)
+            });
+        let not_entered = naming
+            .not_entered
+            .as_deref()
+            .and_then(|name| {
+                let span = attrs.metadata().name();
+                self.inner.config.metric_name(name, Some(span))
+            })
+            .map(|name| scope.counter(&name));
+        let dynamic = (naming.dynamic && self.inner.config.dynamic_scopes).then_some(span_naming);
+        let flush_schedule = naming
+            .flush_every
+            .map(|period| self.schedule_flush(&scope, period));
+        let summary = match (summary_root, &self.inner.config.summary_hook) {
+            (true, Some(hook)) => Some(Arc::new(SummaryCell::new(
+                attrs.metadata().name(),
+                hook.clone(),
+            ))),
+            _ => summary,
+        };
+
+        let mut scope = PointWrap::new(
+            Scope {
+                scope,
+                dynamic,
+                on_drop: SmallVec::new(),
+                pending_timers: Vec::new(),
+                gauges: Vec::new(),
+                busy: None,
+                per_enter: naming.per_enter.then(Box::default),
+                summary,
+                max_levels,
+                held_max_levels: Vec::new(),
+                deferred: self
+                    .inner
+                    .config
+                    .defer_to_close
+                    .then(|| Arc::new(DeferredMetrics::new(Arc::clone(&self.inner)))),
+                flush,
+                _flush_schedule: flush_schedule,
+                child_counts,
+                counted_children,
+                not_entered,
+                counters: Arc::default(),
+                timer_batches: Arc::new(TimerBatches::new(self.inner.config.timer_batch)),
+                auto_gauges: naming.auto_gauge,
+            },
+            &self.inner,
+            attrs.metadata(),
+        );
+        if naming.per_enter {
+            self.inner.track_exits.store(true, Ordering::Relaxed);
+        }
+        scope.auto_gauges = naming.auto_gauge;
+        attrs.record(&mut scope);
+        scope.record_dynamic(|visitor| attrs.record(visitor));
+        emit_warnings(mem::take(&mut scope.warnings));
+
+        match ctx.span(id) {
+            Some(span) => span.extensions_mut().insert(scope.point),
+            // Shouldn't happen with a well-behaved registry, but it's not worth a panic
+            None => tracing::debug!("Span {:?} not found, dropping its metrics", id),
+        }
+    }
+    fn enter<I>(&self, id: &Id, ctx: Context<I>)
+    where
+        I: Subscriber + for<'l> LookupSpan<'l>,
+    {
+        let parent = if self.inner.config.dynamic_scopes {
+            let tag = self.tag();
+            ENTERED.with(|entered| {
+                let mut entered = entered.borrow_mut();
+                // Skip the span itself, in case it is entered recursively
+                let parent = entered
+                    .iter()
+                    .rev()
+                    .find(|(t, i)| *t == tag && i != id)
+                    .map(|(_, i)| i.clone());
+                entered.push((tag, id.clone()));
+                parent
+            })
+        } else {
+            None
+        };
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => {
+                tracing::debug!("Entered span {:?} not found", id);
+                return;
+            }
+        };
+        let dynamic = span
+            .extensions()
+            .get::<Scope<S>>()
+            .and_then(|scope| scope.dynamic.clone());
+        // Computed before locking the span for writing, as the span may be among the ancestors
+        let dynamic = dynamic.map(|naming| {
+            let parent = parent.and_then(|parent| ctx.span(&parent));
+            match nearest_scope(parent, |parent: &Scope<S>| Arc::clone(&parent.scope)) {
+                Some(parent_scope) => naming.apply(&*parent_scope),
+                None => naming.apply(&self.root.get().scope),
+            }
+        });
+        let mut extensions = span.extensions_mut();
+        // The values batched in the previous scope, sent outside of the lock
+        let mut timer_batches = None;
+        if let Some(scope) = extensions.get_mut::<Scope<S>>() {
+            if let Some(dynamic) = dynamic {
+                scope.scope = Arc::new(dynamic);
+                // Cached from the previous scope
+                scope.counters = Arc::default();
+                let batches = Arc::new(TimerBatches::new(self.inner.config.timer_batch));
+                timer_batches = Some(mem::replace(&mut scope.timer_batches, batches));
+            }
+            scope.not_entered = None;
+            if !scope.pending_timers.is_empty() {
+                let start = self.inner.config.clock.now();
+                for timer in mem::take(&mut scope.pending_timers) {
+                    scope.push_running_timer(timer, start);
+                }
+            }
+            if let Some(busy) = &scope.busy {
+                busy.enter();
+            }
+            let per_enter = scope.per_enter.as_ref().map(|per_enter| {
+                (
+                    Arc::clone(&per_enter.metrics),
+                    Arc::clone(&scope.scope),
+                    scope.summary.clone(),
+                )
+            });
+            // Recorded outside of the lock
+            drop(extensions);
+            drop(timer_batches);
+            if let Some((metrics, scope, summary)) = per_enter {
+                let mut point = EnterPoint {
+                    span,
+                    scope,
+                    summary,
+                    on_exit: Vec::new(),
+                };
+                for (tp, name, value) in metrics.iter() {
+                    tp.measure(&mut point, &self.inner, name, *value);
+                }
+            }
+        }
+    }
+    fn exit<I>(&self, id: &Id, ctx: Context<I>)
+    where
+        I: Subscriber + for<'l> LookupSpan<'l>,
+    {
+        if self.inner.track_exits.load(Ordering::Relaxed) {
+            if let Some(span) = ctx.span(id) {
+                let mut extensions = span.extensions_mut();
+                let mut finish = None;
+                if let Some(scope) = extensions.get_mut::<Scope<S>>() {
+                    if let Some(busy) = &scope.busy {
+                        busy.exit();
+                    }
+                    if let Some(per_enter) = &mut scope.per_enter {
+                        let thread = thread::current().id();
+                        if let Some(pos) = per_enter.on_exit.iter().rposition(|(t, _)| *t == thread)
+                        {
+                            let (_, items) = per_enter.on_exit.remove(pos);
+                            finish = Some((items, scope.summary.clone()));
+                        }
+                    }
+                }
+                // Finished outside of the lock
+                drop(extensions);
+                if let Some((items, summary)) = finish {
+                    for item in items {
+                        item.finish(summary.as_deref());
+                    }
+                }
+            }
+        }
+        if self.inner.config.dynamic_scopes {
+            let tag = self.tag();
+            ENTERED.with(|entered| {
+                let mut entered = entered.borrow_mut();
+                if let Some(pos) = entered.iter().rposition(|(t, i)| *t == tag && i == id) {
+                    entered.remove(pos);
+                }
+            });
+        }
+    }
+    fn record<I>(&self, id: &Id, values: &Record, ctx: Context<I>)
+    where
+        I: Subscriber + for<'l> LookupSpan<'l>,
+    {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let metadata = span.metadata();
+        let late_naming = metadata.fields().iter().find(|field| {
+            let name = field.name();
+            let naming = name == SCOPE_NAME
+                || name == SCOPE_NAME_FULL
+                || name == SCOPE_FIELD
+                || name.starts_with(TAG_PREFIX);
+            naming && values.contains(field)
+        });
+        let mut warnings = Vec::new();
+        if let Some(field) = late_naming {
+            if self.inner.config.warn_unknown
+                && self.inner.first_warning("late scope", field.name())
+            {
+                warnings.push(format!(
+                    "Ignoring {} recorded on span {} after its creation, the scope is already set",
+                    field.name(),
+                    metadata.name()
+                ));
+            }
+        }
+        // Without a scope, the span doesn't declare any metrics.* field
+        let scope = span.extensions().get::<Scope<S>>().map(|scope| {
+            (
+                Arc::clone(&scope.scope),
+                scope.summary.clone(),
+                scope.per_enter.is_some(),
+                scope.auto_gauges,
+            )
+        });
+        // Recorded outside of the lock, the point hands the rest over to the scope once dropped
+        if let Some((scope, summary, per_enter, auto_gauges)) = scope {
+            let point = RecordPoint {
+                span,
+                scope,
+                summary,
+                per_enter,
+                held: Vec::new(),
+            };
+            let mut point = PointWrap::new(point, &self.inner, metadata);
+            point.auto_gauges = auto_gauges;
+            values.record(&mut point);
+            point.record_dynamic(|visitor| values.record(visitor));
+            warnings.append(&mut point.warnings);
+        }
+        emit_warnings(warnings);
+    }
+    fn close<I>(&self, id: Id, ctx: Context<I>)
+    where
+        I: Subscriber + for<'l> LookupSpan<'l>,
+    {
+        // Clones of span IDs need no handling, the registry keeps the span (and its scope) alive
+        // until the last one is closed, calling on_close only then
+        if let Some(span) = ctx.span(&id) {
+            let scope = span.extensions_mut().remove::<Scope<S>>();
+            let flush = scope
+                .as_ref()
+                .filter(|scope| scope.flush)
+                .map(|scope| Arc::clone(&scope.scope));
+            // Finished outside of the lock
+            drop(scope);
+            if let Some(scope) = flush {
+                // The periodic flush would run into the same error later on, if it's persistent
+                let _ = scope.flush();
+            }
+        }
+    }
+    /// Checks the `metrics.when` guard of an event.
+    fn guard_passes(&self, event: &Event) -> bool {
+        let names = [METRICS_WHEN.to_owned()];
+        let mut values = FieldValues {
+            names: &names,
+            values: vec![None],
+        };
+        event.record(&mut values);
+        let name = match values.values.pop().flatten() {
+            Some(name) => name,
+            None => return true,
+        };
+        let metadata = event.metadata();
+        let field = match metadata.fields().field(&name) {
+            Some(field) => field,
+            None => {
+                if self.inner.config.warn_unknown
+                    && self.inner.first_warning("missing guard", metadata.name())
+                {
+                    tracing::warn!(
+                        "Event {} has no field {} to guard its metrics",
+                        metadata.name(),
+                        name
+                    );
+                }
+                return true;
+            }
+        };
+        let mut guard = GuardVisitor(field.name(), None);
+        event.record(&mut guard);
+        guard.1.unwrap_or_else(|| {
+            if self.inner.first_warning("non-bool guard", field.name()) {
+                tracing::warn!("Guard field {} is not a bool, recording the metrics", name);
+            }
+            true
+        })
+    }
+    fn event<I>(&self, event: &Event, ctx: Context<I>)
+    where
+        I: Subscriber + for<'l> LookupSpan<'l>,
+    {
+        // TODO: Initialize the scope of a span lazily on the first access? But extensions_mut
+        // might be slower?
+        let metadata = event.metadata();
+        // Don't even look for the scope of the span if there's nothing to record
+        let has_metrics = metadata
+            .fields()
+            .iter()
+            .any(|field| field.name().starts_with("metrics."));
+        let error_marker = self
+            .inner
+            .config
+            .error_marker
+            .as_deref()
+            .filter(|_| *metadata.level() == TracingLevel::ERROR);
+        if !has_metrics && self.inner.config.message_counters.is_none() && error_marker.is_none() {
+            return;
+        }
+        if metadata.fields().field(METRICS_WHEN).is_some() && !self.guard_passes(event) {
+            return;
+        }
+        if self.inner.config.warn_unknown {
+            event.record(&mut UnknownVisitor(&self.inner));
+        }
+
+        let reset = metadata.fields().field(SCOPE_RESET).is_some() && {
+            let mut flag = FlagVisitor(SCOPE_RESET, false);
+            event.record(&mut flag);
+            flag.1
+        };
+        let current = ctx.lookup_current();
+        let span_name = current.as_ref().map(|span| span.name());
+        let span = current.and_then(|span| {
+            span.scope()
+                .find(|span| span.extensions().get::<Scope<S>>().is_some())
+        });
+        // Copied out of the span, the scope is not called while it is locked
+        let span_scope = span.as_ref().and_then(|span| {
+            let extensions = span.extensions();
+            extensions.get::<Scope<S>>().map(|scope| {
+                (
+                    Arc::clone(&scope.scope),
+                    Arc::clone(&scope.counters),
+                    Arc::clone(&scope.timer_batches),
+                    scope.summary.clone(),
+                    scope.deferred.clone(),
+                )
+            })
+        });
+        let level = metadata.level();
+        let level = match self.inner.config.level_scope {
+            // Less verbose levels compare as smaller
+            Some(threshold) if *level <= threshold => Some(level_name(level)),
+            _ => None,
+        };
+        let root;
+        let (scope, counters, timer_batches) = match &span_scope {
+            Some((scope, counters, batches, ..)) if !reset => {
+                (&**scope, &**counters, Some(&**batches))
+            }
+            _ => {
+                root = self.root.get();
+                (&root.scope, &root.counters, None)
+            }
+        };
+        let point = EventPoint {
+            scope,
+            counters: Some(counters),
+            timer_batches,
+            name: ScopeNameVisitor::collect(metadata, |visitor| event.record(visitor)),
+            tags: TagVisitor::collect(metadata, |visitor| event.record(visitor)),
+            level,
+            derived: OnceCell::new(),
+            summary: span_scope.as_ref().and_then(|scope| scope.3.as_ref()),
+            deferred: span_scope.as_ref().and_then(|scope| scope.4.as_ref()),
+        };
+        let mut point = PointWrap::new(point, &self.inner, metadata);
+        point.span_name = span_name;
+        // Recorded in one go once all the fields are seen (the timers across events excepted)
+        point.batch = Some(Batch::default());
+        event.record(&mut point);
+        point.record_dynamic(|visitor| event.record(visitor));
+
+        if let Some(prefix) = &self.inner.config.message_counters {
+            let name = callsite_name(prefix, metadata);
+            point.measure(MetricType::Counter, &name, 1);
+        }
+        if let Some(name) = error_marker {
+            point.measure(MetricType::Marker, name, 1);
+        }
+        point.apply_batch();
+
+        emit_warnings(point.warnings);
+    }
+}
+
+impl<S, I> Layer<I> for DipstickLayer<S>
+where
+    S: DipstickSink,
+    I: Subscriber,
+    for<'l> I: LookupSpan<'l>,
+{
+    fn on_new_span(&self, attrs: &Attributes, id: &Id, ctx: Context<I>) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.catching(|| self.new_span(attrs, id, ctx));
+    }
+    fn on_enter(&self, id: &Id, ctx: Context<I>) {
+        self.catching(|| self.enter(id, ctx));
+    }
+    fn on_exit(&self, id: &Id, ctx: Context<I>) {
+        self.catching(|| self.exit(id, ctx));
+    }
+    fn on_record(&self, id: &Id, values: &Record, ctx: Context<I>) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.catching(|| self.record(id, values, ctx));
+    }
+    fn on_close(&self, id: Id, ctx: Context<I>) {
+        self.catching(|| self.close(id, ctx));
+    }
+    fn on_event(&self, event: &Event, ctx: Context<I>) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.catching(|| self.event(event, ctx));
     }
 }
@@ -0,0 +1,174 @@
+//! Macros checking the `metrics.*` attributes at compile time.
+
+/// Like [`tracing::event!`], but checks the `metrics.*` attributes at compile time.
+///
+/// An unknown `metrics.*` attribute (most likely a typo) is a compile error instead of being
+/// silently ignored. Otherwise, this expands to a plain [`tracing::event!`], so it behaves exactly
+/// the same.
+///
+/// The level may be given as the first argument, written as `Level::LEVEL` (with the
+/// [`Level`][tracing::Level] imported) or `tracing::Level::LEVEL`. The default is `INFO`. The rest
+/// are the fields and the message, as with [`tracing::event!`]. Other forms (eg. with `target:` or
+/// `parent:`) are not supported.
+///
+/// The attributes are checked by a recursive macro, going through the tokens one by one. Events
+/// with very many fields may therefore need a higher `recursion_limit`.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[cfg(feature = "testing")] {
+/// use tracing::Level;
+/// use tracing_dipstick::metric_event;
+/// use tracing_dipstick::testing::with_test_layer;
+///
+/// let events = with_test_layer(|_| {
+///     metric_event!(metrics.counter = "hits");
+///     metric_event!(Level::WARN, metrics.gauge.queue = 42, "The queue is {}", "long");
+/// });
+/// assert_eq!(2, events.len());
+/// # }
+/// ```
+///
+/// ```compile_fail
+/// use tracing_dipstick::metric_event;
+///
+/// metric_event!(metrics.countr = "hits");
+/// ```
+#[macro_export]
+macro_rules! metric_event {
+    (Level :: $level:ident, $($fields:tt)*) => {{
+        $crate::__check_metrics!($($fields)*);
+        $crate::__tracing::event!(Level::$level, $($fields)*)
+    }};
+    (tracing :: Level :: $level:ident, $($fields:tt)*) => {{
+        $crate::__check_metrics!($($fields)*);
+        $crate::__tracing::event!(tracing::Level::$level, $($fields)*)
+    }};
+    ($($fields:tt)*) => {{
+        $crate::__check_metrics!($($fields)*);
+        $crate::__tracing::event!($crate::__tracing::Level::INFO, $($fields)*)
+    }};
+}
+
+/// Like [`tracing::span!`], but checks the `metrics.*` attributes at compile time.
+///
+/// See [`metric_event!`] for details. The level is optional the same way, followed by the name of
+/// the span and its fields.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[cfg(feature = "testing")] {
+/// use tracing_dipstick::metric_span;
+/// use tracing_dipstick::testing::with_test_layer;
+///
+/// let events = with_test_layer(|_| {
+///     let _span = metric_span!("request", metrics.level = "requests").entered();
+/// });
+/// assert_eq!(2, events.len());
+/// # }
+/// ```
+///
+/// ```compile_fail
+/// use tracing_dipstick::metric_span;
+///
+/// let _span = metric_span!("request", metrics.scope.ful = "request");
+/// ```
+#[macro_export]
+macro_rules! metric_span {
+    (Level :: $level:ident, $name:expr $(, $($fields:tt)*)?) => {{
+        $crate::__check_metrics!($($($fields)*)?);
+        $crate::__tracing::span!(Level::$level, $name $(, $($fields)*)?)
+    }};
+    (tracing :: Level :: $level:ident, $name:expr $(, $($fields:tt)*)?) => {{
+        $crate::__check_metrics!($($($fields)*)?);
+        $crate::__tracing::span!(tracing::Level::$level, $name $(, $($fields)*)?)
+    }};
+    ($name:expr $(, $($fields:tt)*)?) => {{
+        $crate::__check_metrics!($($($fields)*)?);
+        $crate::__tracing::span!($crate::__tracing::Level::INFO, $name $(, $($fields)*)?)
+    }};
+}
+
+/// Checks the field names of [`metric_event!`] and [`metric_span!`], one field at a time.
+///
+/// Expands to nothing or to a compile error.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __check_metrics {
+    // Skipping the rest of a field, up to the next one
+    (@skip) => {};
+    (@skip , $($rest:tt)*) => { $crate::__check_metrics!($($rest)*); };
+    (@skip $_t:tt $($rest:tt)*) => { $crate::__check_metrics!(@skip $($rest)*); };
+    // The name must end here, eg. `metrics.marker.x` is not known
+    (@end [$($name:tt)*] . $sub:tt $($rest:tt)*) => {
+        $crate::__check_metrics!(@unknown $($name)* . $sub);
+    };
+    (@end [$($name:tt)*] $($rest:tt)*) => { $crate::__check_metrics!(@skip $($rest)*); };
+    (@unknown $($name:tt)*) => {
+        compile_error!(concat!("Unknown metrics attribute ", stringify!($($name)*)));
+    };
+    // The end
+    () => {};
+    // The metric types, with the value forms
+    (metrics . counter $($rest:tt)*) => { $crate::__check_metrics!(@skip $($rest)*); };
+    (metrics . gauge $($rest:tt)*) => { $crate::__check_metrics!(@skip $($rest)*); };
+    (metrics . level $($rest:tt)*) => { $crate::__check_metrics!(@skip $($rest)*); };
+    (metrics . timer $($rest:tt)*) => { $crate::__check_metrics!(@skip $($rest)*); };
+    (metrics . distribution . $($rest:tt)*) => { $crate::__check_metrics!(@skip $($rest)*); };
+    (metrics . tag . $($rest:tt)*) => { $crate::__check_metrics!(@skip $($rest)*); };
+    // The scopes
+    (metrics . scope . full $($rest:tt)*) => {
+        $crate::__check_metrics!(@end [metrics.scope.full] $($rest)*);
+    };
+    (metrics . scope . dynamic $($rest:tt)*) => {
+        $crate::__check_metrics!(@end [metrics.scope.dynamic] $($rest)*);
+    };
+    (metrics . scope . field $($rest:tt)*) => {
+        $crate::__check_metrics!(@end [metrics.scope.field] $($rest)*);
+    };
+    (metrics . scope . reset $($rest:tt)*) => {
+        $crate::__check_metrics!(@end [metrics.scope.reset] $($rest)*);
+    };
+    // The rest, without any sub-names
+    (metrics . scope $($rest:tt)*) => {
+        $crate::__check_metrics!(@end [metrics.scope] $($rest)*);
+    };
+    (metrics . marker $($rest:tt)*) => {
+        $crate::__check_metrics!(@end [metrics.marker] $($rest)*);
+    };
+    (metrics . reset $($rest:tt)*) => {
+        $crate::__check_metrics!(@end [metrics.reset] $($rest)*);
+    };
+    (metrics . peak $($rest:tt)*) => {
+        $crate::__check_metrics!(@end [metrics.peak] $($rest)*);
+    };
+    (metrics . count_children $($rest:tt)*) => {
+        $crate::__check_metrics!(@end [metrics.count_children] $($rest)*);
+    };
+    (metrics . not_entered $($rest:tt)*) => {
+        $crate::__check_metrics!(@end [metrics.not_entered] $($rest)*);
+    };
+    (metrics . summary $($rest:tt)*) => {
+        $crate::__check_metrics!(@end [metrics.summary] $($rest)*);
+    };
+    (metrics . on $($rest:tt)*) => {
+        $crate::__check_metrics!(@end [metrics.on] $($rest)*);
+    };
+    (metrics . when $($rest:tt)*) => {
+        $crate::__check_metrics!(@end [metrics.when] $($rest)*);
+    };
+    (metrics . auto_gauge $($rest:tt)*) => {
+        $crate::__check_metrics!(@end [metrics.auto_gauge] $($rest)*);
+    };
+    (metrics . flush_every $($rest:tt)*) => {
+        $crate::__check_metrics!(@end [metrics.flush_every] $($rest)*);
+    };
+    (metrics . level_value $($rest:tt)*) => {
+        $crate::__check_metrics!(@end [metrics.level_value] $($rest)*);
+    };
+    (metrics . $name:tt $($rest:tt)*) => { $crate::__check_metrics!(@unknown metrics.$name); };
+    // Not a metrics attribute at all
+    ($_t:tt $($rest:tt)*) => { $crate::__check_metrics!(@skip $($rest)*); };
+}
@@ -0,0 +1,213 @@
+//! The periodic work of the layer, done by a single background thread.
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::Inner;
+
+/// A piece of the periodic work.
+struct Task {
+    /// How often the task runs, or `None` for running only once.
+    period: Option<Duration>,
+    /// When the task is due next, by the [`Clock`][crate::Clock] of the layer.
+    next: Instant,
+    job: Box<dyn FnMut(&Inner) + Send>,
+    /// Set by the [`TaskGuard`] of the task, if it has one.
+    cancelled: Arc<AtomicBool>,
+}
+
+/// Cancels its task once dropped.
+pub(crate) struct TaskGuard(Arc<AtomicBool>);
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+struct WakeState {
+    stopped: bool,
+    /// Tasks were added since the thread looked at them last.
+    changed: bool,
+}
+
+/// Wakes the thread up, either to look at new tasks or to terminate once the layer is gone.
+#[derive(Default)]
+struct Wakeup {
+    state: Mutex<WakeState>,
+    condvar: Condvar,
+}
+
+impl Wakeup {
+    fn notify<F: FnOnce(&mut WakeState)>(&self, change: F) {
+        change(&mut self.state.lock().unwrap_or_else(|e| e.into_inner()));
+        self.condvar.notify_all();
+    }
+
+    /// Waits for the given time (or forever), until new tasks are added or until stopped.
+    ///
+    /// Returns if stopped.
+    fn wait(&self, timeout: Option<Duration>) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if !state.stopped && !state.changed {
+            state = match timeout {
+                Some(timeout) => {
+                    self.condvar
+                        .wait_timeout_while(state, timeout, |s| !s.stopped && !s.changed)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+                None => self
+                    .condvar
+                    .wait_while(state, |s| !s.stopped && !s.changed)
+                    .unwrap_or_else(|e| e.into_inner()),
+            };
+        }
+        state.changed = false;
+        state.stopped
+    }
+}
+
+/// The periodic work of the layer, eg. closing the windows of
+/// [counter bursts][crate::DipstickLayerBuilder::counter_bursts].
+///
+/// All the tasks share a single background thread, started with the first one. The thread
+/// terminates once the layer is dropped. The work can also be run by hand, with
+/// [`DipstickLayer::tick`][crate::DipstickLayer::tick].
+#[derive(Default)]
+pub(crate) struct Maintenance {
+    tasks: Mutex<Vec<Task>>,
+    wakeup: Arc<Wakeup>,
+    started: AtomicBool,
+}
+
+impl Maintenance {
+    /// Adds a task, first due at the given time.
+    pub(crate) fn add<F>(&self, next: Instant, period: Option<Duration>, job: F)
+    where
+        F: FnMut(&Inner) + Send + 'static,
+    {
+        self.tasks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(Task {
+                period,
+                next,
+                job: Box::new(job),
+                cancelled: Arc::default(),
+            });
+    }
+
+    /// Adds a periodic task once the layer is already running, until the guard is dropped.
+    ///
+    /// The task is first due one period from now.
+    pub(crate) fn add_running<F>(inner: &Arc<Inner>, period: Duration, job: F) -> TaskGuard
+    where
+        F: FnMut(&Inner) + Send + 'static,
+    {
+        let maintenance = &inner.maintenance;
+        let cancelled = Arc::new(AtomicBool::new(false));
+        maintenance
+            .tasks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(Task {
+                period: Some(period),
+                next: inner.config.clock.now() + period,
+                job: Box::new(job),
+                cancelled: Arc::clone(&cancelled),
+            });
+        Self::start(inner);
+        // The thread may be sleeping until some much later task
+        maintenance.wakeup.notify(|state| state.changed = true);
+        TaskGuard(cancelled)
+    }
+
+    /// Starts the background thread, if there's any work for it and it's not running yet.
+    pub(crate) fn start(inner: &Arc<Inner>) {
+        let maintenance = &inner.maintenance;
+        if maintenance
+            .tasks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_empty()
+        {
+            return;
+        }
+        if maintenance.started.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let wakeup = Arc::clone(&maintenance.wakeup);
+        let inner = Arc::downgrade(inner);
+        let started = thread::Builder::new()
+            .name("tracing-dipstick-maintenance".to_owned())
+            .spawn(move || run(inner, wakeup));
+        if let Err(e) = started {
+            tracing::warn!(
+                "Failed to start the tracing-dipstick maintenance thread, the periodic work \
+                 runs only through DipstickLayer::tick: {}",
+                e
+            );
+        }
+    }
+
+    /// Runs the tasks that are due.
+    ///
+    /// Returns the time until the next one is due, if there's any.
+    pub(crate) fn tick(&self, inner: &Inner) -> Option<Duration> {
+        let now = inner.config.clock.now();
+        let mut tasks = self.tasks.lock().unwrap_or_else(|e| e.into_inner());
+        tasks.retain_mut(|task| {
+            if task.cancelled.load(Ordering::Relaxed) {
+                return false;
+            }
+            if task.next > now {
+                return true;
+            }
+            (task.job)(inner);
+            match task.period {
+                Some(period) => {
+                    task.next = now + period;
+                    true
+                }
+                None => false,
+            }
+        });
+        tasks
+            .iter()
+            .map(|task| task.next.saturating_duration_since(now))
+            .min()
+    }
+}
+
+impl Debug for Maintenance {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        let tasks = self.tasks.lock().unwrap_or_else(|e| e.into_inner()).len();
+        fmt.debug_struct("Maintenance")
+            .field("tasks", &tasks)
+            .finish()
+    }
+}
+
+impl Drop for Maintenance {
+    fn drop(&mut self) {
+        self.wakeup.notify(|state| state.stopped = true);
+    }
+}
+
+fn run(inner: Weak<Inner>, wakeup: Arc<Wakeup>) {
+    loop {
+        // Not keeping the layer alive while waiting
+        let next = match inner.upgrade() {
+            Some(inner) => inner.maintenance.tick(&inner),
+            None => return,
+        };
+        if wakeup.wait(next) {
+            return;
+        }
+    }
+}
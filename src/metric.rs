@@ -0,0 +1,57 @@
+//! The metric handles a [`DipstickSink`][crate::DipstickSink] hands out.
+//!
+//! With the `dipstick` feature (on by default), these are the types of [`dipstick`] itself.
+//! Without it, the crate doesn't depend on [`dipstick`] and these are minimal stand-ins with the
+//! same methods. The sinks sending the metrics to some other library (like
+//! [`MetricsRs`][crate::MetricsRs]) can create the handles by [`input_metric`] and work either
+//! way.
+
+#[cfg(feature = "dipstick")]
+pub use dipstick::{
+    Counter, Gauge, InputKind, InputMetric, Labels, Level, Marker, NameParts, Timer,
+};
+
+#[cfg(not(feature = "dipstick"))]
+mod standalone;
+
+#[cfg(not(feature = "dipstick"))]
+pub use standalone::{
+    Counter, Gauge, InputKind, InputMetric, Labels, Level, Marker, NameParts, Timer,
+};
+
+/// Creates a metric passing the values written into it to the closure.
+///
+/// The result converts into any of the handles (eg. [`Counter`]). The name only identifies the
+/// metric inside [`dipstick`] (if used), it doesn't get prepended to anything.
+///
+/// # Examples
+///
+/// ```rust
+/// use std::sync::atomic::{AtomicIsize, Ordering};
+/// use std::sync::Arc;
+///
+/// use tracing_dipstick::metric::{input_metric, Counter};
+///
+/// let total = Arc::new(AtomicIsize::new(0));
+/// let total_cp = Arc::clone(&total);
+/// let counter: Counter = input_metric("hits", move |value, _labels| {
+///     total_cp.fetch_add(value, Ordering::Relaxed);
+/// })
+/// .into();
+/// counter.count(2);
+/// counter.count(3);
+/// assert_eq!(5, total.load(Ordering::Relaxed));
+/// ```
+pub fn input_metric<F>(name: &str, write: F) -> InputMetric
+where
+    F: Fn(isize, Labels) + Send + Sync + 'static,
+{
+    #[cfg(feature = "dipstick")]
+    let metric = InputMetric::new(dipstick::MetricId::forge("tracing", name.into()), write);
+    #[cfg(not(feature = "dipstick"))]
+    let metric = {
+        let _ = name;
+        InputMetric::new(write)
+    };
+    metric
+}
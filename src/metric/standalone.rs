@@ -0,0 +1,182 @@
+//! The stand-ins for the [`dipstick`] metric handles, without the `dipstick` feature.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+
+/// The kind of a metric.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum InputKind {
+    /// Counts occurrences, one at a time.
+    Marker,
+    /// Sums non-negative amounts.
+    Counter,
+    /// Sums amounts that may be negative.
+    Level,
+    /// Keeps the last value.
+    Gauge,
+    /// Records durations (or other sampled values).
+    Timer,
+}
+
+/// Additional information attached to a single value.
+#[derive(Clone, Debug, Default)]
+pub struct Labels(HashMap<String, Arc<String>>);
+
+impl Labels {
+    /// The value of the label, if present.
+    pub fn lookup(&self, key: &str) -> Option<Arc<String>> {
+        self.0.get(key).cloned()
+    }
+}
+
+impl From<HashMap<String, Arc<String>>> for Labels {
+    fn from(labels: HashMap<String, Arc<String>>) -> Self {
+        Labels(labels)
+    }
+}
+
+/// The names of nested scopes, outermost first.
+#[derive(Clone, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct NameParts {
+    nodes: VecDeque<String>,
+}
+
+impl<S: Into<String>> From<S> for NameParts {
+    fn from(name: S) -> Self {
+        NameParts {
+            nodes: VecDeque::from([name.into()]),
+        }
+    }
+}
+
+impl Deref for NameParts {
+    type Target = VecDeque<String>;
+    fn deref(&self) -> &Self::Target {
+        &self.nodes
+    }
+}
+
+impl DerefMut for NameParts {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.nodes
+    }
+}
+
+/// A metric passing the values to a closure, the base of all the handles.
+///
+/// Created by [`input_metric`][super::input_metric].
+#[derive(Clone)]
+pub struct InputMetric(Arc<dyn Fn(isize, Labels) + Send + Sync>);
+
+impl InputMetric {
+    pub(super) fn new<F>(write: F) -> Self
+    where
+        F: Fn(isize, Labels) + Send + Sync + 'static,
+    {
+        InputMetric(Arc::new(write))
+    }
+
+    /// Passes the value to the closure.
+    pub fn write(&self, value: isize, labels: Labels) {
+        (self.0)(value, labels)
+    }
+}
+
+impl Debug for InputMetric {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.write_str("InputMetric")
+    }
+}
+
+/// Converts a value for writing, dropping the ones not fitting into `isize`.
+fn write<V: TryInto<isize>>(metric: &InputMetric, value: V) {
+    if let Ok(value) = value.try_into() {
+        metric.write(value, Labels::default());
+    }
+}
+
+macro_rules! handle {
+    ($(#[$attr:meta])* $name:ident) => {
+        $(#[$attr])*
+        #[derive(Clone, Debug)]
+        pub struct $name {
+            inner: InputMetric,
+        }
+
+        impl From<InputMetric> for $name {
+            fn from(inner: InputMetric) -> Self {
+                $name { inner }
+            }
+        }
+
+        impl Deref for $name {
+            type Target = InputMetric;
+            fn deref(&self) -> &InputMetric {
+                &self.inner
+            }
+        }
+    };
+}
+
+handle!(
+    /// Counts occurrences of something.
+    Marker
+);
+
+impl Marker {
+    /// Records one occurrence.
+    pub fn mark(&self) {
+        write(self, 1);
+    }
+}
+
+handle!(
+    /// Sums non-negative amounts.
+    Counter
+);
+
+impl Counter {
+    /// Adds the amount.
+    pub fn count(&self, count: usize) {
+        write(self, count);
+    }
+}
+
+handle!(
+    /// Sums amounts that may be negative.
+    Level
+);
+
+impl Level {
+    /// Adds the (possibly negative) amount.
+    pub fn adjust<V: TryInto<isize>>(&self, count: V) {
+        write(self, count);
+    }
+}
+
+handle!(
+    /// Keeps the last value.
+    Gauge
+);
+
+impl Gauge {
+    /// Sets the value.
+    pub fn value<V: TryInto<isize>>(&self, value: V) {
+        write(self, value);
+    }
+}
+
+handle!(
+    /// Records durations.
+    Timer
+);
+
+impl Timer {
+    /// Records a duration, in microseconds, returning it back.
+    pub fn interval_us(&self, interval_us: u64) -> u64 {
+        write(self, interval_us);
+        interval_us
+    }
+}
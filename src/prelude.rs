@@ -0,0 +1,32 @@
+//! The items needed by most programs, for a glob import.
+//!
+//! Contains the [`DipstickLayer`], the [`Registry`] to put it into (with [`SubscriberExt`] to do
+//! so) and the [`dipstick`] types for the usual setup of an [`AtomicBucket`] periodically flushed
+//! into a [`Stream`].
+//!
+//! The [`dipstick`] traits with methods defining metrics (eg. [`InputScope`][dipstick::InputScope])
+//! are not included, as they would clash with the methods of [`DipstickSink`][crate::DipstickSink].
+//!
+//! # Examples
+//!
+//! ```rust
+//! use std::time::Duration;
+//!
+//! use tracing_dipstick::prelude::*;
+//!
+//! let bucket = AtomicBucket::new();
+//! bucket.drain(Stream::write_to_stdout());
+//! let _flush = bucket.flush_every(Duration::from_secs(5));
+//!
+//! let subscriber = Registry::default().with(DipstickLayer::new(bucket));
+//! tracing::subscriber::with_default(subscriber, || {
+//!     tracing::info!(metrics.counter = "started");
+//! });
+//! ```
+
+#[cfg(feature = "dipstick")]
+pub use dipstick::{AtomicBucket, ScheduleFlush, Stream};
+pub use tracing_subscriber::layer::SubscriberExt;
+pub use tracing_subscriber::Registry;
+
+pub use crate::DipstickLayer;
@@ -0,0 +1,125 @@
+//! Recording metrics without going through [`tracing`](https://docs.rs/tracing).
+
+use std::sync::atomic::AtomicI64;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::metric::{Counter, Gauge, Level};
+
+use crate::summary::SummaryCell;
+use crate::{DipstickSink, Inner, MetricPoint, MetricType, Peak, SpanTimer};
+
+/// Records metrics imperatively, bypassing [`tracing`](https://docs.rs/tracing).
+///
+/// Some code runs before the subscriber is installed or outside of any dispatcher, yet its metrics
+/// should end up at the same place as the rest. The recorder is created by
+/// [`DipstickLayer::recorder`][crate::DipstickLayer::recorder] and shares the root scope and the
+/// configuration with the layer. The metrics it records behave as if they came from an event with
+/// the corresponding `metrics.*` attribute (eg. the bare metrics with empty names work the same
+/// way).
+///
+/// # Examples
+///
+/// ```rust
+/// use dipstick::AtomicBucket;
+/// use tracing_dipstick::DipstickLayer;
+///
+/// let bridge = DipstickLayer::new(AtomicBucket::new());
+/// let recorder = bridge.recorder().scope("startup");
+/// // Lands at the same place as `metrics.counter.config_loaded = 1` inside a span with
+/// // `metrics.scope = "startup"`.
+/// recorder.counter("config_loaded", 1);
+/// ```
+#[derive(Clone, Debug)]
+pub struct Recorder<S> {
+    pub(crate) scope: S,
+    pub(crate) inner: Arc<Inner>,
+}
+
+struct Point<'a, S>(&'a S);
+
+impl<S: DipstickSink> MetricPoint for Point<'_, S> {
+    const SCOPED: bool = false;
+    type Scope = S;
+
+    fn push_timer(&mut self, _: &str, _: SpanTimer, _: Option<Instant>) {
+        unreachable!("Timers are not supported by the recorder");
+    }
+
+    fn push_busy_timer(&mut self, _: &str, _: SpanTimer) {
+        unreachable!("Timers are not supported by the recorder");
+    }
+
+    fn push_level(&mut self, _: Level, _: i64, _: Option<Arc<AtomicI64>>) {}
+
+    fn push_counter(&mut self, _: Counter, _: i64) {
+        unreachable!("Counters are undone only on spans");
+    }
+
+    fn push_gauge(&mut self, _: Gauge, _: i64) {}
+
+    fn hold_max_level(&mut self, _: &str, _: Gauge) {
+        unreachable!("Max levels are not supported by the recorder");
+    }
+
+    fn push_peak(&mut self, _: Arc<Peak>, _: Gauge) {
+        unreachable!("Peaks are not supported by the recorder");
+    }
+
+    fn scope(&self) -> &S {
+        self.0
+    }
+
+    fn summary(&self) -> Option<&SummaryCell> {
+        None
+    }
+}
+
+impl<S: DipstickSink> Recorder<S> {
+    fn measure(&self, tp: MetricType, name: &str, value: i64) {
+        if let Some(name) = self.inner.config.metric_name(name, None) {
+            tp.measure(&mut Point(&self.scope), &self.inner, &name, value);
+        }
+    }
+
+    /// Creates a recorder for a nested scope, like `metrics.scope`.
+    pub fn scope(&self, name: &str) -> Self {
+        Recorder {
+            scope: self.scope.add_name(name),
+            inner: Arc::clone(&self.inner),
+        }
+    }
+
+    /// Creates a recorder for a replaced scope, like `metrics.scope.full`.
+    pub fn scope_full(&self, name: &str) -> Self {
+        Recorder {
+            scope: self.scope.named(name),
+            inner: Arc::clone(&self.inner),
+        }
+    }
+
+    /// Adds to a counter, like `metrics.counter.name = value`.
+    pub fn counter(&self, name: &str, value: i64) {
+        self.measure(MetricType::Counter, name, value);
+    }
+
+    /// Marks an occurrence, like `metrics.marker = "name"`.
+    pub fn marker(&self, name: &str) {
+        self.measure(MetricType::Marker, name, 1);
+    }
+
+    /// Sets a gauge, like `metrics.gauge.name = value`.
+    pub fn gauge(&self, name: &str, value: i64) {
+        self.measure(MetricType::Gauge, name, value);
+    }
+
+    /// Adjusts a gauge, like `metrics.gauge.delta.name = value`.
+    pub fn gauge_delta(&self, name: &str, value: i64) {
+        self.measure(MetricType::GaugeDelta, name, value);
+    }
+
+    /// Adjusts a level, like `metrics.level.name = value` on an event.
+    pub fn level(&self, name: &str, value: i64) {
+        self.measure(MetricType::Level, name, value);
+    }
+}
@@ -0,0 +1,540 @@
+//! The abstraction of the metric operations the layer needs.
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::io::Result as IoResult;
+#[cfg(feature = "dipstick")]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+#[cfg(feature = "dipstick")]
+use dipstick::{Flush, InputScope, Prefixed};
+
+use crate::metric::{Counter, Gauge, Level, Marker, NameParts, Timer};
+
+#[cfg(feature = "metrics")]
+pub(crate) mod metrics_rs;
+#[cfg(feature = "opentelemetry")]
+pub(crate) mod opentelemetry;
+
+/// The scope the [`DipstickLayer`][crate::DipstickLayer] puts the metrics into.
+///
+/// This is the small subset of [`dipstick`]'s [`InputScope`] and [`Prefixed`] the layer actually
+/// uses. It is implemented for everything that implements these (with the right additional
+/// bounds), so usually there's no need to care about this trait at all.
+///
+/// It can however be implemented for custom types, for example wrappers around other scopes or
+/// scopes that don't provide the full [`dipstick`] traits. Note that the methods share names with
+/// the ones on the [`dipstick`] traits, so they may need to be called in the fully qualified form.
+///
+/// The trait itself doesn't need [`dipstick`], it works with the handles from the
+/// [`metric`][crate::metric] module. Without the `dipstick` feature, it's the only way to provide
+/// a backend (also available under the backend-neutral name [`MetricSink`][crate::MetricSink]).
+///
+/// # Examples
+///
+/// ```rust
+/// use dipstick::{AtomicBucket, Counter, Gauge, InputScope, Level, Marker, NameParts, Prefixed, Timer};
+/// use tracing_dipstick::{DipstickLayer, DipstickSink};
+///
+/// /// Puts all the metrics into one bucket, with lowercased names.
+/// #[derive(Clone)]
+/// struct Lowercase(AtomicBucket);
+///
+/// impl DipstickSink for Lowercase {
+///     fn counter(&self, name: &str) -> Counter {
+///         InputScope::counter(&self.0, &name.to_lowercase())
+///     }
+///     fn marker(&self, name: &str) -> Marker {
+///         InputScope::marker(&self.0, &name.to_lowercase())
+///     }
+///     fn gauge(&self, name: &str) -> Gauge {
+///         InputScope::gauge(&self.0, &name.to_lowercase())
+///     }
+///     fn level(&self, name: &str) -> Level {
+///         InputScope::level(&self.0, &name.to_lowercase())
+///     }
+///     fn timer(&self, name: &str) -> Timer {
+///         InputScope::timer(&self.0, &name.to_lowercase())
+///     }
+///     fn add_name(&self, name: &str) -> Self {
+///         Lowercase(Prefixed::add_name(&self.0, name.to_lowercase()))
+///     }
+///     fn named(&self, name: &str) -> Self {
+///         Lowercase(Prefixed::named(&self.0, name.to_lowercase()))
+///     }
+///     fn prefixes(&self) -> &NameParts {
+///         Prefixed::get_prefixes(&self.0)
+///     }
+/// }
+///
+/// let bridge = DipstickLayer::new(Lowercase(AtomicBucket::new()));
+/// # drop(bridge);
+/// ```
+pub trait DipstickSink: Clone + Send + Sync + 'static {
+    /// Defines a counter.
+    fn counter(&self, name: &str) -> Counter;
+
+    /// Defines a marker.
+    fn marker(&self, name: &str) -> Marker;
+
+    /// Defines a gauge.
+    fn gauge(&self, name: &str) -> Gauge;
+
+    /// Defines a level.
+    fn level(&self, name: &str) -> Level;
+
+    /// Defines a timer.
+    fn timer(&self, name: &str) -> Timer;
+
+    /// Creates a sub-scope, with the name appended to the current names.
+    ///
+    /// Used for `metrics.scope`.
+    fn add_name(&self, name: &str) -> Self;
+
+    /// Creates a sibling scope, with the current names replaced by the given one.
+    ///
+    /// Used for `metrics.scope.full`.
+    fn named(&self, name: &str) -> Self;
+
+    /// The names the metrics in this scope are prefixed with.
+    fn prefixes(&self) -> &NameParts;
+
+    /// The name of the scope, with the prefixes joined the way the metric names are.
+    ///
+    /// Used by [`DipstickLayer::current_scope_name`][crate::DipstickLayer::current_scope_name].
+    /// By default, the [`prefixes`][DipstickSink::prefixes] are joined by `.`.
+    fn scope_name(&self) -> String {
+        let mut name = String::new();
+        for (i, part) in self.prefixes().iter().enumerate() {
+            if i > 0 {
+                name.push('.');
+            }
+            name.push_str(part);
+        }
+        name
+    }
+
+    /// Does the backend take relative adjustments of gauges?
+    ///
+    /// If so, the adjustments of `metrics.gauge.delta` are sent as they are, through a
+    /// [`level`][DipstickSink::level], and the backend accumulates them. Otherwise the layer keeps
+    /// the running total of each such gauge and sets the gauge to it.
+    ///
+    /// False by default, which includes all the [`dipstick`] outputs. Some of them (eg. statsd)
+    /// write the levels as plain values, without the `+` sign of a relative adjustment, so there's
+    /// no way to send a signed delta through them.
+    fn relative_gauges(&self) -> bool {
+        false
+    }
+
+    /// Sends the buffered metrics out.
+    ///
+    /// The layer calls this whenever the metrics should go out right away:
+    ///
+    /// * From the panic hook of
+    ///   [`DipstickLayerBuilder::flush_on_panic`][crate::DipstickLayerBuilder::flush_on_panic].
+    /// * Once a span with its own scope is closed, with
+    ///   [`DipstickLayerBuilder::flush_on_scope_close`][crate::DipstickLayerBuilder::flush_on_scope_close].
+    /// * Periodically while a span with `metrics.flush_every` is open, see
+    ///   [periodic flushing](crate#periodic-flushing-of-scopes).
+    ///
+    /// It is independent of any flushing scheduled on the output itself, like the one set up by
+    /// [`DipstickLayer::to_stdout`][crate::DipstickLayer::to_stdout]. Does nothing by default.
+    fn flush(&self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "dipstick")]
+impl<S> DipstickSink for S
+where
+    S: Clone + InputScope + Prefixed + Send + Sync + 'static,
+{
+    fn counter(&self, name: &str) -> Counter {
+        InputScope::counter(self, name)
+    }
+
+    fn marker(&self, name: &str) -> Marker {
+        InputScope::marker(self, name)
+    }
+
+    fn gauge(&self, name: &str) -> Gauge {
+        InputScope::gauge(self, name)
+    }
+
+    fn level(&self, name: &str) -> Level {
+        InputScope::level(self, name)
+    }
+
+    fn timer(&self, name: &str) -> Timer {
+        InputScope::timer(self, name)
+    }
+
+    fn add_name(&self, name: &str) -> Self {
+        Prefixed::add_name(self, name)
+    }
+
+    fn named(&self, name: &str) -> Self {
+        Prefixed::named(self, name)
+    }
+
+    fn prefixes(&self) -> &NameParts {
+        self.get_prefixes()
+    }
+
+    fn flush(&self) -> IoResult<()> {
+        Flush::flush(self)
+    }
+}
+
+/// A scope joining the names of nested scopes with a custom separator.
+///
+/// Created by [`DipstickLayerBuilder::scope_separator`][crate::DipstickLayerBuilder::scope_separator].
+/// The outputs of [`dipstick`] join the parts of metric names with their own separator (usually
+/// `.`). This scope therefore doesn't use the [`dipstick`] prefixes for the nested scopes; it joins
+/// the names itself and creates the metrics directly in the wrapped scope, under the full names
+/// (eg. `outer/inner/name`). Any prefixes of the wrapped scope itself are still joined by the
+/// output.
+#[derive(Clone, Debug)]
+pub struct Separated<S> {
+    root: S,
+    separator: char,
+    parts: NameParts,
+}
+
+impl<S> Separated<S> {
+    pub(crate) fn new(root: S, separator: char) -> Self {
+        Separated {
+            root,
+            separator,
+            parts: NameParts::default(),
+        }
+    }
+
+    fn full_name(&self, name: &str) -> String {
+        let mut full = String::new();
+        for part in self.parts.iter() {
+            full.push_str(part);
+            full.push(self.separator);
+        }
+        full.push_str(name);
+        full
+    }
+}
+
+impl<S: DipstickSink> DipstickSink for Separated<S> {
+    fn counter(&self, name: &str) -> Counter {
+        self.root.counter(&self.full_name(name))
+    }
+
+    fn marker(&self, name: &str) -> Marker {
+        self.root.marker(&self.full_name(name))
+    }
+
+    fn gauge(&self, name: &str) -> Gauge {
+        self.root.gauge(&self.full_name(name))
+    }
+
+    fn level(&self, name: &str) -> Level {
+        self.root.level(&self.full_name(name))
+    }
+
+    fn timer(&self, name: &str) -> Timer {
+        self.root.timer(&self.full_name(name))
+    }
+
+    fn relative_gauges(&self) -> bool {
+        self.root.relative_gauges()
+    }
+
+    fn add_name(&self, name: &str) -> Self {
+        let mut sub = self.clone();
+        sub.parts.push_back(name.to_owned());
+        sub
+    }
+
+    fn named(&self, name: &str) -> Self {
+        let mut sibling = self.clone();
+        sibling.parts.clear();
+        sibling.parts.push_back(name.to_owned());
+        sibling
+    }
+
+    fn prefixes(&self) -> &NameParts {
+        &self.parts
+    }
+
+    fn scope_name(&self) -> String {
+        // The root joins its own prefixes (and the whole name passed to it) the usual way
+        let mut name = self.root.scope_name();
+        for (i, part) in self.parts.iter().enumerate() {
+            match i {
+                0 if name.is_empty() => (),
+                0 => name.push('.'),
+                _ => name.push(self.separator),
+            }
+            name.push_str(part);
+        }
+        name
+    }
+
+    fn flush(&self) -> IoResult<()> {
+        self.root.flush()
+    }
+}
+
+/// A scope ignoring the scope names of spans.
+///
+/// Created by [`DipstickLayer::new_flat`][crate::DipstickLayer::new_flat]. This allows using an
+/// [`InputScope`] that doesn't implement [`Prefixed`]. The price is that all the metrics end up
+/// directly in the wrapped scope; the `metrics.scope` (and related) attributes are ignored, with a
+/// warning emitted through [`tracing`](https://docs.rs/tracing) the first time it happens.
+///
+/// Metrics of the same name from differently scoped spans are therefore merged together.
+///
+/// Available with the `dipstick` feature.
+#[cfg(feature = "dipstick")]
+#[derive(Clone, Debug)]
+pub struct Flat<S> {
+    root: S,
+    warned: Arc<AtomicBool>,
+    parts: NameParts,
+}
+
+#[cfg(feature = "dipstick")]
+impl<S> Flat<S> {
+    /// Wraps the scope.
+    ///
+    /// Usually there's no need to call this directly, see
+    /// [`DipstickLayer::new_flat`][crate::DipstickLayer::new_flat]. This is useful for
+    /// [`DipstickLayer::builder`][crate::DipstickLayer::builder].
+    pub fn new(root: S) -> Self {
+        Flat {
+            root,
+            warned: Arc::new(AtomicBool::new(false)),
+            parts: NameParts::default(),
+        }
+    }
+
+    fn ignore(&self, name: &str) -> Self
+    where
+        S: Clone,
+    {
+        if !self.warned.swap(true, Ordering::Relaxed) {
+            tracing::warn!(
+                "Ignoring metrics scope {}, the layer was created without scope support",
+                name
+            );
+        }
+        self.clone()
+    }
+}
+
+#[cfg(feature = "dipstick")]
+impl<S> DipstickSink for Flat<S>
+where
+    S: Clone + InputScope + Send + Sync + 'static,
+{
+    fn counter(&self, name: &str) -> Counter {
+        self.root.counter(name)
+    }
+
+    fn marker(&self, name: &str) -> Marker {
+        self.root.marker(name)
+    }
+
+    fn gauge(&self, name: &str) -> Gauge {
+        self.root.gauge(name)
+    }
+
+    fn level(&self, name: &str) -> Level {
+        self.root.level(name)
+    }
+
+    fn timer(&self, name: &str) -> Timer {
+        self.root.timer(name)
+    }
+
+    fn add_name(&self, name: &str) -> Self {
+        self.ignore(name)
+    }
+
+    fn named(&self, name: &str) -> Self {
+        self.ignore(name)
+    }
+
+    fn prefixes(&self) -> &NameParts {
+        // Always empty
+        &self.parts
+    }
+
+    fn flush(&self) -> IoResult<()> {
+        Flush::flush(&self.root)
+    }
+}
+
+/// The object-safe part of [`DipstickSink`], for [`DynScope`].
+trait ErasedSink: Send + Sync + 'static {
+    fn counter(&self, name: &str) -> Counter;
+    fn marker(&self, name: &str) -> Marker;
+    fn gauge(&self, name: &str) -> Gauge;
+    fn level(&self, name: &str) -> Level;
+    fn timer(&self, name: &str) -> Timer;
+    fn add_name(&self, name: &str) -> Arc<dyn ErasedSink>;
+    fn named(&self, name: &str) -> Arc<dyn ErasedSink>;
+    fn prefixes(&self) -> &NameParts;
+    fn scope_name(&self) -> String;
+    fn relative_gauges(&self) -> bool;
+    fn flush(&self) -> IoResult<()>;
+}
+
+impl<S: DipstickSink> ErasedSink for S {
+    fn counter(&self, name: &str) -> Counter {
+        DipstickSink::counter(self, name)
+    }
+    fn marker(&self, name: &str) -> Marker {
+        DipstickSink::marker(self, name)
+    }
+    fn gauge(&self, name: &str) -> Gauge {
+        DipstickSink::gauge(self, name)
+    }
+    fn level(&self, name: &str) -> Level {
+        DipstickSink::level(self, name)
+    }
+    fn timer(&self, name: &str) -> Timer {
+        DipstickSink::timer(self, name)
+    }
+    fn add_name(&self, name: &str) -> Arc<dyn ErasedSink> {
+        Arc::new(DipstickSink::add_name(self, name))
+    }
+    fn named(&self, name: &str) -> Arc<dyn ErasedSink> {
+        Arc::new(DipstickSink::named(self, name))
+    }
+    fn prefixes(&self) -> &NameParts {
+        DipstickSink::prefixes(self)
+    }
+    fn scope_name(&self) -> String {
+        DipstickSink::scope_name(self)
+    }
+    fn relative_gauges(&self) -> bool {
+        DipstickSink::relative_gauges(self)
+    }
+    fn flush(&self) -> IoResult<()> {
+        DipstickSink::flush(self)
+    }
+}
+
+/// A type-erased scope, for choosing the backend at runtime.
+///
+/// The layer is generic over its scope, which makes it awkward to store when the actual backend
+/// is decided only at runtime (eg. by the configuration). Wrapping the scope into this makes the
+/// layer a plain `DipstickLayer<DynScope>`, whatever the backend is.
+///
+/// The price is a dynamic dispatch on each created metric and an allocation for each created
+/// scope (eg. a span with `metrics.scope`). This is small compared to the rest of the work done
+/// for each metric, but it is there.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[cfg(feature = "testing")] {
+/// use dipstick::{AtomicBucket, Stream};
+/// use tracing_dipstick::{DipstickLayer, DynScope};
+/// use tracing_dipstick::testing::RecordingScope;
+/// use tracing_subscriber::layer::SubscriberExt;
+/// use tracing_subscriber::Registry;
+///
+/// struct App {
+///     metrics: DipstickLayer<DynScope>,
+/// }
+///
+/// fn backend(name: &str) -> DynScope {
+///     match name {
+///         "stdout" => {
+///             let bucket = AtomicBucket::new();
+///             bucket.drain(Stream::write_to_stdout());
+///             DynScope::new(bucket)
+///         }
+///         _ => DynScope::new(AtomicBucket::new()),
+///     }
+/// }
+///
+/// let recording = RecordingScope::new();
+/// let app = App {
+///     metrics: DipstickLayer::new(DynScope::new(recording.clone())),
+/// };
+/// let other = App {
+///     metrics: DipstickLayer::new(backend("bucket")),
+/// };
+/// # drop(other);
+/// let subscriber = Registry::default().with(app.metrics);
+/// tracing::subscriber::with_default(subscriber, || {
+///     let _span = tracing::info_span!("db", metrics.scope = "db").entered();
+///     tracing::info!(metrics.counter.queries = 2);
+/// });
+/// assert_eq!(vec![2], recording.values("db.queries"));
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct DynScope(Arc<dyn ErasedSink>);
+
+impl DynScope {
+    /// Wraps the scope.
+    pub fn new<S: DipstickSink>(scope: S) -> Self {
+        DynScope(Arc::new(scope))
+    }
+}
+
+impl Debug for DynScope {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_tuple("DynScope")
+            .field(&self.0.scope_name())
+            .finish()
+    }
+}
+
+impl DipstickSink for DynScope {
+    fn counter(&self, name: &str) -> Counter {
+        self.0.counter(name)
+    }
+
+    fn marker(&self, name: &str) -> Marker {
+        self.0.marker(name)
+    }
+
+    fn gauge(&self, name: &str) -> Gauge {
+        self.0.gauge(name)
+    }
+
+    fn level(&self, name: &str) -> Level {
+        self.0.level(name)
+    }
+
+    fn timer(&self, name: &str) -> Timer {
+        self.0.timer(name)
+    }
+
+    fn add_name(&self, name: &str) -> Self {
+        DynScope(self.0.add_name(name))
+    }
+
+    fn named(&self, name: &str) -> Self {
+        DynScope(self.0.named(name))
+    }
+
+    fn prefixes(&self) -> &NameParts {
+        self.0.prefixes()
+    }
+
+    fn scope_name(&self) -> String {
+        self.0.scope_name()
+    }
+
+    fn relative_gauges(&self) -> bool {
+        self.0.relative_gauges()
+    }
+
+    fn flush(&self) -> IoResult<()> {
+        self.0.flush()
+    }
+}
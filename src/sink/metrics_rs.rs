@@ -0,0 +1,209 @@
+//! Routing the metrics into the [`metrics`] crate facade.
+
+use crate::metric::{input_metric, Counter, Gauge, InputMetric, Level, Marker, NameParts, Timer};
+
+use super::DipstickSink;
+
+/// A scope sending the metrics to the [`metrics`] crate instead of [`dipstick`].
+///
+/// This allows driving the metrics of the [`metrics`] ecosystem by the same `metrics.*`
+/// attributes. The metrics go to the recorder installed in the [`metrics`] crate at the time they
+/// are recorded. The scopes are joined into the names by `.`, eg. `outer.inner.name`.
+///
+/// The kinds of metrics map as follows:
+///
+/// * Counters become [`metrics::counter!`], incremented by the value.
+/// * Markers become [`metrics::counter!`] too, incremented by 1.
+/// * Gauges become [`metrics::gauge!`], set to the value.
+/// * Levels become [`metrics::gauge!`] too, but they are incremented or decremented by the value
+///   instead of being set.
+/// * Timers (and distributions) become [`metrics::histogram!`]. The values are recorded as the
+///   layer produces them, that is in microseconds by default (see
+///   [`TimerUnit`][crate::TimerUnit]), not in the seconds the [`metrics`] crate usually uses for
+///   durations.
+///
+/// Available with the `metrics` feature.
+///
+/// # Examples
+///
+/// ```rust
+/// use std::sync::{Arc, Mutex};
+///
+/// use metrics::{
+///     Counter, CounterFn, Gauge, GaugeFn, Histogram, HistogramFn, Key, KeyName, Metadata,
+///     Recorder, SharedString, Unit,
+/// };
+/// use tracing::subscriber;
+/// use tracing_dipstick::{DipstickLayer, MetricsRs};
+/// use tracing_subscriber::layer::SubscriberExt;
+/// use tracing_subscriber::Registry;
+///
+/// type Log = Arc<Mutex<Vec<(String, &'static str, f64)>>>;
+///
+/// /// Logs everything that happens to the metrics.
+/// #[derive(Default)]
+/// struct Recording(Log);
+///
+/// struct Handle(String, Log);
+///
+/// impl Handle {
+///     fn log(&self, op: &'static str, value: f64) {
+///         self.1.lock().unwrap().push((self.0.clone(), op, value));
+///     }
+/// }
+///
+/// impl CounterFn for Handle {
+///     fn increment(&self, value: u64) {
+///         self.log("increment", value as f64);
+///     }
+///     fn absolute(&self, value: u64) {
+///         self.log("absolute", value as f64);
+///     }
+/// }
+///
+/// impl GaugeFn for Handle {
+///     fn increment(&self, value: f64) {
+///         self.log("increment", value);
+///     }
+///     fn decrement(&self, value: f64) {
+///         self.log("decrement", value);
+///     }
+///     fn set(&self, value: f64) {
+///         self.log("set", value);
+///     }
+/// }
+///
+/// impl HistogramFn for Handle {
+///     fn record(&self, value: f64) {
+///         self.log("record", value);
+///     }
+/// }
+///
+/// impl Recording {
+///     fn handle(&self, key: &Key) -> Arc<Handle> {
+///         Arc::new(Handle(key.name().to_owned(), Arc::clone(&self.0)))
+///     }
+/// }
+///
+/// impl Recorder for Recording {
+///     fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+///     fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+///     fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+///     fn register_counter(&self, key: &Key, _: &Metadata) -> Counter {
+///         Counter::from_arc(self.handle(key))
+///     }
+///     fn register_gauge(&self, key: &Key, _: &Metadata) -> Gauge {
+///         Gauge::from_arc(self.handle(key))
+///     }
+///     fn register_histogram(&self, key: &Key, _: &Metadata) -> Histogram {
+///         Histogram::from_arc(self.handle(key))
+///     }
+/// }
+///
+/// let recording = Recording::default();
+/// let bridge = DipstickLayer::new(MetricsRs::new());
+/// metrics::with_local_recorder(&recording, || {
+///     subscriber::with_default(Registry::default().with(bridge), || {
+///         let _span = tracing::info_span!("request", metrics.scope = "request").entered();
+///         tracing::info!(metrics.counter.bytes = 42, metrics.gauge.size = 3);
+///         tracing::info!(metrics.level.queue = -2, metrics.timer.io = 5_000);
+///     });
+/// });
+/// let log = recording.0.lock().unwrap();
+/// let expected = [
+///     ("request.bytes".to_owned(), "increment", 42.0),
+///     ("request.size".to_owned(), "set", 3.0),
+///     ("request.queue".to_owned(), "decrement", 2.0),
+///     // Nanoseconds in, microseconds out
+///     ("request.io".to_owned(), "record", 5.0),
+/// ];
+/// assert_eq!(expected.as_slice(), log.as_slice());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct MetricsRs {
+    parts: NameParts,
+}
+
+impl MetricsRs {
+    /// Creates the root scope, without any prefix.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn full_name(&self, name: &str) -> String {
+        let mut full = String::new();
+        for part in self.parts.iter() {
+            full.push_str(part);
+            full.push('.');
+        }
+        full.push_str(name);
+        full
+    }
+
+    fn metric<F>(&self, name: &str, write: F) -> InputMetric
+    where
+        F: Fn(isize) + Send + Sync + 'static,
+    {
+        input_metric(name, move |value, _labels| write(value))
+    }
+}
+
+impl DipstickSink for MetricsRs {
+    fn counter(&self, name: &str) -> Counter {
+        let counter = metrics::counter!(self.full_name(name));
+        // Counters can't be negative, they take usize
+        self.metric(name, move |value| counter.increment(value as u64))
+            .into()
+    }
+
+    fn marker(&self, name: &str) -> Marker {
+        let counter = metrics::counter!(self.full_name(name));
+        self.metric(name, move |_| counter.increment(1)).into()
+    }
+
+    fn gauge(&self, name: &str) -> Gauge {
+        let gauge = metrics::gauge!(self.full_name(name));
+        self.metric(name, move |value| gauge.set(value as f64))
+            .into()
+    }
+
+    fn level(&self, name: &str) -> Level {
+        let gauge = metrics::gauge!(self.full_name(name));
+        self.metric(name, move |value| {
+            if value < 0 {
+                gauge.decrement(value.unsigned_abs() as f64);
+            } else {
+                gauge.increment(value as f64);
+            }
+        })
+        .into()
+    }
+
+    fn timer(&self, name: &str) -> Timer {
+        let histogram = metrics::histogram!(self.full_name(name));
+        self.metric(name, move |value| histogram.record(value as f64))
+            .into()
+    }
+
+    fn add_name(&self, name: &str) -> Self {
+        let mut sub = self.clone();
+        sub.parts.push_back(name.to_owned());
+        sub
+    }
+
+    fn named(&self, name: &str) -> Self {
+        let mut sibling = self.clone();
+        sibling.parts.clear();
+        sibling.parts.push_back(name.to_owned());
+        sibling
+    }
+
+    fn prefixes(&self) -> &NameParts {
+        &self.parts
+    }
+
+    fn relative_gauges(&self) -> bool {
+        // The levels are increments and decrements of gauges
+        true
+    }
+}
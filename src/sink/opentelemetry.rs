@@ -0,0 +1,192 @@
+//! Routing the metrics into the instruments of an [OpenTelemetry](https://docs.rs/opentelemetry)
+//! meter.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use ::opentelemetry::metrics::Meter;
+
+use super::DipstickSink;
+use crate::metric::{
+    input_metric, Counter, Gauge, InputKind, InputMetric, Level, Marker, NameParts, Timer,
+};
+
+/// A scope recording the metrics into the instruments of an OpenTelemetry [`Meter`].
+///
+/// This lets the `metrics.*` attributes drive OpenTelemetry metrics, exported by whatever reader
+/// and exporter the meter provider is set up with. The scopes are joined into the instrument
+/// names by `.`, eg. `outer.inner.name` (the [tags](crate#tags) become part of the names as
+/// usual, not attributes).
+///
+/// The kinds of metrics map as follows:
+///
+/// * Counters become `Counter<u64>`, incremented by the value. OpenTelemetry counters can't go
+///   down, so negative amounts are dropped (this matters for
+///   [`undo_on_close`][crate::DipstickLayerBuilder::undo_on_close] of counters, which doesn't
+///   work here).
+/// * Markers become `Counter<u64>` too, incremented by 1.
+/// * Gauges become `Gauge<i64>`, set to the value.
+/// * Levels become `UpDownCounter<i64>`, as they are sent as adjustments.
+/// * Timers (and distributions) become `Histogram<u64>`. The values are recorded as the layer
+///   produces them, in microseconds by default (see [`TimerUnit`][crate::TimerUnit]), and the
+///   histograms have no unit set. The labels of the values (eg. the one of
+///   [`timer_sequence`][crate::DipstickLayerBuilder::timer_sequence]) are not passed on.
+///
+/// The instruments are created once for each full name and kind, and shared by the clones and
+/// sub-scopes. Names that OpenTelemetry doesn't accept (eg. longer than 255 characters) end up in
+/// no-op instruments, as the meter decides.
+///
+/// Available with the `opentelemetry` feature.
+///
+/// # Examples
+///
+/// ```rust
+/// use opentelemetry::metrics::MeterProvider;
+/// use opentelemetry_sdk::metrics::data::{AggregatedMetrics, MetricData};
+/// use opentelemetry_sdk::metrics::{InMemoryMetricExporter, PeriodicReader, SdkMeterProvider};
+/// use tracing::subscriber;
+/// use tracing_dipstick::{DipstickLayer, OpenTelemetry};
+/// use tracing_subscriber::layer::SubscriberExt;
+/// use tracing_subscriber::Registry;
+///
+/// let exporter = InMemoryMetricExporter::default();
+/// let provider = SdkMeterProvider::builder()
+///     .with_reader(PeriodicReader::builder(exporter.clone()).build())
+///     .build();
+/// let bridge = DipstickLayer::new(OpenTelemetry::new(provider.meter("app")));
+/// subscriber::with_default(Registry::default().with(bridge), || {
+///     let _span = tracing::info_span!("request", metrics.scope = "request").entered();
+///     tracing::info!(metrics.counter.bytes = 42);
+///     tracing::info!(metrics.counter.bytes = 8);
+/// });
+/// provider.force_flush().unwrap();
+///
+/// let exported = exporter.get_finished_metrics().unwrap();
+/// let metric = exported
+///     .iter()
+///     .flat_map(|resource| resource.scope_metrics())
+///     .flat_map(|scope| scope.metrics())
+///     .find(|metric| metric.name() == "request.bytes")
+///     .unwrap();
+/// match metric.data() {
+///     AggregatedMetrics::U64(MetricData::Sum(sum)) => {
+///         let total: u64 = sum.data_points().map(|point| point.value()).sum();
+///         assert_eq!(50, total);
+///     }
+///     other => panic!("Unexpected data {:?}", other),
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct OpenTelemetry {
+    meter: Meter,
+    parts: NameParts,
+    /// The created instruments, by their kinds and full names.
+    instruments: Arc<Mutex<HashMap<(InputKind, String), InputMetric>>>,
+}
+
+impl OpenTelemetry {
+    /// Creates the root scope, without any prefix.
+    pub fn new(meter: Meter) -> Self {
+        OpenTelemetry {
+            meter,
+            parts: NameParts::default(),
+            instruments: Arc::default(),
+        }
+    }
+
+    fn full_name(&self, name: &str) -> String {
+        let mut full = String::new();
+        for part in self.parts.iter() {
+            full.push_str(part);
+            full.push('.');
+        }
+        full.push_str(name);
+        full
+    }
+
+    /// Looks the instrument up, or creates it by the closure from the meter and the full name.
+    fn metric<C, W>(&self, kind: InputKind, name: &str, create: C) -> InputMetric
+    where
+        C: FnOnce(&Meter, String) -> W,
+        W: Fn(isize) + Send + Sync + 'static,
+    {
+        let full = self.full_name(name);
+        let mut instruments = self.instruments.lock().unwrap_or_else(|e| e.into_inner());
+        instruments
+            .entry((kind, full.clone()))
+            .or_insert_with(|| {
+                let write = create(&self.meter, full);
+                input_metric(name, move |value, _labels| write(value))
+            })
+            .clone()
+    }
+}
+
+impl DipstickSink for OpenTelemetry {
+    fn counter(&self, name: &str) -> Counter {
+        self.metric(InputKind::Counter, name, |meter, name| {
+            let counter = meter.u64_counter(name).build();
+            move |value| {
+                // Counters can't go down
+                if let Ok(value) = u64::try_from(value) {
+                    counter.add(value, &[]);
+                }
+            }
+        })
+        .into()
+    }
+
+    fn marker(&self, name: &str) -> Marker {
+        self.metric(InputKind::Marker, name, |meter, name| {
+            let counter = meter.u64_counter(name).build();
+            move |_| counter.add(1, &[])
+        })
+        .into()
+    }
+
+    fn gauge(&self, name: &str) -> Gauge {
+        self.metric(InputKind::Gauge, name, |meter, name| {
+            let gauge = meter.i64_gauge(name).build();
+            move |value| gauge.record(value as i64, &[])
+        })
+        .into()
+    }
+
+    fn level(&self, name: &str) -> Level {
+        self.metric(InputKind::Level, name, |meter, name| {
+            let counter = meter.i64_up_down_counter(name).build();
+            move |value| counter.add(value as i64, &[])
+        })
+        .into()
+    }
+
+    fn timer(&self, name: &str) -> Timer {
+        self.metric(InputKind::Timer, name, |meter, name| {
+            let histogram = meter.u64_histogram(name).build();
+            move |value| histogram.record(value.max(0) as u64, &[])
+        })
+        .into()
+    }
+
+    fn add_name(&self, name: &str) -> Self {
+        let mut sub = self.clone();
+        sub.parts.push_back(name.to_owned());
+        sub
+    }
+
+    fn named(&self, name: &str) -> Self {
+        let mut sibling = self.clone();
+        sibling.parts.clear();
+        sibling.parts.push_back(name.to_owned());
+        sibling
+    }
+
+    fn prefixes(&self) -> &NameParts {
+        &self.parts
+    }
+
+    fn relative_gauges(&self) -> bool {
+        // The levels are up-down counters
+        true
+    }
+}
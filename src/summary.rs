@@ -0,0 +1,106 @@
+//! Per-span summaries of the metrics recorded inside the span's subtree.
+
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::drop_guarded;
+
+/// The callback receiving finished summaries.
+#[derive(Clone)]
+pub(crate) struct SummaryHook(pub(crate) Arc<dyn Fn(&Summary) + Send + Sync>);
+
+impl Debug for SummaryHook {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.write_str("SummaryHook")
+    }
+}
+
+/// Metric totals collected within a subtree of spans.
+///
+/// Collected for spans marked with `metrics.summary = true` and passed to the hook set through
+/// [`DipstickLayerBuilder::on_summary`][crate::DipstickLayerBuilder::on_summary]. It contains the
+/// counters and timers recorded by the span itself and all its descendants (up to the nearest
+/// descendant that is a summary root on its own).
+///
+/// The metrics are keyed by their names as written in the attributes, without the prefixes from
+/// `metrics.scope`.
+#[derive(Clone, Debug)]
+pub struct Summary {
+    span_name: &'static str,
+    counters: HashMap<String, i64>,
+    timers: HashMap<String, (u64, Duration)>,
+}
+
+impl Summary {
+    /// The name of the span this summary belongs to.
+    pub fn span_name(&self) -> &'static str {
+        self.span_name
+    }
+
+    /// The total of a counter, 0 if it was not recorded at all.
+    pub fn counter(&self, name: &str) -> i64 {
+        self.counters.get(name).copied().unwrap_or_default()
+    }
+
+    /// The number of observations and their total duration of a timer.
+    pub fn timer(&self, name: &str) -> (u64, Duration) {
+        self.timers.get(name).copied().unwrap_or_default()
+    }
+
+    /// Iterates through all the recorded counters.
+    pub fn counters(&self) -> impl Iterator<Item = (&str, i64)> {
+        self.counters.iter().map(|(n, v)| (n.as_str(), *v))
+    }
+
+    /// Iterates through all the recorded timers, with their observation counts and totals.
+    pub fn timers(&self) -> impl Iterator<Item = (&str, u64, Duration)> {
+        self.timers.iter().map(|(n, (c, t))| (n.as_str(), *c, *t))
+    }
+}
+
+/// The shared accumulator of a summary.
+///
+/// Held by all the spans in the subtree. It reports the summary once the last of them goes away.
+pub(crate) struct SummaryCell {
+    summary: Mutex<Summary>,
+    hook: SummaryHook,
+}
+
+impl SummaryCell {
+    pub(crate) fn new(span_name: &'static str, hook: SummaryHook) -> Self {
+        SummaryCell {
+            summary: Mutex::new(Summary {
+                span_name,
+                counters: HashMap::new(),
+                timers: HashMap::new(),
+            }),
+            hook,
+        }
+    }
+
+    pub(crate) fn count(&self, name: &str, value: i64) {
+        let mut summary = self.summary.lock().unwrap_or_else(|e| e.into_inner());
+        match summary.counters.get_mut(name) {
+            Some(total) => *total = total.saturating_add(value),
+            None => {
+                summary.counters.insert(name.to_owned(), value);
+            }
+        }
+    }
+
+    pub(crate) fn time(&self, name: &str, elapsed: Duration) {
+        let mut summary = self.summary.lock().unwrap_or_else(|e| e.into_inner());
+        let (count, total) = summary.timers.entry(name.to_owned()).or_default();
+        *count += 1;
+        *total += elapsed;
+    }
+}
+
+impl Drop for SummaryCell {
+    fn drop(&mut self) {
+        let summary = self.summary.get_mut().unwrap_or_else(|e| e.into_inner());
+        drop_guarded(|| (self.hook.0)(summary));
+    }
+}
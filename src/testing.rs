@@ -0,0 +1,354 @@
+//! Helpers for testing instrumented code.
+//!
+//! Available with the `testing` feature. The [`RecordingScope`] captures everything that would
+//! otherwise be sent to a real [`dipstick`] output, so tests can make assertions about it.
+
+use std::io::Result as IoResult;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "dipstick")]
+use dipstick::{Attributes, Flush, InputScope, MetricName, Prefixed, WithAttributes};
+use tracing::subscriber;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Registry;
+
+use crate::metric::{input_metric, InputKind, InputMetric};
+#[cfg(not(feature = "dipstick"))]
+use crate::metric::{Counter, Gauge, Level, Marker, NameParts, Timer};
+#[cfg(not(feature = "dipstick"))]
+use crate::DipstickSink;
+use crate::{Clock, DipstickLayer};
+
+/// A single value recorded into a [`RecordingScope`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct MetricEvent {
+    /// The full name of the metric, with all the prefixes, joined by `.`.
+    pub name: String,
+    /// The kind of the metric.
+    pub kind: InputKind,
+    /// The recorded value (microseconds in case of timers).
+    pub value: isize,
+}
+
+/// An in-memory scope remembering everything recorded into it.
+///
+/// Clones (including the ones with `add_name`) share the recorded events. With the `dipstick`
+/// feature, it is a full [`dipstick`] `InputScope`, otherwise only a
+/// [`DipstickSink`][crate::DipstickSink].
+///
+/// # Examples
+///
+/// ```rust
+/// use tracing::subscriber;
+/// use tracing_dipstick::DipstickLayer;
+/// use tracing_dipstick::testing::RecordingScope;
+/// use tracing_subscriber::layer::SubscriberExt;
+/// use tracing_subscriber::Registry;
+///
+/// let recording = RecordingScope::new();
+/// let subscriber = Registry::default().with(DipstickLayer::new(recording.clone()));
+/// subscriber::with_default(subscriber, || {
+///     tracing::info!(metrics.counter = "hits");
+/// });
+/// assert_eq!(1, recording.events().len());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct RecordingScope {
+    #[cfg(feature = "dipstick")]
+    attributes: Attributes,
+    #[cfg(not(feature = "dipstick"))]
+    prefixes: NameParts,
+    events: Arc<Mutex<Vec<MetricEvent>>>,
+    flushes: Arc<AtomicUsize>,
+}
+
+impl RecordingScope {
+    /// Creates a new empty scope.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// All the values recorded so far, in the order they were recorded.
+    pub fn events(&self) -> Vec<MetricEvent> {
+        self.events
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// The values recorded into the metric with the given full name, in the order they were
+    /// recorded.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use tracing_dipstick::testing::with_test_layer;
+    ///
+    /// with_test_layer(|recording| {
+    ///     let _span = tracing::info_span!("db", metrics.scope = "db").entered();
+    ///     tracing::info!(metrics.counter.queries = 2);
+    ///     tracing::info!(metrics.gauge.connections = 5);
+    ///     tracing::info!(metrics.counter.queries = 3);
+    ///     assert_eq!(vec![2, 3], recording.values("db.queries"));
+    /// });
+    /// ```
+    pub fn values(&self, name: &str) -> Vec<isize> {
+        self.events
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .filter(|event| event.name == name)
+            .map(|event| event.value)
+            .collect()
+    }
+
+    /// Forgets all the recorded values.
+    pub fn clear(&self) {
+        self.events
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+    }
+
+    /// How many times the scope was flushed.
+    pub fn flush_count(&self) -> usize {
+        self.flushes.load(Ordering::Relaxed)
+    }
+
+    fn record(&self, full_name: String, kind: InputKind) -> InputMetric {
+        let events = Arc::clone(&self.events);
+        let id = full_name.clone();
+        input_metric(&id, move |value, _labels| {
+            events
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(MetricEvent {
+                    name: full_name.clone(),
+                    kind,
+                    value,
+                });
+        })
+    }
+
+    fn record_flush(&self) {
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(feature = "dipstick")]
+impl WithAttributes for RecordingScope {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+#[cfg(feature = "dipstick")]
+impl Flush for RecordingScope {
+    fn flush(&self) -> IoResult<()> {
+        self.record_flush();
+        Ok(())
+    }
+}
+
+#[cfg(feature = "dipstick")]
+impl InputScope for RecordingScope {
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        self.record(self.prefix_prepend(name).join("."), kind)
+    }
+}
+
+#[cfg(not(feature = "dipstick"))]
+impl RecordingScope {
+    fn full_name(&self, name: &str) -> String {
+        let mut full = String::new();
+        for part in self.prefixes.iter() {
+            full.push_str(part);
+            full.push('.');
+        }
+        full.push_str(name);
+        full
+    }
+}
+
+#[cfg(not(feature = "dipstick"))]
+impl DipstickSink for RecordingScope {
+    fn counter(&self, name: &str) -> Counter {
+        self.record(self.full_name(name), InputKind::Counter).into()
+    }
+    fn marker(&self, name: &str) -> Marker {
+        self.record(self.full_name(name), InputKind::Marker).into()
+    }
+    fn gauge(&self, name: &str) -> Gauge {
+        self.record(self.full_name(name), InputKind::Gauge).into()
+    }
+    fn level(&self, name: &str) -> Level {
+        self.record(self.full_name(name), InputKind::Level).into()
+    }
+    fn timer(&self, name: &str) -> Timer {
+        self.record(self.full_name(name), InputKind::Timer).into()
+    }
+    fn add_name(&self, name: &str) -> Self {
+        let mut sub = self.clone();
+        sub.prefixes.push_back(name.to_owned());
+        sub
+    }
+    fn named(&self, name: &str) -> Self {
+        let mut sibling = self.clone();
+        sibling.prefixes = NameParts::from(name);
+        sibling
+    }
+    fn prefixes(&self) -> &NameParts {
+        &self.prefixes
+    }
+    fn flush(&self) -> IoResult<()> {
+        self.record_flush();
+        Ok(())
+    }
+}
+
+/// A [`Clock`] that moves only when told to.
+///
+/// Clones share the time. Set through [`DipstickLayerBuilder::clock`][crate::DipstickLayerBuilder::clock],
+/// it makes the timers and the periodic work of the layer (see [`DipstickLayer::tick`])
+/// deterministic.
+///
+/// # Examples
+///
+/// ```rust
+/// use std::time::Duration;
+///
+/// use tracing::subscriber;
+/// use tracing_dipstick::DipstickLayer;
+/// use tracing_dipstick::testing::{ManualClock, RecordingScope};
+/// use tracing_subscriber::layer::SubscriberExt;
+/// use tracing_subscriber::Registry;
+///
+/// let clock = ManualClock::new();
+/// let recording = RecordingScope::new();
+/// let bridge = DipstickLayer::builder(recording.clone())
+///     .clock(clock.clone())
+///     .finish();
+/// subscriber::with_default(Registry::default().with(bridge), || {
+///     let _span = tracing::info_span!("work", metrics.timer = "work").entered();
+///     clock.advance(Duration::from_millis(3));
+/// });
+/// // In microseconds
+/// assert_eq!(vec![3000], recording.values("work"));
+/// ```
+#[derive(Clone, Debug)]
+pub struct ManualClock(Arc<Mutex<Instant>>);
+
+impl ManualClock {
+    /// Creates the clock, starting at the current time.
+    pub fn new() -> Self {
+        ManualClock(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    /// Moves the time forward.
+    pub fn advance(&self, by: Duration) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) += by;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Makes the [sampling][crate::DipstickLayerBuilder::sample_rate] on the current thread
+/// deterministic.
+///
+/// The random decisions of the sampling follow a sequence of each thread, seeded randomly. This
+/// restarts the sequence of the current thread from the given seed, so the same spans and events
+/// get sampled in each run of a test.
+///
+/// # Examples
+///
+/// ```rust
+/// use tracing::subscriber;
+/// use tracing_dipstick::DipstickLayer;
+/// use tracing_dipstick::testing::{seed_sampling, RecordingScope};
+/// use tracing_subscriber::layer::SubscriberExt;
+/// use tracing_subscriber::Registry;
+///
+/// let run = || {
+///     let recording = RecordingScope::new();
+///     let bridge = DipstickLayer::builder(recording.clone())
+///         .sample_rate(0.5)
+///         .finish();
+///     seed_sampling(42);
+///     subscriber::with_default(Registry::default().with(bridge), || {
+///         for _ in 0..10 {
+///             tracing::info!(metrics.counter = "hits");
+///         }
+///     });
+///     recording.values("hits").len()
+/// };
+/// assert_eq!(run(), run());
+/// ```
+pub fn seed_sampling(seed: u64) {
+    crate::seed_random(seed);
+}
+
+/// Runs the body with a [`DipstickLayer`] installed as the thread-local default subscriber.
+///
+/// The layer records into a fresh [`RecordingScope`], which is passed to the body. The subscriber
+/// is active only for the duration of the body (and only on the current thread), so tests don't
+/// influence each other. Returns everything that was recorded.
+///
+/// # Examples
+///
+/// ```rust
+/// use tracing_dipstick::testing::with_test_layer;
+///
+/// fn handle_request() {
+///     tracing::info!(metrics.counter = "requests", "Handling a request");
+/// }
+///
+/// let events = with_test_layer(|_| handle_request());
+/// assert_eq!(1, events.len());
+/// assert_eq!("requests", events[0].name);
+/// assert_eq!(1, events[0].value);
+/// ```
+///
+/// The metrics of spans that are finished on close (timers, levels) show up once the span
+/// closes.
+///
+/// ```rust
+/// use dipstick::InputKind;
+/// use tracing_dipstick::testing::with_test_layer;
+///
+/// let events = with_test_layer(|recording| {
+///     let span = tracing::info_span!("request", metrics.timer = "time", metrics.level = "active");
+///     assert_eq!(1, recording.events().len());
+///     drop(span);
+/// });
+/// assert_eq!(3, events.len());
+/// // The level is raised on creation, the timer is stopped before the level is lowered again
+/// assert_eq!((InputKind::Level, 1), (events[0].kind, events[0].value));
+/// assert_eq!(InputKind::Timer, events[1].kind);
+/// assert_eq!((InputKind::Level, -1), (events[2].kind, events[2].value));
+/// ```
+pub fn with_test_layer<F>(body: F) -> Vec<MetricEvent>
+where
+    F: FnOnce(&RecordingScope),
+{
+    let recording = RecordingScope::new();
+    let subscriber = Registry::default().with(DipstickLayer::new(recording.clone()));
+    subscriber::with_default(subscriber, || body(&recording));
+    recording.events()
+}
@@ -0,0 +1,362 @@
+//! The recognized `metrics.*` attributes and the forms of their values.
+#![cfg(feature = "testing")]
+
+use tracing::field::Empty;
+use tracing::{info, info_span, subscriber};
+use tracing_dipstick::metric::InputKind;
+use tracing_dipstick::testing::{with_test_layer, RecordingScope};
+use tracing_dipstick::DipstickLayer;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Registry;
+
+mod db {
+    pub fn query() {
+        tracing::info!(metrics.counter = "@target", "Querying");
+    }
+}
+
+mod http {
+    pub fn request() {
+        tracing::info!(metrics.counter = "@target", "Requesting");
+    }
+}
+
+/// Runs the body with `misc` as the default metric name, returns the names of the metrics.
+fn named_with_default<F: FnOnce()>(body: F) -> Vec<String> {
+    let recording = RecordingScope::new();
+    let bridge = DipstickLayer::builder(recording.clone())
+        .default_metric_name("misc")
+        .finish();
+    subscriber::with_default(Registry::default().with(bridge), body);
+    recording.events().into_iter().map(|e| e.name).collect()
+}
+
+#[test]
+fn bool_values() {
+    with_test_layer(|recording| {
+        tracing::info!(metrics.gauge.healthy = true);
+        tracing::info!(metrics.gauge.healthy = false);
+        tracing::info!(metrics.counter.failed = true);
+        tracing::info!(metrics.counter.failed = false);
+        assert_eq!(vec![1, 0], recording.values("healthy"));
+        assert_eq!(1, recording.values("failed").iter().sum::<isize>());
+    });
+}
+
+#[test]
+fn multiple_attributes() {
+    let events = with_test_layer(|recording| {
+        tracing::debug!(
+            metrics.counter.done = 4,
+            metrics.counter.retries = 2,
+            metrics.counter = "batches",
+            metrics.gauge.queue = 7,
+            metrics.marker = "flushed",
+        );
+        assert_eq!(vec![4], recording.values("done"));
+        assert_eq!(vec![2], recording.values("retries"));
+        assert_eq!(vec![1], recording.values("batches"));
+        assert_eq!(vec![7], recording.values("queue"));
+        assert_eq!(vec![1], recording.values("flushed"));
+    });
+    let names: Vec<_> = events.into_iter().map(|e| e.name).collect();
+    assert_eq!(
+        ["done", "retries", "batches", "queue", "flushed"].as_slice(),
+        names
+    );
+}
+
+#[test]
+fn negative_counters_ignored() {
+    let events = with_test_layer(|_| {
+        tracing::info!(metrics.counter.requests = -1);
+        tracing::info!(metrics.counter.requests = 2);
+    });
+    assert_eq!(1, events.len());
+    assert_eq!(2, events[0].value);
+}
+
+#[test]
+fn numbers_for_names_ignored() {
+    let events = with_test_layer(|_| {
+        tracing::info!(metrics.timer.start = 5);
+        tracing::info!(metrics.timer.stop = 5);
+        let _span = tracing::info_span!("work", metrics.level.max = 3, metrics.timer.busy = 1);
+    });
+    assert!(events.is_empty(), "{:?}", events);
+}
+
+#[test]
+fn reset_gauges() {
+    let events = with_test_layer(|recording| {
+        tracing::info!(metrics.gauge.state = 3);
+        tracing::info!(metrics.gauge.state = 0);
+        tracing::info!(metrics.reset = "state");
+        assert_eq!(vec![3, 0, 0], recording.values("state"));
+    });
+    assert!(events.iter().all(|event| event.kind == InputKind::Gauge));
+}
+
+#[test]
+fn gauge_deltas() {
+    with_test_layer(|recording| {
+        tracing::info!(metrics.gauge.delta.queue = 3);
+        tracing::info!(metrics.gauge.delta.queue = 2);
+        tracing::info!(metrics.gauge.delta.queue = -4);
+        tracing::info!(metrics.gauge.delta = "queue");
+        assert_eq!(vec![3, 5, 1, 2], recording.values("queue"));
+    });
+}
+
+#[test]
+fn clamped_u64() {
+    with_test_layer(|recording| {
+        tracing::info!(metrics.counter.bytes = u64::MAX);
+        tracing::info!(metrics.gauge.free = 1u64 << 63);
+        assert_eq!(vec![i64::MAX as isize], recording.values("bytes"));
+        assert_eq!(vec![i64::MAX as isize], recording.values("free"));
+    });
+}
+
+#[test]
+fn clamped_u128() {
+    let events = with_test_layer(|_| {
+        tracing::info!(metrics.gauge.huge = 12345678901234567890u128);
+    });
+    assert_eq!(i64::MAX as isize, events[0].value);
+}
+
+#[test]
+fn target_names() {
+    let events = with_test_layer(|_| {
+        db::query();
+        http::request();
+    });
+    assert!(events[0].name.ends_with("_db"), "{}", events[0].name);
+    assert!(events[1].name.ends_with("_http"), "{}", events[1].name);
+}
+
+#[test]
+fn special_values() {
+    with_test_layer(|recording| {
+        tracing::warn!(
+            user = "alice",
+            metrics.gauge.fields = "@field_count",
+            "Login failed"
+        );
+        tracing::warn!(metrics.gauge.level = "@level");
+        tracing::trace!(metrics.gauge.level = "@level");
+        tracing::error!(metrics.level_value = "severity");
+        tracing::debug!(metrics.level_value = "severity");
+        assert_eq!(vec![3], recording.values("fields"));
+        assert_eq!(vec![4, 1], recording.values("level"));
+        let severity = recording.values("severity");
+        assert_eq!(vec![5, 2], severity);
+        // The error is the more severe one
+        assert!(severity[0] > severity[1]);
+    });
+}
+
+#[test]
+fn level_amounts_undone() {
+    with_test_layer(|recording| {
+        let level = || {
+            recording
+                .values("pool")
+                .iter()
+                .map(|&v| v as i128)
+                .sum::<i128>()
+        };
+        tracing::info!(metrics.level.pool = 10);
+        assert_eq!(10, level());
+        for amount in [3, -2, i64::MIN] {
+            let span = tracing::info_span!("borrow", metrics.level.pool = amount);
+            assert_ne!(10, level());
+            drop(span);
+            assert_eq!(10, level());
+        }
+    });
+}
+
+#[test]
+fn count_children() {
+    with_test_layer(|recording| {
+        let batch = tracing::info_span!("batch", metrics.count_children = "items");
+        batch.in_scope(|| {
+            for _ in 0..3 {
+                let _item = tracing::info_span!("item").entered();
+                drop(tracing::info_span!("step", metrics.counter = "steps"));
+            }
+        });
+        drop(batch);
+        assert_eq!(vec![6], recording.values("items"));
+    });
+}
+
+#[test]
+fn conditional() {
+    with_test_layer(|recording| {
+        for cache_hit in [true, false, true] {
+            tracing::info!(
+                cache_hit,
+                metrics.when = "cache_hit",
+                metrics.counter = "hits"
+            );
+        }
+        assert_eq!(vec![1, 1], recording.values("hits"));
+    });
+}
+
+#[test]
+fn timers_across_events() {
+    let events = with_test_layer(|_| {
+        tracing::info!(metrics.timer.start = "sync", "Sync requested");
+        tracing::info!(metrics.timer.stop = "sync", "Sync done");
+        // Not running any more, ignored
+        tracing::info!(metrics.timer.stop = "sync", "Sync done again");
+    });
+    assert_eq!(1, events.len());
+    assert_eq!(
+        ("sync", InputKind::Timer),
+        (events[0].name.as_str(), events[0].kind)
+    );
+}
+
+#[test]
+fn peaks() {
+    with_test_layer(|recording| {
+        let request = || tracing::info_span!("request", metrics.peak = "requests.peak");
+        let a = request();
+        let b = request();
+        drop(a);
+        let c = request();
+        let d = request();
+        drop((b, c, d));
+        let _e = request();
+        assert_eq!(
+            vec![1, 2, 2, 2, 3, 3, 3, 3, 3],
+            recording.values("requests.peak")
+        );
+    });
+}
+
+#[test]
+fn not_entered() {
+    with_test_layer(|recording| {
+        let request = || tracing::info_span!("request", metrics.not_entered = "dead_spans");
+        // Oops, forgot to enter it
+        drop(request());
+        request().in_scope(|| ());
+        assert_eq!(vec![1], recording.values("dead_spans"));
+    });
+}
+
+#[test]
+fn names_from_fields() {
+    let events = with_test_layer(|_| {
+        let _span = tracing::info_span!("http", metrics.scope = "status").entered();
+        tracing::info!(status = "404", metrics.counter.dynamic = "status");
+    });
+    assert_eq!(1, events.len());
+    assert_eq!("status.404", events[0].name);
+}
+
+#[test]
+fn recorded_later() {
+    with_test_layer(|recording| {
+        let span = tracing::info_span!(
+            "request",
+            metrics.scope = "request",
+            metrics.counter = Empty,
+            metrics.gauge.size = Empty,
+        );
+        assert!(recording.events().is_empty());
+        span.record("metrics.counter", "failed");
+        span.record("metrics.gauge.size", 42);
+        assert_eq!(vec![1], recording.values("request.failed"));
+        assert_eq!(vec![42], recording.values("request.size"));
+    });
+}
+
+#[test]
+fn timer_percentiles() {
+    with_test_layer(|recording| {
+        for _ in 0..3 {
+            tracing::info_span!(
+                "db",
+                metrics.scope = "db",
+                metrics.timer.percentiles = "query"
+            )
+            .in_scope(|| ());
+        }
+        // The hint only once
+        assert_eq!(vec![1], recording.values("db.query.percentiles"));
+        assert_eq!(3, recording.values("db.query").len());
+    });
+}
+
+#[test]
+fn auto_gauge() {
+    with_test_layer(|recording| {
+        let _span = tracing::info_span!(
+            "batch",
+            metrics.scope = "batch",
+            metrics.auto_gauge = true,
+            size = 42,
+            ratio = 0.75,
+            name = "import",
+        )
+        .entered();
+        assert_eq!(vec![42], recording.values("batch.size"));
+        assert_eq!(vec![1], recording.values("batch.ratio"));
+        assert_eq!(2, recording.events().len());
+    });
+}
+
+#[test]
+fn bare_explicit_name() {
+    let names = named_with_default(|| {
+        let _span = info_span!("request", metrics.counter = "requests").entered();
+        info!(metrics.counter = "hits");
+    });
+    assert_eq!(["requests", "hits"].as_slice(), names);
+}
+
+#[test]
+fn bare_span_name() {
+    let names = named_with_default(|| {
+        let _span = info_span!("request", metrics.counter = "").entered();
+        info!(metrics.counter = "");
+        info!(metrics.counter = true, metrics.gauge = true);
+        info!(metrics.counter = false);
+        let _inner = info_span!("Shaving yaks::inner", metrics.scope = "inner").entered();
+        info!(metrics.counter = true);
+    });
+    let expected = [
+        "request",
+        "request",
+        "request",
+        "request",
+        "inner.Shaving_yaks_inner",
+    ];
+    assert_eq!(expected.as_slice(), names);
+}
+
+#[test]
+fn bare_default_name() {
+    let names = named_with_default(|| {
+        info!(metrics.counter = "");
+        info!(metrics.counter = true);
+        info!(metrics.counter = false);
+    });
+    assert_eq!(["misc", "misc"].as_slice(), names);
+}
+
+#[test]
+fn bare_ignored() {
+    let events = with_test_layer(|_| {
+        info!(metrics.counter = "");
+        info!(metrics.counter = true);
+    });
+    assert!(events.is_empty(), "{:?}", events);
+}
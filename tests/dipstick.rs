@@ -0,0 +1,30 @@
+//! The parts working with the scopes of dipstick itself.
+#![cfg(all(feature = "dipstick", feature = "testing"))]
+
+use dipstick::{AtomicBucket, InputKind, ScoreType};
+use tracing_dipstick::testing::RecordingScope;
+use tracing_dipstick::DipstickLayer;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Registry;
+
+#[test]
+fn timer_stats() {
+    let bucket = AtomicBucket::new();
+    // Only the mean for all the timers, except the latencies get the maximum too
+    bucket.stats(|kind, name, score| match (kind, score) {
+        (InputKind::Timer, ScoreType::Mean(mean)) => Some((kind, name, mean as isize)),
+        (InputKind::Timer, ScoreType::Max(max)) if name.join(".").ends_with("latency") => {
+            Some((kind, name.make_name("max"), max))
+        }
+        _ => None,
+    });
+    let subscriber = Registry::default().with(DipstickLayer::new(bucket.clone()));
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::info!(metrics.timer.latency = 3_000, metrics.timer.parse = 1_000);
+    });
+    let published = RecordingScope::new();
+    bucket.flush_to(&published).unwrap();
+    let mut names: Vec<_> = published.events().into_iter().map(|e| e.name).collect();
+    names.sort();
+    assert_eq!(vec!["latency", "latency.max", "parse"], names);
+}
@@ -0,0 +1,156 @@
+//! The optional behaviour of the layer, configured through its builder.
+#![cfg(feature = "testing")]
+
+use std::time::Duration;
+
+use tracing::subscriber;
+use tracing_dipstick::metric::InputKind;
+use tracing_dipstick::testing::{seed_sampling, ManualClock, RecordingScope};
+use tracing_dipstick::DipstickLayer;
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::layer::{Layer, SubscriberExt};
+use tracing_subscriber::Registry;
+
+fn sampled_hits(seed: u64) -> Vec<isize> {
+    let recording = RecordingScope::new();
+    let bridge = DipstickLayer::builder(recording.clone())
+        .sample_rate(0.1)
+        .finish();
+    seed_sampling(seed);
+    subscriber::with_default(Registry::default().with(bridge), || {
+        for _ in 0..1_000 {
+            tracing::info!(metrics.counter = "hits");
+        }
+    });
+    recording.values("hits")
+}
+
+#[test]
+fn sampled_counters_scaled() {
+    let hits = sampled_hits(42);
+    // Each recorded increment makes up for 10 of them
+    assert!(hits.iter().all(|&hit| hit == 10));
+    // Only some of them got recorded, but the same ones each time
+    assert!((50..150).contains(&hits.len()), "{}", hits.len());
+    assert_eq!(hits, sampled_hits(42));
+}
+
+#[test]
+fn sampled_levels_balanced() {
+    let recording = RecordingScope::new();
+    let bridge = DipstickLayer::builder(recording.clone())
+        .sample_rate(0.5)
+        .finish();
+    subscriber::with_default(Registry::default().with(bridge), || {
+        for _ in 0..1_000 {
+            let _span = tracing::info_span!("work", metrics.level = "active").entered();
+            tracing::info!(metrics.level.queued = 2);
+            tracing::info!(metrics.level.queued = -2);
+        }
+    });
+    let active = recording.values("active");
+    assert_eq!(2_000, active.len());
+    assert_eq!(0, active.iter().sum::<isize>());
+    let queued = recording.values("queued");
+    assert_eq!(2_000, queued.len());
+    assert_eq!(0, queued.iter().sum::<isize>());
+}
+
+#[test]
+fn self_monitoring() {
+    let recording = RecordingScope::new();
+    let bridge = DipstickLayer::builder(recording.clone())
+        .self_monitoring(true)
+        .finish();
+    subscriber::with_default(Registry::default().with(bridge), || {
+        let _span = tracing::info_span!("request", metrics.scope = "request").entered();
+        tracing::info!(metrics.counter = "hits", metrics.gauge.size = 42);
+        tracing::info!(metrics.counter = "hits");
+    });
+    assert_eq!(vec![1, 1, 1], recording.values("tracing_dipstick.emitted"));
+}
+
+#[test]
+fn auto_error_marker() {
+    let recording = RecordingScope::new();
+    let bridge = DipstickLayer::builder(recording.clone())
+        .auto_error_marker("errors")
+        .finish();
+    subscriber::with_default(Registry::default().with(bridge), || {
+        tracing::error!("Connection refused");
+        tracing::warn!("Retrying");
+        let _span = tracing::info_span!("request", metrics.scope = "request").entered();
+        tracing::error!("Gave up");
+    });
+    let events = recording.events();
+    let names: Vec<_> = events.iter().map(|e| e.name.as_str()).collect();
+    assert_eq!(["errors", "request.errors"].as_slice(), names);
+    assert!(events.iter().all(|e| e.kind == InputKind::Marker));
+}
+
+#[test]
+fn per_layer_filter() {
+    let recording = RecordingScope::new();
+    let layer =
+        DipstickLayer::new(recording.clone()).with_filter(filter_fn(|meta| meta.is_event()));
+    subscriber::with_default(Registry::default().with(layer), || {
+        let _span = tracing::info_span!("request", metrics.scope = "request").entered();
+        tracing::info!(metrics.counter = "hits", "Hit");
+    });
+    assert_eq!(vec![1], recording.values("hits"));
+}
+
+#[test]
+fn seen_gauges() {
+    let clock = ManualClock::new();
+    let recording = RecordingScope::new();
+    let bridge = DipstickLayer::builder(recording.clone())
+        .clock(clock.clone())
+        .seen_gauges(1)
+        .finish();
+    subscriber::with_default(Registry::default().with(bridge), || {
+        tracing::info!(metrics.counter = "hits");
+        clock.advance(Duration::from_secs(5));
+        tracing::info!(metrics.counter = "hits");
+        // Over the limit
+        tracing::info!(metrics.counter = "misses");
+    });
+    let first = recording.values("hits.first_seen");
+    let last = recording.values("hits.last_seen");
+    assert_eq!(1, first.len());
+    assert_eq!(vec![first[0], first[0] + 5], last);
+    assert!(recording.values("misses.first_seen").is_empty());
+    assert!(recording.values("misses.last_seen").is_empty());
+}
+
+#[test]
+fn open_levels_untracked() {
+    let bridge = DipstickLayer::new(RecordingScope::new());
+    subscriber::with_default(Registry::default().with(bridge.clone()), || {
+        let _span = tracing::info_span!("request", metrics.level = "active").entered();
+        // Off by default
+        assert!(bridge.open_levels().is_empty());
+    });
+}
+
+#[test]
+fn message_counters() {
+    let recording = RecordingScope::new();
+    let bridge = DipstickLayer::builder(recording.clone())
+        .message_counters("msgs")
+        .finish();
+    subscriber::with_default(Registry::default().with(bridge), || {
+        for user in ["alice", "bob"] {
+            tracing::warn!(name: "login_failed", "Login failed for {}", user);
+        }
+        tracing::info!("Started");
+        tracing::info!("Stopped");
+    });
+    assert_eq!(vec![1, 1], recording.values("msgs.login_failed"));
+    let mut names: Vec<_> = recording.events().into_iter().map(|e| e.name).collect();
+    names.dedup();
+    // Each log line of its own
+    assert_eq!(3, names.len());
+    assert!(names[1].starts_with("msgs.tests_layer_rs_"), "{}", names[1]);
+    assert_ne!(names[1], names[2]);
+}
@@ -0,0 +1,8 @@
+//! Compile-time checks of the `metric_event!` and `metric_span!` macros.
+
+#[test]
+fn ui() {
+    let cases = trybuild::TestCases::new();
+    cases.pass("tests/ui/known.rs");
+    cases.compile_fail("tests/ui/unknown_*.rs");
+}
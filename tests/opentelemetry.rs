@@ -0,0 +1,92 @@
+//! The mapping of the metric kinds onto the OpenTelemetry instruments.
+#![cfg(feature = "opentelemetry")]
+
+use std::collections::HashMap;
+
+use opentelemetry::metrics::MeterProvider;
+use opentelemetry_sdk::metrics::data::{AggregatedMetrics, MetricData};
+use opentelemetry_sdk::metrics::{InMemoryMetricExporter, PeriodicReader, SdkMeterProvider};
+use tracing::{info, info_span, subscriber};
+use tracing_dipstick::{DipstickLayer, OpenTelemetry};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Registry;
+
+/// A simplified view of an exported metric.
+#[derive(Debug, PartialEq)]
+enum Exported {
+    Sum(i128, bool),
+    Gauge(i64),
+    Histogram(u64, u64),
+}
+
+fn export<F: FnOnce()>(body: F) -> HashMap<String, Exported> {
+    let exporter = InMemoryMetricExporter::default();
+    let provider = SdkMeterProvider::builder()
+        .with_reader(PeriodicReader::builder(exporter.clone()).build())
+        .build();
+    let bridge = DipstickLayer::builder(OpenTelemetry::new(provider.meter("test")))
+        .undo_on_close(true)
+        .finish();
+    subscriber::with_default(Registry::default().with(bridge), body);
+    provider.force_flush().unwrap();
+    let mut result = HashMap::new();
+    let exported = exporter.get_finished_metrics().unwrap();
+    let metrics = exported
+        .iter()
+        .flat_map(|resource| resource.scope_metrics())
+        .flat_map(|scope| scope.metrics());
+    for metric in metrics {
+        let value = match metric.data() {
+            AggregatedMetrics::U64(MetricData::Sum(sum)) => {
+                let total: u64 = sum.data_points().map(|point| point.value()).sum();
+                Exported::Sum(total.into(), sum.is_monotonic())
+            }
+            AggregatedMetrics::I64(MetricData::Sum(sum)) => {
+                let total: i64 = sum.data_points().map(|point| point.value()).sum();
+                Exported::Sum(total.into(), sum.is_monotonic())
+            }
+            AggregatedMetrics::I64(MetricData::Gauge(gauge)) => {
+                Exported::Gauge(gauge.data_points().map(|point| point.value()).sum())
+            }
+            AggregatedMetrics::U64(MetricData::Histogram(histogram)) => {
+                let point = histogram.data_points().next().unwrap();
+                Exported::Histogram(point.count(), point.sum())
+            }
+            other => panic!("Unexpected data {:?}", other),
+        };
+        result.insert(metric.name().to_owned(), value);
+    }
+    result
+}
+
+#[test]
+fn kinds() {
+    let exported = export(|| {
+        let _span = info_span!(
+            "request",
+            metrics.scope = "request",
+            metrics.level = "active",
+            metrics.counter = "started",
+        )
+        .entered();
+        info!(metrics.counter.bytes = 42, metrics.marker = "done");
+        info!(metrics.counter.bytes = 8, metrics.marker = "done");
+        info!(metrics.gauge.size = 3, metrics.level.queue = -2);
+        info!(metrics.timer.io = 5_000, metrics.distribution.rows = 10);
+        info!(metrics.distribution.rows = 20);
+    });
+    let expected = HashMap::from([
+        ("request.bytes".to_owned(), Exported::Sum(50, true)),
+        ("request.done".to_owned(), Exported::Sum(2, true)),
+        ("request.size".to_owned(), Exported::Gauge(3)),
+        // Held until the span closes
+        ("request.active".to_owned(), Exported::Sum(0, false)),
+        ("request.queue".to_owned(), Exported::Sum(-2, false)),
+        // Nanoseconds in, microseconds out
+        ("request.io".to_owned(), Exported::Histogram(1, 5)),
+        ("request.rows".to_owned(), Exported::Histogram(2, 30)),
+        // The undo on close is dropped, counters can't go down
+        ("request.started".to_owned(), Exported::Sum(1, true)),
+    ]);
+    assert_eq!(expected, exported);
+}
@@ -0,0 +1,171 @@
+//! Panics of the scope while recording the metrics of a span.
+//!
+//! The layer catches them, but they must not leave the span broken for the other layers (eg. by
+//! poisoning the lock of its extensions) or finish the metrics of the span early.
+#![cfg(feature = "testing")]
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use tracing::field::Empty;
+use tracing::span::Id;
+use tracing::{info, info_span, subscriber, Subscriber};
+use tracing_dipstick::metric::{Counter, Gauge, Level, Marker, NameParts, Timer};
+use tracing_dipstick::testing::RecordingScope;
+use tracing_dipstick::{DipstickLayer, DipstickSink};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{Layer, Registry};
+
+/// A scope that can't handle one of the metrics.
+#[derive(Clone)]
+struct Fragile(RecordingScope);
+
+impl DipstickSink for Fragile {
+    fn counter(&self, name: &str) -> Counter {
+        assert_ne!("boom", name, "Can't count booms");
+        DipstickSink::counter(&self.0, name)
+    }
+    fn marker(&self, name: &str) -> Marker {
+        DipstickSink::marker(&self.0, name)
+    }
+    fn gauge(&self, name: &str) -> Gauge {
+        DipstickSink::gauge(&self.0, name)
+    }
+    fn level(&self, name: &str) -> Level {
+        DipstickSink::level(&self.0, name)
+    }
+    fn timer(&self, name: &str) -> Timer {
+        DipstickSink::timer(&self.0, name)
+    }
+    fn add_name(&self, name: &str) -> Self {
+        assert_ne!("boom", name, "Can't scope booms");
+        Fragile(DipstickSink::add_name(&self.0, name))
+    }
+    fn named(&self, name: &str) -> Self {
+        Fragile(DipstickSink::named(&self.0, name))
+    }
+    fn prefixes(&self) -> &NameParts {
+        DipstickSink::prefixes(&self.0)
+    }
+}
+
+/// Another layer, looking into the extensions of the spans on each exit and close.
+#[derive(Clone, Default)]
+struct Reader(Arc<AtomicUsize>);
+
+impl Reader {
+    fn look<I>(&self, id: &Id, ctx: Context<I>)
+    where
+        I: Subscriber + for<'l> LookupSpan<'l>,
+    {
+        let span = ctx.span(id).expect("Span not found");
+        // Panics if the lock is poisoned
+        assert!(span.extensions().get::<()>().is_none());
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl<I> Layer<I> for Reader
+where
+    I: Subscriber + for<'l> LookupSpan<'l>,
+{
+    fn on_exit(&self, id: &Id, ctx: Context<I>) {
+        self.look(id, ctx);
+    }
+    fn on_close(&self, id: Id, ctx: Context<I>) {
+        self.look(&id, ctx);
+    }
+}
+
+/// Runs the body with the fragile scope and the reader, returning the number of panics caught.
+fn run<F: FnOnce(&RecordingScope)>(reader: &Reader, body: F) -> (RecordingScope, usize) {
+    let recording = RecordingScope::new();
+    let errors = Arc::new(AtomicUsize::new(0));
+    let errors_cp = Arc::clone(&errors);
+    let bridge = DipstickLayer::builder(Fragile(recording.clone()))
+        .on_error(move |_| {
+            errors_cp.fetch_add(1, Ordering::Relaxed);
+        })
+        .finish();
+    let subscriber = Registry::default().with(bridge).with(reader.clone());
+    subscriber::with_default(subscriber, || body(&recording));
+    let errors = errors.load(Ordering::Relaxed);
+    (recording, errors)
+}
+
+#[test]
+fn panic_on_enter() {
+    let reader = Reader::default();
+    let (recording, errors) = run(&reader, |recording| {
+        let span = info_span!(
+            "work",
+            metrics.on = "enter",
+            metrics.level = "active",
+            metrics.counter = "boom",
+        );
+        span.in_scope(|| {
+            // The level before the panic is still held until the exit
+            assert_eq!(vec![1], recording.values("active"));
+        });
+        assert_eq!(vec![1, -1], recording.values("active"));
+    });
+    assert_eq!(1, errors);
+    // One exit and one close
+    assert_eq!(2, reader.0.load(Ordering::Relaxed));
+    assert_eq!(vec![1, -1], recording.values("active"));
+}
+
+#[test]
+fn panic_on_record() {
+    let reader = Reader::default();
+    let (recording, errors) = run(&reader, |recording| {
+        let span = info_span!(
+            "work",
+            metrics.level = "active",
+            metrics.level.queued = Empty,
+            metrics.counter.boom = Empty,
+        );
+        span.record("metrics.level.queued", 2);
+        span.record("metrics.counter.boom", 1);
+        // Nothing of the span is finished before it's closed
+        assert_eq!(vec![1], recording.values("active"));
+        assert_eq!(vec![2], recording.values("queued"));
+        span.in_scope(|| ());
+    });
+    assert_eq!(1, errors);
+    assert_eq!(2, reader.0.load(Ordering::Relaxed));
+    assert_eq!(vec![1, -1], recording.values("active"));
+    assert_eq!(vec![2, -2], recording.values("queued"));
+}
+
+#[test]
+fn panic_in_event() {
+    let reader = Reader::default();
+    let (recording, errors) = run(&reader, |recording| {
+        let _span = info_span!("work", metrics.level = "active").entered();
+        info!(metrics.counter = "boom");
+        info!(metrics.counter = "hits");
+        assert_eq!(vec![1], recording.values("hits"));
+    });
+    assert_eq!(1, errors);
+    assert_eq!(2, reader.0.load(Ordering::Relaxed));
+    assert_eq!(vec![1, -1], recording.values("active"));
+}
+
+#[test]
+fn panic_in_child_scope() {
+    let reader = Reader::default();
+    let (recording, errors) = run(&reader, |recording| {
+        let _span = info_span!("work", metrics.level = "active").entered();
+        info_span!("child", metrics.scope = "boom").in_scope(|| {
+            // Falls back to the scope of the parent
+            info!(metrics.counter = "hits");
+        });
+        assert_eq!(vec![1], recording.values("hits"));
+    });
+    assert_eq!(1, errors);
+    // The exits and closes of both spans
+    assert_eq!(4, reader.0.load(Ordering::Relaxed));
+    assert_eq!(vec![1, -1], recording.values("active"));
+}
@@ -0,0 +1,172 @@
+//! The naming of the metrics by the scopes, tags and suffixes.
+#![cfg(feature = "testing")]
+
+use std::thread;
+use std::time::Duration;
+
+use tracing::{info, info_span, subscriber};
+use tracing_dipstick::testing::{with_test_layer, ManualClock, RecordingScope};
+use tracing_dipstick::DipstickLayer;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Registry;
+
+#[test]
+fn scope_from_field() {
+    let events = with_test_layer(|_| {
+        let _span = tracing::info_span!(
+            "request",
+            tenant = "acme",
+            metrics.scope = "requests",
+            metrics.scope.field = "tenant",
+        )
+        .entered();
+        tracing::info!(metrics.counter = "handled");
+    });
+    assert_eq!("requests.acme.handled", events[0].name);
+}
+
+#[test]
+fn non_string_scopes() {
+    let shard_id = 3;
+    let events = with_test_layer(|_| {
+        let _span = tracing::info_span!("shard", metrics.scope = shard_id).entered();
+        tracing::info!(metrics.counter = "writes");
+        tracing::info!(metrics.scope = 42u64, metrics.counter = "reads");
+    });
+    let names: Vec<_> = events.into_iter().map(|e| e.name).collect();
+    assert_eq!(["3.writes", "3.42.reads"].as_slice(), names);
+}
+
+#[test]
+fn event_scopes() {
+    let events = with_test_layer(|_| {
+        tracing::info!(metrics.scope = "cache", metrics.counter = "misses");
+        let _span = tracing::info_span!("request", metrics.scope = "request").entered();
+        tracing::info!(metrics.scope = "cache", metrics.counter = "misses");
+        tracing::info!(metrics.scope.full = "cache", metrics.counter = "misses");
+        tracing::info!(metrics.counter = "done");
+    });
+    let names: Vec<_> = events.into_iter().map(|e| e.name).collect();
+    let expected = [
+        "cache.misses",
+        "request.cache.misses",
+        "cache.misses",
+        "request.done",
+    ];
+    assert_eq!(expected.as_slice(), names);
+}
+
+#[test]
+fn dotted_scopes() {
+    let recording = RecordingScope::new();
+    let bridge = DipstickLayer::builder(recording.clone())
+        .scope_separator('/')
+        .finish();
+    subscriber::with_default(Registry::default().with(bridge), || {
+        let _outer = info_span!("outer", metrics.scope = "http.server").entered();
+        info!(metrics.scope = "cache..hot", metrics.counter = "hits");
+        info!(metrics.scope.full = "db.pool", metrics.counter = "queries");
+    });
+    let names: Vec<_> = recording.events().into_iter().map(|e| e.name).collect();
+    let expected = ["http/server/cache/hot/hits", "db.pool/queries"];
+    assert_eq!(expected.as_slice(), names);
+}
+
+#[test]
+fn tags() {
+    let events = with_test_layer(|_| {
+        let _outer = tracing::info_span!("outer", metrics.tag.region = "eu").entered();
+        let _inner = tracing::info_span!(
+            "inner",
+            metrics.scope = "db",
+            metrics.tag.table = "users",
+            metrics.tag.kind = "read",
+        )
+        .entered();
+        tracing::info!(metrics.counter = "queries");
+    });
+    assert_eq!("region.eu.db.kind.read.table.users.queries", events[0].name);
+}
+
+#[test]
+fn scope_reset() {
+    let events = with_test_layer(|_| {
+        let _span = tracing::info_span!("request", metrics.scope.full = "requests").entered();
+        tracing::info!(metrics.counter = "handled");
+        tracing::error!(metrics.scope.reset = true, metrics.counter = "errors");
+    });
+    assert_eq!("requests.handled", events[0].name);
+    assert_eq!("errors", events[1].name);
+}
+
+#[test]
+fn level_suffix() {
+    let recording = RecordingScope::new();
+    let bridge = DipstickLayer::builder(recording.clone())
+        .append_level_suffix(true)
+        .finish();
+    subscriber::with_default(Registry::default().with(bridge), || {
+        let _span =
+            tracing::debug_span!("job", metrics.scope = "job", metrics.counter = "runs").entered();
+        tracing::info!(metrics.counter = "started");
+        tracing::error!(metrics.counter = "started");
+        tracing::warn!(metrics.gauge.queue = 3);
+    });
+    let names: Vec<_> = recording.events().into_iter().map(|e| e.name).collect();
+    let expected = [
+        "job.runs.debug",
+        "job.started.info",
+        "job.started.error",
+        "job.queue.warn",
+    ];
+    assert_eq!(expected.as_slice(), names);
+}
+
+#[test]
+fn thread_name() {
+    let recording = RecordingScope::new();
+    let bridge = DipstickLayer::builder(recording.clone())
+        .append_thread_name(true)
+        .finish();
+    let dispatch = tracing::Dispatch::new(Registry::default().with(bridge));
+    for worker in ["worker-1", "worker-2"] {
+        let dispatch = dispatch.clone();
+        thread::Builder::new()
+            .name(worker.to_owned())
+            .spawn(move || {
+                tracing::dispatcher::with_default(&dispatch, || {
+                    tracing::info!(metrics.counter = "requests");
+                })
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+    assert_eq!(vec![1], recording.values("requests.worker-1"));
+    assert_eq!(vec![1], recording.values("requests.worker-2"));
+}
+
+#[test]
+fn flush_every() {
+    let clock = ManualClock::new();
+    let recording = RecordingScope::new();
+    let bridge = DipstickLayer::builder(recording.clone())
+        .clock(clock.clone())
+        .finish();
+    subscriber::with_default(Registry::default().with(bridge.clone()), || {
+        let span = info_span!("sync", metrics.scope = "sync", metrics.flush_every = 0.1);
+        bridge.tick();
+        assert_eq!(0, recording.flush_count());
+        clock.advance(Duration::from_millis(100));
+        bridge.tick();
+        assert_eq!(1, recording.flush_count());
+        // Once per tick, even if more periods passed
+        clock.advance(Duration::from_millis(250));
+        bridge.tick();
+        assert_eq!(2, recording.flush_count());
+        drop(span);
+        clock.advance(Duration::from_secs(1));
+        bridge.tick();
+        assert_eq!(2, recording.flush_count());
+    });
+}
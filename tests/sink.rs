@@ -0,0 +1,142 @@
+//! A sink written only against the backend-neutral API, without touching dipstick.
+
+use std::sync::{Arc, Mutex};
+
+use tracing::{info, info_span, subscriber};
+use tracing_dipstick::metric::{
+    input_metric, Counter, Gauge, InputMetric, Level, Marker, NameParts, Timer,
+};
+use tracing_dipstick::{DipstickLayer, MetricSink};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Registry;
+
+type Log = Arc<Mutex<Vec<(&'static str, String, isize)>>>;
+
+/// Logs the kind, full name and value of everything written.
+#[derive(Clone, Default)]
+struct Dummy {
+    prefixes: NameParts,
+    log: Log,
+    relative_gauges: bool,
+}
+
+impl Dummy {
+    fn metric(&self, kind: &'static str, name: &str) -> InputMetric {
+        let mut full = self.prefixes.iter().cloned().collect::<Vec<_>>();
+        full.push(name.to_owned());
+        let full = full.join(".");
+        let log = Arc::clone(&self.log);
+        input_metric(name, move |value, _| {
+            log.lock().unwrap().push((kind, full.clone(), value));
+        })
+    }
+
+    fn check(&self, expected: &[(&str, &str, isize)]) {
+        let log = self.log.lock().unwrap();
+        let log: Vec<_> = log
+            .iter()
+            .map(|(kind, name, value)| (*kind, name.as_str(), *value))
+            .collect();
+        assert_eq!(expected, log.as_slice());
+    }
+}
+
+impl MetricSink for Dummy {
+    fn counter(&self, name: &str) -> Counter {
+        self.metric("counter", name).into()
+    }
+    fn marker(&self, name: &str) -> Marker {
+        self.metric("marker", name).into()
+    }
+    fn gauge(&self, name: &str) -> Gauge {
+        self.metric("gauge", name).into()
+    }
+    fn level(&self, name: &str) -> Level {
+        self.metric("level", name).into()
+    }
+    fn timer(&self, name: &str) -> Timer {
+        self.metric("timer", name).into()
+    }
+    fn add_name(&self, name: &str) -> Self {
+        let mut sub = self.clone();
+        sub.prefixes.push_back(name.to_owned());
+        sub
+    }
+    fn named(&self, name: &str) -> Self {
+        Dummy {
+            prefixes: NameParts::from(name),
+            log: Arc::clone(&self.log),
+            relative_gauges: self.relative_gauges,
+        }
+    }
+    fn prefixes(&self) -> &NameParts {
+        &self.prefixes
+    }
+    fn relative_gauges(&self) -> bool {
+        self.relative_gauges
+    }
+}
+
+#[test]
+fn all_kinds() {
+    let dummy = Dummy::default();
+    let subscriber = Registry::default().with(DipstickLayer::new(dummy.clone()));
+    subscriber::with_default(subscriber, || {
+        let _span = info_span!("db", metrics.scope = "db", metrics.level = "open").entered();
+        info!(metrics.counter.queries = 2, metrics.marker = "done");
+        info!(metrics.gauge.size = 3, metrics.distribution.rows = 10);
+    });
+    let expected = [
+        ("level", "db.open", 1),
+        ("counter", "db.queries", 2),
+        ("marker", "db.done", 1),
+        ("gauge", "db.size", 3),
+        ("timer", "db.rows", 10),
+        ("level", "db.open", -1),
+    ];
+    dummy.check(&expected);
+}
+
+#[test]
+fn handles() {
+    let dummy = Dummy::default();
+    dummy.counter("hits").count(3);
+    dummy.marker("done").mark();
+    dummy.gauge("size").value(-2);
+    dummy.level("open").adjust(5);
+    dummy.timer("time").interval_us(7);
+    let expected = [
+        ("counter", "hits", 3),
+        ("marker", "done", 1),
+        ("gauge", "size", -2),
+        ("level", "open", 5),
+        ("timer", "time", 7),
+    ];
+    dummy.check(&expected);
+}
+
+fn gauge_deltas(dummy: &Dummy) {
+    let subscriber = Registry::default().with(DipstickLayer::new(dummy.clone()));
+    subscriber::with_default(subscriber, || {
+        let _span = info_span!("queue", metrics.scope = "queue").entered();
+        info!(metrics.gauge.delta.len = 3);
+        info!(metrics.gauge.delta.len = -1);
+    });
+}
+
+#[test]
+fn absolute_gauge_deltas() {
+    let dummy = Dummy::default();
+    gauge_deltas(&dummy);
+    dummy.check(&[("gauge", "queue.len", 3), ("gauge", "queue.len", 2)]);
+}
+
+#[test]
+fn relative_gauge_deltas() {
+    let dummy = Dummy {
+        relative_gauges: true,
+        ..Dummy::default()
+    };
+    gauge_deltas(&dummy);
+    dummy.check(&[("level", "queue.len", 3), ("level", "queue.len", -1)]);
+}
@@ -0,0 +1,116 @@
+//! The lifetime of spans: what their metrics do when they are closed, cloned, entered or dropped
+//! during a panic.
+#![cfg(feature = "testing")]
+
+use std::panic;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use tracing::span::Id;
+use tracing::{subscriber, Subscriber};
+use tracing_dipstick::metric::InputKind;
+use tracing_dipstick::testing::{with_test_layer, ManualClock, RecordingScope};
+use tracing_dipstick::DipstickLayer;
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::Registry;
+
+/// Looks at what was recorded at the time a span gets closed.
+struct OnClose(RecordingScope, Arc<Mutex<usize>>);
+
+impl<S: Subscriber> Layer<S> for OnClose {
+    fn on_close(&self, _: Id, _: Context<S>) {
+        *self.1.lock().unwrap() = self.0.events().len();
+    }
+}
+
+#[test]
+fn finished_before_later_layers() {
+    let recording = RecordingScope::new();
+    let seen = Arc::new(Mutex::new(0));
+    let subscriber = Registry::default()
+        .with(DipstickLayer::new(recording.clone()))
+        .with(OnClose(recording.clone(), Arc::clone(&seen)));
+    subscriber::with_default(subscriber, || {
+        let span = tracing::info_span!("request", metrics.timer = "time");
+        drop(span);
+    });
+    // The timer was already stopped when the later layer was notified
+    assert_eq!(1, *seen.lock().unwrap());
+}
+
+#[test]
+fn clones_share_metrics() {
+    let events = with_test_layer(|recording| {
+        let span = tracing::info_span!("request", metrics.level = "active");
+        let dispatch = tracing::dispatcher::get_default(Clone::clone);
+        let id = dispatch.clone_span(&span.id().unwrap());
+        let handle = span.clone();
+        thread::spawn(move || drop(handle)).join().unwrap();
+        drop(span);
+        // Still held by the cloned ID
+        assert_eq!(1, recording.events().len());
+        dispatch.try_close(id);
+    });
+    let level: isize = events.iter().map(|event| event.value).sum();
+    assert_eq!((2, 0), (events.len(), level));
+}
+
+#[test]
+fn metrics_on_enter() {
+    let events = with_test_layer(|_| {
+        let span = tracing::info_span!(
+            "poll",
+            metrics.on = "enter",
+            metrics.counter = "polls",
+            metrics.timer = "poll_time",
+        );
+        for _ in 0..3 {
+            let _guard = span.enter();
+        }
+    });
+    let count = |kind| events.iter().filter(|e| e.kind == kind).count();
+    assert_eq!(3, count(InputKind::Counter));
+    assert_eq!(3, count(InputKind::Timer));
+}
+
+#[test]
+fn busy_time() {
+    let clock = ManualClock::new();
+    let recording = RecordingScope::new();
+    let bridge = DipstickLayer::builder(recording.clone())
+        .clock(clock.clone())
+        .finish();
+    subscriber::with_default(Registry::default().with(bridge), || {
+        let span = tracing::info_span!("task", metrics.timer.busy = "busy");
+        for _ in 0..2 {
+            let _guard = span.enter();
+            clock.advance(Duration::from_millis(10));
+        }
+        // Idle, not counted
+        clock.advance(Duration::from_millis(50));
+    });
+    // In microseconds
+    assert_eq!(vec![20_000], recording.values("busy"));
+}
+
+#[test]
+fn panic_in_span() {
+    let events = with_test_layer(|_| {
+        let result = panic::catch_unwind(|| {
+            let _span =
+                tracing::info_span!("work", metrics.timer = "work", metrics.level = "active",)
+                    .entered();
+            panic!("Oops");
+        });
+        assert!(result.is_err());
+    });
+    let kinds: Vec<_> = events.iter().map(|e| (e.name.as_str(), e.kind)).collect();
+    let expected = [
+        ("active", InputKind::Level),
+        ("work", InputKind::Timer),
+        ("active", InputKind::Level),
+    ];
+    assert_eq!(expected.as_slice(), kinds);
+    assert_eq!((1, -1), (events[0].value, events[2].value));
+}
@@ -0,0 +1,49 @@
+//! Batching of explicit timer values of events inside spans.
+#![cfg(feature = "testing")]
+
+use tracing::{info, info_span, subscriber};
+use tracing_dipstick::testing::RecordingScope;
+use tracing_dipstick::DipstickLayer;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Registry;
+
+#[test]
+fn separate_per_timer() {
+    let recording = RecordingScope::new();
+    let bridge = DipstickLayer::builder(recording.clone())
+        .timer_batch(3)
+        .finish();
+    subscriber::with_default(Registry::default().with(bridge), || {
+        let _span = info_span!("work", metrics.scope = "work").entered();
+        for i in 0..4 {
+            info!(metrics.distribution.a = i, metrics.distribution.b = 10 + i);
+        }
+        assert_eq!(vec![0, 1, 2], recording.values("work.a"));
+        assert_eq!(vec![10, 11, 12], recording.values("work.b"));
+    });
+    assert_eq!(vec![0, 1, 2, 3], recording.values("work.a"));
+    assert_eq!(vec![10, 11, 12, 13], recording.values("work.b"));
+}
+
+#[test]
+fn sent_when_scope_changes() {
+    let recording = RecordingScope::new();
+    let bridge = DipstickLayer::builder(recording.clone())
+        .dynamic_scopes(true)
+        .timer_batch(10)
+        .finish();
+    subscriber::with_default(Registry::default().with(bridge), || {
+        let task = info_span!("task", metrics.scope = "task", metrics.scope.dynamic = true);
+        info_span!("first", metrics.scope = "first").in_scope(|| {
+            task.in_scope(|| info!(metrics.distribution.size = 1));
+        });
+        assert!(recording.events().is_empty());
+        info_span!("second", metrics.scope = "second").in_scope(|| {
+            // The value batched under the previous scope is sent when entered under a new one
+            task.in_scope(|| info!(metrics.distribution.size = 2));
+            assert_eq!(vec![1], recording.values("first.task.size"));
+        });
+        drop(task);
+        assert_eq!(vec![2], recording.values("second.task.size"));
+    });
+}
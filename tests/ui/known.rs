@@ -0,0 +1,43 @@
+use tracing::Level;
+use tracing_dipstick::{metric_event, metric_span};
+
+fn main() {
+    let shard = 3;
+    let span = metric_span!(
+        Level::DEBUG,
+        "request",
+        metrics.scope = "request",
+        metrics.scope.field = "shard",
+        metrics.tag.kind = "read",
+        metrics.level = "requests",
+        metrics.level.max = "requests.max",
+        metrics.peak = "requests.peak",
+        metrics.timer = "latency",
+        metrics.timer.busy = "busy",
+        metrics.timer.percentiles = "latency.full",
+        metrics.count_children = "children",
+        metrics.not_entered = "waiting",
+        metrics.summary = true,
+        metrics.on = "enter",
+        metrics.auto_gauge = true,
+        metrics.flush_every = 60,
+        shard,
+    );
+    let _entered = span.enter();
+    metric_event!(metrics.counter = "hits");
+    metric_event!(
+        tracing::Level::WARN,
+        cache_hit = true,
+        metrics.when = "cache_hit",
+        metrics.counter.bytes = 42,
+        metrics.gauge.delta.queue = -1,
+        metrics.timer.start = "sync",
+        metrics.distribution.size = 10,
+        metrics.scope.full = "other",
+        metrics.marker = "done",
+        metrics.level_value = "severity",
+        "Done with {} of {}",
+        shard,
+        vec![1, 2].len(),
+    );
+}
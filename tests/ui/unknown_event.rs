@@ -0,0 +1,5 @@
+use tracing_dipstick::metric_event;
+
+fn main() {
+    metric_event!(cache_hit = true, metrics.countr = "hits");
+}
@@ -0,0 +1,6 @@
+use tracing::Level;
+use tracing_dipstick::metric_span;
+
+fn main() {
+    let _span = metric_span!(Level::INFO, "request", metrics.scope.ful = "request");
+}